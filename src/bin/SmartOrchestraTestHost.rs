@@ -1,34 +1,246 @@
 use anyhow::{Context, Result};
 use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
-use smart_orchestra_vst::engine::{MidiProcessor, SmoothedValue, Voice, MAX_VOICES};
+use smart_orchestra_vst::convolution::ImpulseResponse;
+use smart_orchestra_vst::engine::{
+    BrassMute, Family, Instruments, OrchestraEngine, RangePolicy, RangeViolation, Section, SynthModel,
+};
+use smart_orchestra_vst::patch::PatchBank;
+use smart_orchestra_vst::preset::Preset;
+use smart_orchestra_vst::stage::Seating;
+use smart_orchestra_vst::streaming::StreamReader;
+use smart_orchestra_vst::{sf2, sfz};
+use smart_orchestra_vst::tuning::{MtsSysEx, Temperament, Tuning, TuningConfig};
 use std::{env, fs, path::PathBuf};
 
-#[derive(Debug, Clone, Copy)]
+// Same granularity as a typical plugin block
+const STREAM_POLL_SAMPLES: usize = 256;
+// Largest value of a 24-bit sample
+const WAV_FULL_SCALE: f32 = ((1 << 23) - 1) as f32;
+// The host's own dynamics curve, softer than the plugin's: CC1 starts at 0.4 and fully open gives 1.1
+const INITIAL_CC1: f32 = 0.4;
+const FULL_DYNAMICS: f32 = 1.1;
+
+#[derive(Debug, Clone)]
 struct ScheduledEvent {
     sample: usize,
     kind: EventKind,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 enum EventKind {
-    NoteOn { note: u8, vel: u8 },
-    NoteOff { note: u8 },
-    Cc { cc: u8, value: f32 },
+    NoteOn { channel: u8, note: u8, vel: u8 },
+    NoteOff { channel: u8, note: u8 },
+    Cc { channel: u8, cc: u8, value: f32 },
+    Program { channel: u8, program: u8 },
+    ChannelPressure { channel: u8, value: f32 },
+    PolyPressure { channel: u8, note: u8, value: f32 },
+    SysEx(MtsSysEx),
+}
+
+#[derive(Debug, Default)]
+struct HostOptions {
+    positional: Vec<String>,
+    scl: Option<PathBuf>,
+    kbm: Option<PathBuf>,
+    concert_pitch: Option<f32>,
+    temperament: Option<Temperament>,
+    smart_intonation: bool,
+    section_size: Option<usize>,
+    solo_channels: Vec<u8>,
+    range_policy: Option<RangePolicy>,
+    bowed_sections: Vec<Section>,
+    brass: bool,
+    brass_mute: Option<BrassMute>,
+    sfz: Vec<(u8, PathBuf)>,
+    sf2: Option<PathBuf>,
+    preset: Option<String>,
+    save_preset: Option<PathBuf>,
+    seating: Option<Seating>,
+    reverb_mix: Option<f32>,
+    reverb_decay: Option<f32>,
+    early: Option<f32>,
+    ir: Option<PathBuf>,
+}
+
+impl HostOptions {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut options = Self::default();
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            if !arg.starts_with("--") {
+                options.positional.push(arg.clone());
+                continue;
+            }
+            if arg == "--smart-intonation" {
+                options.smart_intonation = true;
+                continue;
+            }
+            if arg == "--brass" {
+                options.brass = true;
+                continue;
+            }
+
+            let value = iter.next().with_context(|| format!("Opção sem valor: {arg}"))?;
+            match arg.as_str() {
+                "--scl" => options.scl = Some(PathBuf::from(value)),
+                "--kbm" => options.kbm = Some(PathBuf::from(value)),
+                "--a4" => {
+                    options.concert_pitch = Some(value.parse().with_context(|| format!("Diapasão inválido: {value}"))?)
+                }
+                "--section-size" => {
+                    options.section_size =
+                        Some(value.parse().with_context(|| format!("Tamanho de naipe inválido: {value}"))?)
+                }
+                "--solo" => {
+                    for channel in value.split(',') {
+                        let channel: u8 = channel.trim().parse().with_context(|| format!("Canal inválido: {channel}"))?;
+                        anyhow::ensure!((1..=16).contains(&channel), "Canal fora de 1..16: {channel}");
+                        options.solo_channels.push(channel - 1);
+                    }
+                }
+                "--bowed" => {
+                    for name in value.split(',') {
+                        let section = Section::from_name(name.trim()).with_context(|| format!("Naipe desconhecido: {name}"))?;
+                        options.bowed_sections.push(section);
+                    }
+                }
+                "--brass-mute" => {
+                    options.brass_mute = Some(BrassMute::from_name(value).with_context(|| format!("Surdina desconhecida: {value}"))?)
+                }
+                "--sfz" => {
+                    let (program, path) = value.split_once('=').with_context(|| format!("Use --sfz programa=arquivo.sfz: {value}"))?;
+                    let program = program.parse().with_context(|| format!("Programa inválido: {program}"))?;
+                    options.sfz.push((program, PathBuf::from(path)));
+                }
+                "--sf2" => options.sf2 = Some(PathBuf::from(value)),
+                "--preset" => options.preset = Some(value.clone()),
+                "--save-preset" => options.save_preset = Some(PathBuf::from(value)),
+                "--ir" => options.ir = Some(PathBuf::from(value)),
+                "--reverb-mix" => {
+                    options.reverb_mix = Some(value.parse().with_context(|| format!("Mix de reverb inválido: {value}"))?)
+                }
+                "--reverb-decay" => {
+                    options.reverb_decay = Some(value.parse().with_context(|| format!("Decaimento inválido: {value}"))?)
+                }
+                "--early" => {
+                    options.early = Some(value.parse().with_context(|| format!("Nível de reflexões inválido: {value}"))?)
+                }
+                "--seating" => {
+                    options.seating = Some(Seating::from_name(value).with_context(|| format!("Disposição desconhecida: {value}"))?)
+                }
+                "--range-policy" => {
+                    options.range_policy =
+                        Some(RangePolicy::from_name(value).with_context(|| format!("Política de extensão desconhecida: {value}"))?)
+                }
+                "--temperament" => {
+                    options.temperament =
+                        Some(Temperament::from_name(value).with_context(|| format!("Temperamento desconhecido: {value}"))?)
+                }
+                _ => anyhow::bail!("Opção desconhecida: {arg}"),
+            }
+        }
+
+        Ok(options)
+    }
 }
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
+    let options = HostOptions::parse(&args[1..])?;
+    if options.positional.len() < 2 {
         eprintln!(
-            "Uso: {} <arquivo.mid> <saida.wav> [sample_rate]\nExemplo: {} demo.mid out.wav 48000",
+            "Uso: {} <arquivo.mid> <saida.wav> [sample_rate] [--scl escala.scl] [--kbm mapa.kbm] [--a4 Hz] [--temperament nome] [--smart-intonation] [--section-size N] [--solo canais] [--range-policy política] [--bowed naipes] [--brass] [--brass-mute surdina] [--seating american|european] [--reverb-mix 0..1] [--reverb-decay segundos] [--early 0..1] [--ir resposta.wav] [--sfz programa=arquivo.sfz] [--sf2 arquivo.sf2] [--preset nome|arquivo.json] [--save-preset arquivo.json]\nExemplo: {} demo.mid out.wav 48000 --a4 415",
             args[0], args[0]
         );
         std::process::exit(1);
     }
 
-    let midi_path = PathBuf::from(&args[1]);
-    let wav_path = PathBuf::from(&args[2]);
-    let sample_rate = args.get(3).and_then(|s| s.parse::<u32>().ok()).unwrap_or(48_000);
+    let midi_path = PathBuf::from(&options.positional[0]);
+    let wav_path = PathBuf::from(&options.positional[1]);
+    let sample_rate = options.positional.get(2).and_then(|s| s.parse::<u32>().ok()).unwrap_or(48_000);
+
+    let mut engine = OrchestraEngine::new(sample_rate as f32);
+    engine.full_dynamics = FULL_DYNAMICS;
+    engine.set_initial_dynamics(INITIAL_CC1);
+    let tuning_config = TuningConfig::from_files(options.scl.as_deref(), options.kbm.as_deref())?;
+    engine.tuning = Tuning::from_config(&tuning_config)?;
+    if let Some(concert_pitch) = options.concert_pitch {
+        engine.tuning.set_concert_pitch(concert_pitch);
+    }
+    if let Some(temperament) = options.temperament {
+        engine.tuning.set_temperament(temperament);
+    }
+    let mut instruments = Instruments::new(sample_rate as f32);
+    if let Some(path) = &options.sf2 {
+        instruments.add_sound_font(sf2::load(path)?);
+    }
+    for (program, path) in &options.sfz {
+        instruments.add_sample_instrument(*program, sfz::load(path)?);
+    }
+    if let Some(path) = &options.ir {
+        let response = ImpulseResponse::load(path)?;
+        println!(
+            "Resposta ao impulso: {} canal(is), {:.2}s",
+            response.channels.len(),
+            response.channels[0].len() as f32 / response.sample_rate
+        );
+        instruments.set_impulse_response(Some(response));
+    }
+    engine.install(&mut instruments);
+    if options.sf2.is_some() {
+        // General MIDI keeps its drum kits on channel 10, bank 128
+        engine.select_patch(9, 128, 0);
+    }
+    // The preset sets the scene and the options below adjust it
+    if let Some(name) = &options.preset {
+        let preset = match Preset::factory(name) {
+            Some(preset) => preset,
+            None => Preset::load(name.as_ref())?,
+        };
+        println!("Preset: {}", preset.name);
+        preset.apply(&mut engine);
+    }
+    if options.smart_intonation {
+        engine.smart_intonation = true;
+    }
+    if let Some(section_size) = options.section_size {
+        engine.section_size = section_size;
+    }
+    if let Some(range_policy) = options.range_policy {
+        engine.range_policy = range_policy;
+    }
+    for &section in &options.bowed_sections {
+        engine.bank.set_model(section, SynthModel::BowedString);
+    }
+    for section in Section::ALL.into_iter().filter(|s| s.family() == Family::Brass) {
+        if options.brass {
+            engine.bank.set_model(section, SynthModel::Brass);
+        }
+        if let Some(mute) = options.brass_mute {
+            engine.bank.set_mute(section, mute);
+        }
+    }
+    for &channel in &options.solo_channels {
+        engine.set_solo(channel, true);
+    }
+    if let Some(seating) = options.seating {
+        engine.stage.seating = seating;
+    }
+    if let Some(mix) = options.reverb_mix {
+        engine.reverb.settings.mix = mix;
+    }
+    if let Some(decay_s) = options.reverb_decay {
+        engine.reverb.settings.decay_s = decay_s;
+    }
+    if let Some(early_level) = options.early {
+        engine.reverb.settings.early_level = early_level;
+    }
+    if let Some(path) = &options.save_preset {
+        let name = path.file_stem().map_or("Preset".into(), |stem| stem.to_string_lossy());
+        Preset::capture(&name, &engine).save(path)?;
+        println!("Preset salvo em: {}", path.display());
+    }
 
     let midi_data = fs::read(&midi_path).with_context(|| format!("Falha ao ler MIDI: {midi_path:?}"))?;
     let smf = Smf::parse(&midi_data).context("Falha no parse do arquivo MIDI")?;
@@ -36,9 +248,15 @@ fn main() -> Result<()> {
     let mut events = collect_events(&smf, sample_rate as f32)?;
     events.sort_by_key(|e| e.sample);
 
-    let total_samples = events.last().map(|e| e.sample + sample_rate as usize * 2).unwrap_or(sample_rate as usize * 2);
+    // Two seconds of release after the last event, plus the reverb tail
+    let reverb_s = match engine.impulse_response() {
+        Some(response) => response.channels[0].len() as f32 / response.sample_rate,
+        None => engine.reverb.settings.decay_s,
+    };
+    let tail = sample_rate as usize * 2 + (reverb_s * sample_rate as f32) as usize;
+    let total_samples = events.last().map(|e| e.sample + tail).unwrap_or(tail);
 
-    render_to_wav(events, total_samples, sample_rate, &wav_path)
+    render_to_wav(engine, events, total_samples, sample_rate, &wav_path)
 }
 
 fn collect_events(smf: &Smf<'_>, sample_rate: f32) -> Result<Vec<ScheduledEvent>> {
@@ -62,11 +280,22 @@ fn collect_events(smf: &Smf<'_>, sample_rate: f32) -> Result<Vec<ScheduledEvent>
             let seconds = (abs_ticks as f32 / ticks_per_beat) * (tempo_us_per_beat / 1_000_000.0);
             let sample = (seconds * sample_rate) as usize;
 
-            if let TrackEventKind::Midi { message, .. } = event.kind {
+            if let TrackEventKind::SysEx(data) = event.kind {
+                if let Some(message) = MtsSysEx::from_bytes(data) {
+                    out.push(ScheduledEvent {
+                        sample,
+                        kind: EventKind::SysEx(message),
+                    });
+                }
+            }
+
+            if let TrackEventKind::Midi { channel, message } = event.kind {
+                let channel = channel.as_int();
                 match message {
                     MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => out.push(ScheduledEvent {
                         sample,
                         kind: EventKind::NoteOn {
+                            channel,
                             note: key.as_int(),
                             vel: vel.as_int(),
                         },
@@ -74,21 +303,42 @@ fn collect_events(smf: &Smf<'_>, sample_rate: f32) -> Result<Vec<ScheduledEvent>
                     MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
                         out.push(ScheduledEvent {
                             sample,
-                            kind: EventKind::NoteOff { note: key.as_int() },
+                            kind: EventKind::NoteOff {
+                                channel,
+                                note: key.as_int(),
+                            },
                         })
                     }
-                    MidiMessage::Controller { controller, value } => {
-                        let cc = controller.as_int();
-                        if cc == 1 || cc == 11 {
-                            out.push(ScheduledEvent {
-                                sample,
-                                kind: EventKind::Cc {
-                                    cc,
-                                    value: value.as_int() as f32 / 127.0,
-                                },
-                            });
-                        }
-                    }
+                    MidiMessage::Controller { controller, value } => out.push(ScheduledEvent {
+                        sample,
+                        kind: EventKind::Cc {
+                            channel,
+                            cc: controller.as_int(),
+                            value: value.as_int() as f32 / 127.0,
+                        },
+                    }),
+                    MidiMessage::ChannelAftertouch { vel } => out.push(ScheduledEvent {
+                        sample,
+                        kind: EventKind::ChannelPressure {
+                            channel,
+                            value: vel.as_int() as f32 / 127.0,
+                        },
+                    }),
+                    MidiMessage::Aftertouch { key, vel } => out.push(ScheduledEvent {
+                        sample,
+                        kind: EventKind::PolyPressure {
+                            channel,
+                            note: key.as_int(),
+                            value: vel.as_int() as f32 / 127.0,
+                        },
+                    }),
+                    MidiMessage::ProgramChange { program } => out.push(ScheduledEvent {
+                        sample,
+                        kind: EventKind::Program {
+                            channel,
+                            program: program.as_int(),
+                        },
+                    }),
                     _ => {}
                 }
             }
@@ -98,12 +348,17 @@ fn collect_events(smf: &Smf<'_>, sample_rate: f32) -> Result<Vec<ScheduledEvent>
     Ok(out)
 }
 
-fn render_to_wav(events: Vec<ScheduledEvent>, total_samples: usize, sample_rate: u32, path: &PathBuf) -> Result<()> {
-    let mut voices: Vec<Voice> = (0..MAX_VOICES).map(|_| Voice::new()).collect();
-    let mut midi = MidiProcessor::new();
-    let mut cc1 = SmoothedValue::new(0.4);
-    let mut cc11 = SmoothedValue::new(1.0);
+fn render_to_wav(
+    mut engine: OrchestraEngine,
+    events: Vec<ScheduledEvent>,
+    total_samples: usize,
+    sample_rate: u32,
+    path: &PathBuf,
+) -> Result<()> {
     let mut event_cursor = 0;
+    let mut flagged = 0;
+    // Offline the disk reads run in line after each block, so the render never waits on the disk
+    let mut stream_reader = StreamReader::new(engine.streams.clone());
 
     let spec = hound::WavSpec {
         channels: 2,
@@ -115,64 +370,64 @@ fn render_to_wav(events: Vec<ScheduledEvent>, total_samples: usize, sample_rate:
 
     for sample_idx in 0..total_samples {
         while event_cursor < events.len() && events[event_cursor].sample <= sample_idx {
-            match events[event_cursor].kind {
-                EventKind::NoteOn { note, vel } => {
-                    let (_layer, layer_gain) = midi.detect_layer(vel);
-                    let legato = midi.legato_engine.note_on(note as i32, sample_idx as i64);
-                    let humanize = midi.humanize();
-                    midi.step_round_robin();
-                    if let Some(v) = voices.iter_mut().find(|v| !v.active) {
-                        v.start(
-                            note,
-                            vel,
-                            midi.detect_articulation(500.0),
-                            layer_gain,
-                            legato,
-                            sample_rate as f32,
-                            sample_idx as i64,
-                            humanize,
-                        );
-                    }
-                }
-                EventKind::NoteOff { note } => {
-                    midi.legato_engine.note_off(sample_idx as i64);
-                    for v in &mut voices {
-                        if v.active && v.note == note {
-                            v.note_off(sample_rate as f32);
-                        }
-                    }
-                }
-                EventKind::Cc { cc, value } => match cc {
-                    1 => cc1.set_target(value, 5.0, sample_rate as f32),
-                    11 => cc11.set_target(value, 5.0, sample_rate as f32),
-                    _ => {}
-                },
+            match &events[event_cursor].kind {
+                &EventKind::NoteOn { channel, note, vel } => engine.note_on(channel, note, vel),
+                &EventKind::NoteOff { channel, note } => engine.note_off(channel, note),
+                &EventKind::Cc { channel, cc, value } => engine.handle_cc(channel, cc, value),
+                &EventKind::Program { channel, program } => engine.program_change(channel, program),
+                &EventKind::ChannelPressure { channel, value } => engine.channel_pressure(channel, value),
+                &EventKind::PolyPressure { channel, note, value } => engine.poly_pressure(channel, note, value),
+                EventKind::SysEx(message) => engine.handle_sysex(message),
             }
             event_cursor += 1;
         }
+        for violation in engine.range_violations.drain(..) {
+            flagged += 1;
+            report_range_violation(&violation, &engine.bank, sample_rate);
+        }
 
-        let mut l = 0.0;
-        let mut r = 0.0;
-        let dyn_gain = 0.35 + cc1.next() * 0.75;
-        let expr = cc11.next();
-
-        for v in &mut voices {
-            if v.active {
-                v.set_layer_gain(dyn_gain, sample_rate as f32);
-                let (vl, vr) = v.render(sample_rate as f32, 12_000.0);
-                l += vl;
-                r += vr;
-            }
+        let (l, r) = engine.render(12_000.0);
+        if sample_idx % STREAM_POLL_SAMPLES == 0 {
+            engine.poll_streams();
+        }
+        for request in engine.stream_requests.drain(..) {
+            stream_reader.run(request)?;
         }
 
-        let scale = 0.22 * expr;
-        let li = (l * scale * i32::MAX as f32) as i32;
-        let ri = (r * scale * i32::MAX as f32) as i32;
+        let scale = 0.22;
+        let li = ((l * scale).clamp(-1.0, 1.0) * WAV_FULL_SCALE) as i32;
+        let ri = ((r * scale).clamp(-1.0, 1.0) * WAV_FULL_SCALE) as i32;
         writer.write_sample(li)?;
         writer.write_sample(ri)?;
     }
 
     writer.finalize()?;
+    if flagged > 0 {
+        println!("{flagged} nota(s) fora da extensão dos instrumentos");
+    }
+    let underruns = engine.streams.underruns();
+    if underruns > 0 {
+        println!("{underruns} frame(s) de sample sem dados do disco (underrun)");
+    }
     println!("Render concluído em: {}", path.display());
     Ok(())
 }
+
+fn report_range_violation(violation: &RangeViolation, bank: &PatchBank, sample_rate: u32) {
+    let seconds = violation.sample as f32 / sample_rate as f32;
+    let action = match violation.played {
+        None => "ignorada".to_string(),
+        Some((note, _)) if note != violation.note => format!("tocada como nota {note}"),
+        Some((_, patch)) if bank.get(patch).section != violation.section => {
+            format!("passada para {}", bank.get(patch).name)
+        }
+        Some(_) => "tocada assim mesmo".to_string(),
+    };
+    println!(
+        "Fora da extensão: {:.3}s canal {} nota {} ({}) — {action}",
+        seconds,
+        violation.channel + 1,
+        violation.note,
+        violation.section.name()
+    );
+}
@@ -0,0 +1,237 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CcTarget {
+    Dynamics,
+    Expression,
+    Cutoff,
+    Release,
+    Legato,
+    Vibrato,
+    Solo,
+}
+
+impl CcTarget {
+    pub const ALL: [CcTarget; 7] = [
+        CcTarget::Dynamics,
+        CcTarget::Expression,
+        CcTarget::Cutoff,
+        CcTarget::Release,
+        CcTarget::Legato,
+        CcTarget::Vibrato,
+        CcTarget::Solo,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CcBinding {
+    pub msb: u8,
+    pub lsb: Option<u8>,
+    pub target: CcTarget,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CcMap {
+    // One slot per target, so learning a controller on the audio thread never allocates. Saved as a plain
+    // list of bindings
+    #[serde(with = "binding_slots")]
+    bindings: [Option<CcBinding>; CcTarget::ALL.len()],
+    #[serde(skip)]
+    learn: Option<CcTarget>,
+    #[serde(skip)]
+    learned: Option<CcBinding>,
+    #[serde(skip, default = "controller_table")]
+    msb_values: Vec<u8>,
+    #[serde(skip, default = "controller_table")]
+    lsb_values: Vec<u8>,
+}
+
+impl Default for CcMap {
+    fn default() -> Self {
+        Self::new(vec![
+            CcBinding {
+                msb: 1,
+                lsb: None,
+                target: CcTarget::Dynamics,
+            },
+            CcBinding {
+                msb: 11,
+                lsb: None,
+                target: CcTarget::Expression,
+            },
+            CcBinding {
+                msb: 21,
+                lsb: None,
+                target: CcTarget::Vibrato,
+            },
+        ])
+    }
+}
+
+impl CcMap {
+    pub fn new(bindings: Vec<CcBinding>) -> Self {
+        Self {
+            bindings: binding_slots::from_list(bindings),
+            learn: None,
+            learned: None,
+            msb_values: controller_table(),
+            lsb_values: controller_table(),
+        }
+    }
+
+    pub fn bindings(&self) -> impl Iterator<Item = &CcBinding> {
+        self.bindings.iter().flatten()
+    }
+
+    pub fn arm_learn(&mut self, target: Option<CcTarget>) {
+        self.learn = target;
+    }
+
+    pub fn is_learning(&self) -> bool {
+        self.learn.is_some()
+    }
+
+    pub fn take_learned(&mut self) -> Option<CcBinding> {
+        self.learned.take()
+    }
+
+    pub fn bind(&mut self, cc: u8, target: CcTarget) {
+        // Controllers 0..=31 are the MSB half of a 14-bit pair whose LSB sits 32 numbers higher
+        let lsb = (cc < 32).then_some(cc + 32);
+        for slot in &mut self.bindings {
+            if slot.is_some_and(|b| b.msb == cc) {
+                *slot = None;
+            }
+        }
+        self.bindings[target as usize] = Some(CcBinding { msb: cc, lsb, target });
+    }
+
+    pub fn unbind(&mut self, target: CcTarget) {
+        self.bindings[target as usize] = None;
+    }
+
+    pub fn is_learnable(cc: u8) -> bool {
+        // Bank select, volume, pan and the channel mode messages are never remapped
+        !matches!(cc, 0 | 7 | 10 | 32 | 120..=127)
+    }
+
+    // While armed, the first learnable controller is bound to the pending target before resolving
+    pub fn handle(&mut self, cc: u8, value: f32) -> Option<(CcTarget, f32)> {
+        if cc >= 128 {
+            return None;
+        }
+
+        let raw = (value.clamp(0.0, 1.0) * 127.0).round() as u8;
+
+        if let Some(target) = self.learn {
+            if Self::is_learnable(cc) && !self.is_lsb(cc) {
+                self.bind(cc, target);
+                self.learn = None;
+                self.learned = self.bindings[target as usize];
+            }
+        }
+
+        if let Some(binding) = self.bindings.iter().flatten().find(|b| b.msb == cc).copied() {
+            self.msb_values[cc as usize] = raw;
+            // A new MSB invalidates the previously received LSB
+            self.lsb_values[cc as usize] = 0;
+            return Some((binding.target, self.resolve(&binding)));
+        }
+
+        if let Some(binding) = self.bindings.iter().flatten().find(|b| b.lsb == Some(cc)).copied() {
+            self.lsb_values[binding.msb as usize] = raw;
+            return Some((binding.target, self.resolve(&binding)));
+        }
+
+        None
+    }
+
+    fn is_lsb(&self, cc: u8) -> bool {
+        self.bindings().any(|b| b.lsb == Some(cc))
+    }
+
+    fn resolve(&self, binding: &CcBinding) -> f32 {
+        let msb = self.msb_values[binding.msb as usize] as f32;
+        let lsb = match binding.lsb {
+            Some(_) => self.lsb_values[binding.msb as usize] as f32 / 128.0,
+            None => 0.0,
+        };
+        ((msb + lsb) / 127.0).clamp(0.0, 1.0)
+    }
+}
+
+fn controller_table() -> Vec<u8> {
+    vec![0; 128]
+}
+
+mod binding_slots {
+    use super::{CcBinding, CcTarget};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    type Slots = [Option<CcBinding>; CcTarget::ALL.len()];
+
+    // A later binding of the same target wins, as it would have when bound in that order
+    pub fn from_list(bindings: Vec<CcBinding>) -> Slots {
+        let mut slots = [None; CcTarget::ALL.len()];
+        for binding in bindings {
+            slots[binding.target as usize] = Some(binding);
+        }
+        slots
+    }
+
+    pub fn serialize<S: Serializer>(slots: &Slots, serializer: S) -> Result<S::Ok, S::Error> {
+        slots.iter().flatten().collect::<Vec<_>>().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Slots, D::Error> {
+        Vec::deserialize(deserializer).map(from_list)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binding_takes_the_controller_and_target_from_older_bindings() {
+        let mut map = CcMap::default();
+        map.bind(11, CcTarget::Cutoff);
+        map.bind(2, CcTarget::Cutoff);
+        let bindings: Vec<_> = map.bindings().map(|b| (b.msb, b.lsb, b.target)).collect();
+        assert_eq!(
+            bindings,
+            [
+                (1, None, CcTarget::Dynamics),
+                (2, Some(34), CcTarget::Cutoff),
+                (21, None, CcTarget::Vibrato),
+            ]
+        );
+    }
+
+    #[test]
+    fn learning_binds_the_first_learnable_controller() {
+        let mut map = CcMap::default();
+        map.arm_learn(Some(CcTarget::Release));
+        assert_eq!(map.handle(7, 0.5), None);
+        assert_eq!(map.handle(16, 1.0), Some((CcTarget::Release, 1.0)));
+        assert_eq!(
+            map.take_learned(),
+            Some(CcBinding {
+                msb: 16,
+                lsb: Some(48),
+                target: CcTarget::Release
+            })
+        );
+        assert!(!map.is_learning());
+    }
+
+    #[test]
+    fn bindings_save_as_a_list() {
+        let mut map = CcMap::default();
+        map.bind(74, CcTarget::Cutoff);
+        let json = serde_json::to_string(&map).unwrap();
+        let loaded: CcMap = serde_json::from_str(&json).unwrap();
+        assert!(loaded.bindings().eq(map.bindings()));
+        assert!(json.starts_with(r#"{"bindings":[{"msb":1,"#));
+    }
+}
@@ -0,0 +1,541 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+use crate::sampler::{open_wav, read_samples};
+
+// Samples per partition. The tail starts one partition late, which only adds a few ms to the hall's pre-delay
+pub const PARTITION: usize = 256;
+pub const MAX_IR_SECONDS: f32 = 12.0;
+// Zero crossings on each side of the resampling kernel
+const SINC_ZEROS: usize = 32;
+
+// Mono IRs are used for both sides, stereo ones per side, and true-stereo ones hold the four paths
+// left to left, left to right, right to left and right to right
+#[derive(Debug, Clone)]
+pub struct ImpulseResponse {
+    pub channels: Vec<Vec<f32>>,
+    pub sample_rate: f32,
+}
+
+impl ImpulseResponse {
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut reader = open_wav(path)?;
+        let spec = reader.spec();
+        let channels = spec.channels as usize;
+        if !matches!(channels, 1 | 2 | 4) {
+            bail!("IR com {channels} canais, use 1, 2 ou 4: {path:?}");
+        }
+        if reader.duration() as f32 > MAX_IR_SECONDS * spec.sample_rate as f32 {
+            bail!("IR com mais de {MAX_IR_SECONDS} segundos: {path:?}");
+        }
+
+        let samples = read_samples(&mut reader, usize::MAX)
+            .with_context(|| format!("Falha ao ler IR: {path:?}"))?;
+        let mut response = Self {
+            channels: (0..channels)
+                .map(|channel| {
+                    samples
+                        .iter()
+                        .skip(channel)
+                        .step_by(channels)
+                        .copied()
+                        .collect()
+                })
+                .collect(),
+            sample_rate: spec.sample_rate as f32,
+        };
+        if response
+            .channels
+            .iter()
+            .all(|channel| channel.iter().all(|&s| s == 0.0))
+        {
+            bail!("IR silenciosa: {path:?}");
+        }
+        response.trim();
+        Ok(response)
+    }
+
+    // Windowed-sinc conversion, low-passed below the lower of the two Nyquist frequencies
+    pub fn resampled(&self, sample_rate: f32) -> Self {
+        if (sample_rate - self.sample_rate).abs() < 0.5 {
+            return self.clone();
+        }
+        let ratio = self.sample_rate / sample_rate;
+        let cutoff = (1.0 / ratio).min(1.0);
+        let radius = SINC_ZEROS as f32 / cutoff;
+        let channels = self
+            .channels
+            .iter()
+            .map(|input| {
+                let length = (input.len() as f32 / ratio).ceil() as usize;
+                (0..length)
+                    .map(|index| {
+                        let centre = index as f32 * ratio;
+                        let first = (centre - radius).ceil().max(0.0) as usize;
+                        let last = ((centre + radius).floor() as usize).min(input.len() - 1);
+                        (first..=last)
+                            .map(|tap| {
+                                let x = tap as f32 - centre;
+                                let window = 0.5 + 0.5 * (std::f32::consts::PI * x / radius).cos();
+                                input[tap] * cutoff * sinc(x * cutoff) * window
+                            })
+                            .sum()
+                    })
+                    .collect()
+            })
+            .collect();
+        Self {
+            channels,
+            sample_rate,
+        }
+    }
+
+    // Drops the silence after the tail, which would only cost partitions
+    fn trim(&mut self) {
+        let peak = self
+            .channels
+            .iter()
+            .flatten()
+            .fold(0.0_f32, |peak, s| peak.max(s.abs()));
+        let floor = peak * 1e-5;
+        let length = self
+            .channels
+            .iter()
+            .filter_map(|channel| channel.iter().rposition(|s| s.abs() > floor))
+            .max()
+            .map_or(1, |last| last + 1);
+        for channel in &mut self.channels {
+            channel.truncate(length);
+        }
+    }
+
+    // (input, output, response) of each path
+    fn paths(&self) -> Vec<(usize, usize, &[f32])> {
+        match self.channels.as_slice() {
+            [mono] => vec![(0, 0, mono), (1, 1, mono)],
+            [left, right] => vec![(0, 0, left), (1, 1, right)],
+            [ll, lr, rl, rr] => vec![(0, 0, ll), (0, 1, lr), (1, 0, rl), (1, 1, rr)],
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-6 {
+        1.0
+    } else {
+        let x = std::f32::consts::PI * x;
+        x.sin() / x
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+impl Complex {
+    fn mul(self, other: Self) -> Self {
+        Self {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        }
+    }
+
+    fn conj(self) -> Self {
+        Self {
+            re: self.re,
+            im: -self.im,
+        }
+    }
+}
+
+// Iterative radix-2 FFT with the twiddles and bit reversal worked out once
+#[derive(Debug)]
+struct Fft {
+    twiddles: Vec<Complex>,
+    reversed: Vec<usize>,
+}
+
+impl Fft {
+    fn new(size: usize) -> Self {
+        let bits = size.trailing_zeros();
+        Self {
+            twiddles: (0..size / 2)
+                .map(|k| {
+                    let angle = -std::f32::consts::TAU * k as f32 / size as f32;
+                    Complex {
+                        re: angle.cos(),
+                        im: angle.sin(),
+                    }
+                })
+                .collect(),
+            reversed: (0..size)
+                .map(|i| i.reverse_bits() >> (usize::BITS - bits))
+                .collect(),
+        }
+    }
+
+    fn forward(&self, data: &mut [Complex]) {
+        let size = data.len();
+        for (i, &j) in self.reversed.iter().enumerate() {
+            if i < j {
+                data.swap(i, j);
+            }
+        }
+        let mut span = 1;
+        while span < size {
+            let stride = size / (span * 2);
+            for start in (0..size).step_by(span * 2) {
+                for k in 0..span {
+                    let odd = data[start + k + span].mul(self.twiddles[k * stride]);
+                    let even = data[start + k];
+                    data[start + k] = Complex {
+                        re: even.re + odd.re,
+                        im: even.im + odd.im,
+                    };
+                    data[start + k + span] = Complex {
+                        re: even.re - odd.re,
+                        im: even.im - odd.im,
+                    };
+                }
+            }
+            span *= 2;
+        }
+    }
+
+    fn inverse(&self, data: &mut [Complex]) {
+        for value in data.iter_mut() {
+            *value = value.conj();
+        }
+        self.forward(data);
+        let scale = 1.0 / data.len() as f32;
+        for value in data.iter_mut() {
+            *value = Complex {
+                re: value.re * scale,
+                im: -value.im * scale,
+            };
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ConvolutionPath {
+    input: usize,
+    output: usize,
+    // Spectrum of each partition of the response, bins 0..=PARTITION of the real signal
+    partitions: Vec<Vec<Complex>>,
+}
+
+// Uniformly partitioned overlap-save convolution. Every PARTITION samples the newest input block joins a
+// delay line of spectra, which is multiplied with the partitions of the response and summed. Only the first
+// partition needs the newest block: the others are multiplied a few at a time on the samples in between,
+// so a long IR costs the same on every sample instead of all of it at the end of a block
+#[derive(Debug)]
+pub struct Convolver {
+    fft: Fft,
+    paths: Vec<ConvolutionPath>,
+    // Per input channel: the last two blocks of samples and the spectra of the past blocks
+    window: [Vec<f32>; 2],
+    history: [Vec<Vec<Complex>>; 2],
+    newest: usize,
+    output: [Vec<f32>; 2],
+    position: usize,
+    spectrum: Vec<Complex>,
+    sum: Vec<Complex>,
+    // Per output: the older partitions' share of the next block, summed up to `next_age`
+    tail: [Vec<Complex>; 2],
+    next_age: usize,
+    ages_per_sample: usize,
+}
+
+impl Convolver {
+    // Built off the audio thread: resampling and transforming the response is the expensive part
+    pub fn new(response: &ImpulseResponse, sample_rate: f32) -> Self {
+        let response = response.resampled(sample_rate);
+        let size = PARTITION * 2;
+        let fft = Fft::new(size);
+        let bins = PARTITION + 1;
+
+        let paths = response.paths();
+        let gain = normalization(&paths);
+
+        let count = response.channels[0].len().div_ceil(PARTITION);
+        let paths: Vec<ConvolutionPath> = paths
+            .into_iter()
+            .map(|(input, output, samples)| ConvolutionPath {
+                input,
+                output,
+                partitions: samples
+                    .chunks(PARTITION)
+                    .map(|chunk| {
+                        let mut buffer = vec![Complex::default(); size];
+                        for (value, &sample) in buffer.iter_mut().zip(chunk) {
+                            value.re = sample * gain;
+                        }
+                        fft.forward(&mut buffer);
+                        buffer.truncate(bins);
+                        buffer
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        Self {
+            fft,
+            paths,
+            window: std::array::from_fn(|_| vec![0.0; size]),
+            history: std::array::from_fn(|_| vec![vec![Complex::default(); bins]; count]),
+            newest: 0,
+            output: std::array::from_fn(|_| vec![0.0; PARTITION]),
+            position: 0,
+            spectrum: vec![Complex::default(); size],
+            sum: vec![Complex::default(); size],
+            tail: std::array::from_fn(|_| vec![Complex::default(); bins]),
+            next_age: 1,
+            // The first partition is done with the block, the rest over the PARTITION - 1 samples between
+            ages_per_sample: (count - 1).div_ceil(PARTITION - 1),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for window in &mut self.window {
+            window.fill(0.0);
+        }
+        for spectrum in self.history.iter_mut().flatten() {
+            spectrum.fill(Complex::default());
+        }
+        for output in &mut self.output {
+            output.fill(0.0);
+        }
+        for tail in &mut self.tail {
+            tail.fill(Complex::default());
+        }
+        self.position = 0;
+        self.next_age = 1;
+    }
+
+    // The convolved signal for one stereo sample, a partition behind the input
+    pub fn process(&mut self, input: [f32; 2]) -> [f32; 2] {
+        let out = [self.output[0][self.position], self.output[1][self.position]];
+        for (window, sample) in self.window.iter_mut().zip(input) {
+            window[PARTITION + self.position] = sample;
+        }
+        self.position += 1;
+        if self.position == PARTITION {
+            self.position = 0;
+            self.block();
+        } else {
+            self.accumulate(self.ages_per_sample);
+        }
+        out
+    }
+
+    // Adds the next `ages` partitions to the tail of the coming block. They only meet input that is already
+    // in the history, which moves up one place when the block comes
+    fn accumulate(&mut self, ages: usize) {
+        let count = self.history[0].len();
+        let end = self.next_age.saturating_add(ages).min(count);
+        for age in self.next_age..end {
+            let slot = (self.newest + 1 + count - age) % count;
+            for path in &self.paths {
+                let spectrum = &self.history[path.input][slot];
+                multiply_add(&mut self.tail[path.output], spectrum, &path.partitions[age]);
+            }
+        }
+        self.next_age = end;
+    }
+
+    fn block(&mut self) {
+        // Normally the samples already got through all of them
+        self.accumulate(usize::MAX);
+        let count = self.history[0].len();
+        self.newest = (self.newest + 1) % count;
+        let bins = PARTITION + 1;
+        for (window, history) in self.window.iter_mut().zip(&mut self.history) {
+            for (value, &sample) in self.spectrum.iter_mut().zip(window.iter()) {
+                *value = Complex {
+                    re: sample,
+                    im: 0.0,
+                };
+            }
+            self.fft.forward(&mut self.spectrum);
+            history[self.newest].copy_from_slice(&self.spectrum[..bins]);
+            // The current block becomes the first half of the next window
+            window.copy_within(PARTITION.., 0);
+        }
+
+        for output in 0..2 {
+            self.sum[..bins].copy_from_slice(&self.tail[output]);
+            self.tail[output].fill(Complex::default());
+            for path in self.paths.iter().filter(|path| path.output == output) {
+                let spectrum = &self.history[path.input][self.newest];
+                multiply_add(&mut self.sum[..bins], spectrum, &path.partitions[0]);
+            }
+            // A real signal's spectrum mirrors around Nyquist, so only the lower half is accumulated
+            for k in 1..PARTITION {
+                self.sum[PARTITION * 2 - k] = self.sum[k].conj();
+            }
+            self.fft.inverse(&mut self.sum);
+            // Overlap-save keeps the second half, the first is wrapped around by the circular convolution
+            for (out, value) in self.output[output].iter_mut().zip(&self.sum[PARTITION..]) {
+                *out = value.re;
+            }
+        }
+        self.next_age = 1;
+    }
+}
+
+// Scale that brings the response to unit energy per output, averaged over both. The algorithmic tail comes
+// out about 5 dB above unit energy for the same decay, so an IR sits at its level whatever it was
+// recorded at
+fn normalization(paths: &[(usize, usize, &[f32])]) -> f32 {
+    let mut energy = [0.0_f32; 2];
+    for &(_, output, samples) in paths {
+        energy[output] += samples.iter().map(|s| s * s).sum::<f32>();
+    }
+    3.0_f32.sqrt() / ((energy[0] + energy[1]) * 0.5).sqrt().max(1e-9)
+}
+
+fn multiply_add(sum: &mut [Complex], spectrum: &[Complex], partition: &[Complex]) {
+    for ((sum, &x), &h) in sum.iter_mut().zip(spectrum).zip(partition) {
+        let product = x.mul(h);
+        sum.re += product.re;
+        sum.im += product.im;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    // A dense early part and single echoes out to well past PARTITION - 1 partitions, so the samples between
+    // blocks each have to take more than one
+    fn response(channels: usize, rng: &mut SmallRng) -> ImpulseResponse {
+        let length = PARTITION * 300 + 17;
+        ImpulseResponse {
+            channels: (0..channels)
+                .map(|_| {
+                    let mut samples = vec![0.0; length];
+                    for sample in &mut samples[..700] {
+                        *sample = rng.gen_range(-1.0..1.0);
+                    }
+                    for index in (700..length).step_by(997) {
+                        samples[index] = rng.gen_range(-0.5..0.5);
+                    }
+                    samples[length - 1] = 0.25;
+                    samples
+                })
+                .collect(),
+            sample_rate: SAMPLE_RATE,
+        }
+    }
+
+    // The same sum the FFT works out, one tap at a time and a partition late
+    fn direct(response: &ImpulseResponse, input: &[[f32; 2]], length: usize) -> Vec<[f32; 2]> {
+        let paths = response.paths();
+        let gain = normalization(&paths);
+        let mut out = vec![[0.0; 2]; length];
+        for (input_channel, output, samples) in paths {
+            let taps: Vec<_> = samples
+                .iter()
+                .enumerate()
+                .filter(|(_, &h)| h != 0.0)
+                .collect();
+            for (time, frame) in input.iter().enumerate() {
+                for &(delay, &h) in &taps {
+                    if let Some(out) = out.get_mut(PARTITION + time + delay) {
+                        out[output] += frame[input_channel] * h * gain;
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn partitions_add_up_to_the_direct_convolution() {
+        let mut rng = SmallRng::seed_from_u64(5);
+        let input: Vec<[f32; 2]> = (0..3_000)
+            .map(|_| [rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)])
+            .collect();
+        for channels in [1, 2, 4] {
+            let response = response(channels, &mut rng);
+            let length = PARTITION * 303 + input.len();
+            let expected = direct(&response, &input, length);
+
+            let mut convolver = Convolver::new(&response, SAMPLE_RATE);
+            assert!(convolver.ages_per_sample > 1);
+            let silence = std::iter::repeat([0.0; 2]);
+            let out: Vec<[f32; 2]> = input
+                .iter()
+                .copied()
+                .chain(silence)
+                .take(length)
+                .map(|frame| convolver.process(frame))
+                .collect();
+
+            let peak = expected
+                .iter()
+                .flatten()
+                .fold(0.0_f32, |peak, s| peak.max(s.abs()));
+            for (time, (got, wanted)) in out.iter().zip(&expected).enumerate() {
+                for channel in 0..2 {
+                    let error = (got[channel] - wanted[channel]).abs();
+                    assert!(
+                        error < peak * 1e-4,
+                        "{channels} channels, sample {time}, side {channel}: {} against {}",
+                        got[channel],
+                        wanted[channel]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn true_stereo_keeps_its_four_paths_apart() {
+        let mut channels = vec![vec![0.0; PARTITION * 3]; 4];
+        // Left to left right away, left to right a partition later, right to left two and right to right
+        // two and a half
+        channels[0][0] = 1.0;
+        channels[1][PARTITION] = 1.0;
+        channels[2][PARTITION * 2] = 1.0;
+        channels[3][PARTITION * 5 / 2] = 1.0;
+        let response = ImpulseResponse {
+            channels,
+            sample_rate: SAMPLE_RATE,
+        };
+        let out = |input: [f32; 2]| {
+            let mut convolver = Convolver::new(&response, SAMPLE_RATE);
+            let mut out = vec![convolver.process(input)];
+            out.extend((1..PARTITION * 5).map(|_| convolver.process([0.0; 2])));
+            let arrivals = |side: usize| -> Vec<usize> {
+                (0..out.len())
+                    .filter(|&t| out[t][side].abs() > 1e-3)
+                    .collect()
+            };
+            (arrivals(0), arrivals(1))
+        };
+        assert_eq!(out([1.0, 0.0]), (vec![PARTITION], vec![PARTITION * 2]));
+        assert_eq!(
+            out([0.0, 1.0]),
+            (vec![PARTITION * 3], vec![PARTITION * 7 / 2])
+        );
+    }
+
+    #[test]
+    fn reset_drops_the_tail_on_its_way() {
+        let mut rng = SmallRng::seed_from_u64(9);
+        let mut convolver = Convolver::new(&response(2, &mut rng), SAMPLE_RATE);
+        for _ in 0..PARTITION * 3 + 100 {
+            convolver.process([1.0, -1.0]);
+        }
+        convolver.reset();
+        assert!((0..PARTITION * 310).all(|_| convolver.process([0.0; 2]) == [0.0; 2]));
+    }
+}
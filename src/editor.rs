@@ -0,0 +1,334 @@
+use nih_plug::prelude::*;
+use nih_plug_egui::egui::{self, Ui};
+use nih_plug_egui::widgets::generic_ui::{self, GenericSlider};
+use nih_plug_egui::{create_egui_editor, EguiState};
+use std::sync::{Arc, Mutex};
+
+use crate::engine::MIDI_CHANNELS;
+use crate::preset::{changed_channels, factory_presets, ChannelPreset, Preset};
+use crate::reverb::ReverbSettings;
+use crate::sfz::SfzInstrument;
+use crate::state::EngineState;
+use crate::{
+    BrassModelChoice, BrassMuteChoice, RangePolicyChoice, SeatingChoice, SectionModelChoice,
+    SmartOrchestraVST, SmartParams, StringModelChoice, Task,
+};
+
+// Channel layout waiting for the audio thread
+type PendingLayout = Arc<Mutex<Option<[ChannelPreset; MIDI_CHANNELS]>>>;
+
+pub fn default_state() -> Arc<EguiState> {
+    EguiState::from_size(640, 760)
+}
+
+// What the editor keeps between frames
+#[derive(Default)]
+struct Choices {
+    sfz_program: u8,
+    // Name of the last preset applied
+    preset: String,
+}
+
+// Presets and files on top, every parameter below. Files are picked here and written to the engine state,
+// the background thread does the loading
+pub(crate) fn create(
+    params: Arc<SmartParams>,
+    layout: PendingLayout,
+    executor: AsyncExecutor<SmartOrchestraVST>,
+) -> Option<Box<dyn Editor>> {
+    let presets = factory_presets();
+    create_egui_editor(
+        params.editor_state.clone(),
+        Choices::default(),
+        |_, _| {},
+        move |ctx, setter, choices| {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                presets_ui(ui, &params, setter, &layout, &presets, choices);
+                ui.separator();
+                ui.heading("Files");
+                sound_font_ui(ui, &params, &executor);
+                sfz_ui(ui, &params, &executor, choices);
+                impulse_response_ui(ui, &params, &executor);
+                ui.separator();
+                generic_ui::create(ui, params.clone(), setter, GenericSlider);
+            });
+        },
+    )
+}
+
+// Factory presets and JSON files
+fn presets_ui(
+    ui: &mut Ui,
+    params: &SmartParams,
+    setter: &ParamSetter,
+    layout: &PendingLayout,
+    presets: &[Preset],
+    choices: &mut Choices,
+) {
+    let mut chosen = None;
+    ui.horizontal(|ui| {
+        egui::ComboBox::from_label("Preset")
+            .selected_text(choices.preset.as_str())
+            .show_ui(ui, |ui| {
+                for preset in presets {
+                    if ui
+                        .selectable_label(preset.name == choices.preset, &preset.name)
+                        .clicked()
+                    {
+                        chosen = Some(preset.clone());
+                    }
+                }
+            });
+        if ui.button("Import JSON...").clicked() {
+            if let Some(path) = rfd::FileDialog::new()
+                .add_filter("Preset", &["json"])
+                .pick_file()
+            {
+                match Preset::load(&path) {
+                    Ok(preset) => chosen = Some(preset),
+                    Err(err) => nih_log!("Failed to import preset {path:?}: {err:#}"),
+                }
+            }
+        }
+        if ui.button("Export JSON...").clicked() {
+            if let Some(path) = rfd::FileDialog::new()
+                .add_filter("Preset", &["json"])
+                .save_file()
+            {
+                let name = path
+                    .file_stem()
+                    .map_or("Preset".into(), |stem| stem.to_string_lossy());
+                if let Err(err) = current_preset(params, &name).save(&path) {
+                    nih_log!("Failed to export preset {path:?}: {err:#}");
+                }
+            }
+        }
+    });
+    if let Some(preset) = chosen {
+        apply_preset(params, setter, layout, &preset);
+        choices.preset = preset.name;
+    }
+}
+
+// The settings of a preset are parameters, set the way the host would set them, and its channels go to the
+// audio thread. Channels the preset leaves out keep what they play
+fn apply_preset(
+    params: &SmartParams,
+    setter: &ParamSetter,
+    layout: &PendingLayout,
+    preset: &Preset,
+) {
+    let (tone, articulation) = (&params.tone, &params.articulation);
+    set(
+        setter,
+        &params.humanize.section_size,
+        preset.section_size as i32,
+    );
+    set(setter, &articulation.vibrato_depth, preset.vibrato_depth);
+    set(setter, &articulation.vibrato_rate, preset.vibrato_rate);
+    set(setter, &articulation.vibrato_delay, preset.vibrato_delay);
+    set(setter, &tone.smart_intonation, preset.smart_intonation);
+    set(
+        setter,
+        &params.range_policy,
+        RangePolicyChoice::from_policy(preset.range_policy),
+    );
+    // A preset has one model for all the strings
+    set(
+        setter,
+        &tone.string_model,
+        StringModelChoice::from_model(preset.string_model),
+    );
+    for param in tone.section_models() {
+        set(setter, param, SectionModelChoice::Strings);
+    }
+    set(
+        setter,
+        &tone.brass_model,
+        BrassModelChoice::from_model(preset.brass_model),
+    );
+    set(
+        setter,
+        &tone.brass_mute,
+        BrassMuteChoice::from_mute(preset.brass_mute),
+    );
+    set(
+        setter,
+        &params.stage.seating,
+        SeatingChoice::from_seating(preset.seating),
+    );
+    let reverb = &params.reverb;
+    set(setter, &reverb.pre_delay_ms, preset.reverb.pre_delay_ms);
+    set(setter, &reverb.decay_s, preset.reverb.decay_s);
+    set(setter, &reverb.damping, preset.reverb.damping);
+    set(setter, &reverb.size, preset.reverb.size);
+    set(setter, &reverb.mix, preset.reverb.mix);
+    set(setter, &reverb.early_level, preset.reverb.early_level);
+
+    let Some(mut channels) = params.engine_state.read().ok().map(|state| state.channels) else {
+        return;
+    };
+    for channel in &preset.channels {
+        channels[channel.channel.clamp(1, MIDI_CHANNELS as u8) as usize - 1] = *channel;
+    }
+    if let Ok(mut pending) = layout.lock() {
+        *pending = Some(channels);
+    }
+}
+
+// What the parameters and channels make up now, as the test host's `--save-preset` exports it
+fn current_preset(params: &SmartParams, name: &str) -> Preset {
+    let (tone, articulation, reverb) = (&params.tone, &params.articulation, &params.reverb);
+    Preset {
+        name: name.to_string(),
+        section_size: params.humanize.section_size.value() as usize,
+        vibrato_depth: articulation.vibrato_depth.value(),
+        vibrato_rate: articulation.vibrato_rate.value(),
+        vibrato_delay: articulation.vibrato_delay.value(),
+        smart_intonation: tone.smart_intonation.value(),
+        range_policy: params.range_policy.value().policy(),
+        string_model: tone
+            .violins1_model
+            .value()
+            .resolve(tone.string_model.value())
+            .model(),
+        brass_model: tone.brass_model.value().model(),
+        brass_mute: tone.brass_mute.value().mute(),
+        seating: params.stage.seating.value().seating(),
+        reverb: ReverbSettings {
+            pre_delay_ms: reverb.pre_delay_ms.value(),
+            decay_s: reverb.decay_s.value(),
+            damping: reverb.damping.value(),
+            size: reverb.size.value(),
+            mix: reverb.mix.value(),
+            early_level: reverb.early_level.value(),
+        },
+        channels: params
+            .engine_state
+            .read()
+            .map(|state| changed_channels(&state.channels))
+            .unwrap_or_default(),
+    }
+}
+
+fn set<P: Param>(setter: &ParamSetter, param: &P, value: P::Plain) {
+    setter.begin_set_parameter(param);
+    setter.set_parameter(param, value);
+    setter.end_set_parameter(param);
+}
+
+// A SoundFont fills its own banks and programs, channel 10 going to its drum kit
+fn sound_font_ui(ui: &mut Ui, params: &SmartParams, executor: &AsyncExecutor<SmartOrchestraVST>) {
+    let loaded = params
+        .engine_state
+        .read()
+        .ok()
+        .and_then(|state| state.sound_font.clone());
+    ui.horizontal(|ui| {
+        match &loaded {
+            Some(path) => ui.label(format!("SF2: {}", path.display())),
+            None => ui.label("SF2: none"),
+        };
+        if ui.button("Load SF2...").clicked() {
+            if let Some(path) = rfd::FileDialog::new()
+                .add_filter("SoundFont", &["sf2"])
+                .pick_file()
+            {
+                update(params, executor, |state| state.sound_font = Some(path));
+            }
+        }
+        if loaded.is_some() && ui.button("Remove").clicked() {
+            update(params, executor, |state| state.sound_font = None);
+        }
+    });
+}
+
+// One SFZ instrument per program of bank 0
+fn sfz_ui(
+    ui: &mut Ui,
+    params: &SmartParams,
+    executor: &AsyncExecutor<SmartOrchestraVST>,
+    choices: &mut Choices,
+) {
+    let loaded = params
+        .engine_state
+        .read()
+        .map(|state| state.sfz_instruments.clone())
+        .unwrap_or_default();
+    for instrument in loaded {
+        ui.horizontal(|ui| {
+            ui.label(format!(
+                "SFZ, program {}: {}",
+                instrument.program,
+                instrument.path.display()
+            ));
+            if ui.button("Remove").clicked() {
+                update(params, executor, |state| {
+                    state
+                        .sfz_instruments
+                        .retain(|i| i.program != instrument.program)
+                });
+            }
+        });
+    }
+    ui.horizontal(|ui| {
+        ui.add(egui::Slider::new(&mut choices.sfz_program, 0..=127).text("Program"));
+        if ui.button("Load SFZ...").clicked() {
+            let program = choices.sfz_program;
+            if let Some(path) = rfd::FileDialog::new()
+                .add_filter("SFZ", &["sfz"])
+                .pick_file()
+            {
+                update(params, executor, |state| {
+                    // The new instrument takes over the program
+                    state.sfz_instruments.retain(|i| i.program != program);
+                    state.sfz_instruments.push(SfzInstrument { program, path });
+                });
+            }
+        }
+    });
+}
+
+// A hall recording for the convolution reverb, which then takes over from the algorithmic one
+fn impulse_response_ui(
+    ui: &mut Ui,
+    params: &SmartParams,
+    executor: &AsyncExecutor<SmartOrchestraVST>,
+) {
+    let loaded = params
+        .engine_state
+        .read()
+        .ok()
+        .and_then(|state| state.impulse_response.clone());
+    ui.horizontal(|ui| {
+        match &loaded {
+            Some(path) => ui.label(format!("IR: {}", path.display())),
+            None => ui.label("IR: none"),
+        };
+        if ui.button("Load IR...").clicked() {
+            if let Some(path) = rfd::FileDialog::new()
+                .add_filter("Impulse response", &["wav"])
+                .pick_file()
+            {
+                update(params, executor, |state| {
+                    state.impulse_response = Some(path)
+                });
+            }
+        }
+        if loaded.is_some() && ui.button("Remove").clicked() {
+            update(params, executor, |state| state.impulse_response = None);
+        }
+    });
+}
+
+// Changes what the engine state points at and has the background thread load it
+fn update(
+    params: &SmartParams,
+    executor: &AsyncExecutor<SmartOrchestraVST>,
+    change: impl FnOnce(&mut EngineState),
+) {
+    if let Ok(mut state) = params.engine_state.write() {
+        change(&mut state);
+    }
+    executor.execute_background(Task::LoadInstruments);
+}
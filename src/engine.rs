@@ -1,8 +1,38 @@
 use rand::{rngs::SmallRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::cc_map::{CcMap, CcTarget};
+use crate::convolution::{Convolver, ImpulseResponse};
+use crate::patch::{ArticulationMap, NoteRange, Patch, PatchBank};
+use crate::reverb::Reverb;
+use crate::sampler::{SamplePlayer, SampleSet, SampleZone};
+use crate::sf2::SoundFont;
+use crate::stage::{Stage, STAGE_BUSES};
+use crate::streaming::{StreamRequest, StreamRing, Streams, STREAM_CHUNK_FRAMES};
+use crate::tuning::{detect_chord_root, just_deviation, mts_frequency, MtsSysEx, Tuning};
+use crate::waveguide::BowedString;
 
 pub const MAX_VOICES: usize = 64;
+pub const MIDI_CHANNELS: usize = 16;
+pub const INTONATION_GLIDE_MS: f32 = 80.0;
+pub const VIBRATO_FADE_MS: f32 = 350.0;
+pub const NOISE_BURST_MS: f32 = 45.0;
+pub const NOISE_CLICK_MS: f32 = 3.0;
+pub const BRASS_BLAT_MS: f32 = 80.0;
+pub const MAX_SECTION_SIZE: usize = 8;
+pub const MAX_RANGE_VIOLATIONS: usize = 256;
+pub const HUMANIZE_SEED: u64 = 0xA11CE55;
+pub const ENSEMBLE_DETUNE_CENTS: f32 = 9.0;
+pub const ENSEMBLE_SPREAD_MS: f32 = 18.0;
+pub const ENSEMBLE_WIDTH: f32 = 0.4;
+pub const SOLO_GLIDE_MS: f32 = 60.0;
+pub const SOLO_BRIGHTNESS: f32 = 1.25;
+pub const FULL_DYNAMICS: f32 = 1.15;
+const NOTE_STACK_SIZE: usize = 16;
+const RR_DETUNE_STEPS: usize = 4;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Articulation {
     Staccato,
     Marcato,
@@ -19,6 +49,776 @@ pub enum DynamicLayer {
     Ff,
 }
 
+impl DynamicLayer {
+    // Layer of a note velocity and the level it plays at
+    pub fn from_velocity(velocity: u8) -> (Self, f32) {
+        match velocity {
+            0..=29 => (DynamicLayer::Pp, 0.20),
+            30..=49 => (DynamicLayer::P, 0.32),
+            50..=69 => (DynamicLayer::Mp, 0.45),
+            70..=89 => (DynamicLayer::Mf, 0.6),
+            90..=109 => (DynamicLayer::F, 0.78),
+            _ => (DynamicLayer::Ff, 0.95),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Section {
+    Violins1,
+    Violins2,
+    Violas,
+    Cellos,
+    Basses,
+    Flutes,
+    Oboes,
+    Clarinets,
+    Bassoons,
+    Horns,
+    Trumpets,
+    Trombones,
+    Tuba,
+}
+
+impl Section {
+    pub const ALL: [Section; 13] = [
+        Section::Violins1,
+        Section::Violins2,
+        Section::Violas,
+        Section::Cellos,
+        Section::Basses,
+        Section::Flutes,
+        Section::Oboes,
+        Section::Clarinets,
+        Section::Bassoons,
+        Section::Horns,
+        Section::Trumpets,
+        Section::Trombones,
+        Section::Tuba,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Section::Violins1 => "Violins I",
+            Section::Violins2 => "Violins II",
+            Section::Violas => "Violas",
+            Section::Cellos => "Cellos",
+            Section::Basses => "Basses",
+            Section::Flutes => "Flutes",
+            Section::Oboes => "Oboes",
+            Section::Clarinets => "Clarinets",
+            Section::Bassoons => "Bassoons",
+            Section::Horns => "Horns",
+            Section::Trumpets => "Trumpets",
+            Section::Trombones => "Trombones",
+            Section::Tuba => "Tuba",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "violins1" | "violinsi" => Some(Section::Violins1),
+            "violins2" | "violinsii" => Some(Section::Violins2),
+            "violas" => Some(Section::Violas),
+            "cellos" => Some(Section::Cellos),
+            "basses" => Some(Section::Basses),
+            "flutes" => Some(Section::Flutes),
+            "oboes" => Some(Section::Oboes),
+            "clarinets" => Some(Section::Clarinets),
+            "bassoons" => Some(Section::Bassoons),
+            "horns" => Some(Section::Horns),
+            "trumpets" => Some(Section::Trumpets),
+            "trombones" => Some(Section::Trombones),
+            "tuba" => Some(Section::Tuba),
+            _ => None,
+        }
+    }
+
+    // Closest section for a General MIDI program, the other sounds borrow the first violins' behaviour
+    pub fn from_gm_program(program: u8) -> Option<Self> {
+        match program {
+            40 | 44..=45 | 48..=51 => Some(Section::Violins1),
+            41 => Some(Section::Violas),
+            42 => Some(Section::Cellos),
+            43 => Some(Section::Basses),
+            56 | 59 | 61 => Some(Section::Trumpets),
+            57 => Some(Section::Trombones),
+            58 => Some(Section::Tuba),
+            60 => Some(Section::Horns),
+            68..=69 => Some(Section::Oboes),
+            70 => Some(Section::Bassoons),
+            71 => Some(Section::Clarinets),
+            72..=79 => Some(Section::Flutes),
+            _ => None,
+        }
+    }
+
+    pub fn family(self) -> Family {
+        match self {
+            Section::Violins1
+            | Section::Violins2
+            | Section::Violas
+            | Section::Cellos
+            | Section::Basses => Family::Strings,
+            Section::Flutes | Section::Oboes | Section::Clarinets | Section::Bassoons => {
+                Family::Woodwinds
+            }
+            Section::Horns | Section::Trumpets | Section::Trombones | Section::Tuba => {
+                Family::Brass
+            }
+        }
+    }
+
+    // Main resonance of the bell, which keeps the low brass dark even when played loud
+    pub fn brass_formant(self) -> f32 {
+        match self {
+            Section::Horns => 450.0,
+            Section::Trumpets => 1300.0,
+            Section::Trombones => 600.0,
+            Section::Tuba => 280.0,
+            _ => 1000.0,
+        }
+    }
+
+    // Sounding ranges of the real instruments, so basses and horns already include their transposition
+    pub fn range(self) -> NoteRange {
+        match self {
+            Section::Violins1 | Section::Violins2 => NoteRange::new(55, 103),
+            Section::Violas => NoteRange::new(48, 91),
+            Section::Cellos => NoteRange::new(36, 81),
+            Section::Basses => NoteRange::new(28, 67),
+            Section::Flutes => NoteRange::new(60, 96),
+            Section::Oboes => NoteRange::new(58, 91),
+            Section::Clarinets => NoteRange::new(50, 94),
+            Section::Bassoons => NoteRange::new(34, 75),
+            Section::Horns => NoteRange::new(34, 77),
+            Section::Trumpets => NoteRange::new(54, 84),
+            Section::Trombones => NoteRange::new(40, 72),
+            Section::Tuba => NoteRange::new(28, 65),
+        }
+    }
+
+    pub fn vibrato(self) -> VibratoSettings {
+        // Strings use a wide vibrato, brass a narrow one, and clarinets, horns and tuba traditionally none
+        match self {
+            Section::Violins1 | Section::Violins2 => VibratoSettings::new(5.8, 18.0, 250.0),
+            Section::Violas => VibratoSettings::new(5.5, 16.0, 250.0),
+            Section::Cellos => VibratoSettings::new(5.2, 15.0, 300.0),
+            Section::Basses => VibratoSettings::new(4.8, 10.0, 350.0),
+            Section::Flutes => VibratoSettings::new(5.0, 10.0, 300.0),
+            Section::Oboes => VibratoSettings::new(5.2, 8.0, 300.0),
+            Section::Bassoons => VibratoSettings::new(4.8, 5.0, 350.0),
+            Section::Trumpets => VibratoSettings::new(5.5, 4.0, 400.0),
+            Section::Trombones => VibratoSettings::new(5.0, 3.0, 400.0),
+            Section::Clarinets | Section::Horns | Section::Tuba => {
+                VibratoSettings::new(5.0, 0.0, 0.0)
+            }
+        }
+    }
+
+    // Bow scrape dominates string attacks, breath runs under wind notes and woodwind keys click
+    pub fn noise(self) -> NoiseSettings {
+        match self {
+            Section::Violins1 | Section::Violins2 => NoiseSettings::new(0.35, 0.03, 0.0, 3500.0),
+            Section::Violas => NoiseSettings::new(0.35, 0.03, 0.0, 3000.0),
+            Section::Cellos => NoiseSettings::new(0.4, 0.03, 0.0, 2400.0),
+            Section::Basses => NoiseSettings::new(0.45, 0.04, 0.0, 1600.0),
+            Section::Flutes => NoiseSettings::new(0.25, 0.12, 0.06, 2600.0),
+            Section::Oboes => NoiseSettings::new(0.12, 0.04, 0.1, 2200.0),
+            Section::Clarinets => NoiseSettings::new(0.15, 0.06, 0.1, 1800.0),
+            Section::Bassoons => NoiseSettings::new(0.15, 0.05, 0.12, 1200.0),
+            Section::Horns => NoiseSettings::new(0.15, 0.03, 0.02, 1200.0),
+            Section::Trumpets => NoiseSettings::new(0.2, 0.03, 0.03, 2000.0),
+            Section::Trombones => NoiseSettings::new(0.18, 0.03, 0.02, 1300.0),
+            Section::Tuba => NoiseSettings::new(0.2, 0.04, 0.02, 800.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SynthModel {
+    Subtractive,
+    BowedString,
+    Brass,
+    Sampled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BrassMute {
+    Open,
+    Straight,
+    Cup,
+    Stopped,
+}
+
+impl BrassMute {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "open" => Some(BrassMute::Open),
+            "straight" => Some(BrassMute::Straight),
+            "cup" => Some(BrassMute::Cup),
+            "stopped" => Some(BrassMute::Stopped),
+            _ => None,
+        }
+    }
+
+    // Centre, damping and level of the resonance the mute adds, and how much of the open bell gets past it
+    fn resonance(self) -> (f32, f32, f32, f32) {
+        match self {
+            BrassMute::Open => (1000.0, 1.0, 0.0, 1.0),
+            BrassMute::Straight => (1800.0, 0.5, 0.7, 0.3),
+            BrassMute::Cup => (700.0, 0.8, 0.6, 0.25),
+            BrassMute::Stopped => (2800.0, 0.3, 0.8, 0.15),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    Strings,
+    Woodwinds,
+    Brass,
+}
+
+// What happens to a note outside the playable range of its channel's patch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RangePolicy {
+    Ignore,
+    Fold,
+    HandOff,
+    Flag,
+}
+
+impl RangePolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "ignore" => Some(RangePolicy::Ignore),
+            "fold" => Some(RangePolicy::Fold),
+            "handoff" | "hand-off" => Some(RangePolicy::HandOff),
+            "flag" => Some(RangePolicy::Flag),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RangeViolation {
+    pub sample: i64,
+    pub channel: u8,
+    pub note: u8,
+    pub section: Section,
+    // The note and patch actually played, if any
+    pub played: Option<(u8, usize)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Vibrato {
+    phase: f32,
+    rate_hz: f32,
+    depth_cents: f32,
+    delay_samps: f32,
+    fade_samps: f32,
+    age: f32,
+}
+
+impl Vibrato {
+    pub fn new() -> Self {
+        Self {
+            phase: 0.0,
+            rate_hz: 5.0,
+            depth_cents: 0.0,
+            delay_samps: 0.0,
+            fade_samps: 1.0,
+            age: 0.0,
+        }
+    }
+
+    pub fn trigger(
+        &mut self,
+        settings: VibratoSettings,
+        rate_variation: f32,
+        phase: f32,
+        sample_rate: f32,
+    ) {
+        self.phase = phase;
+        self.rate_hz = settings.rate_hz * rate_variation;
+        self.depth_cents = settings.depth_cents;
+        self.delay_samps = ms_to_samples(settings.delay_ms, sample_rate);
+        self.fade_samps = ms_to_samples(VIBRATO_FADE_MS, sample_rate);
+        self.age = 0.0;
+    }
+
+    // Returns the pitch ratio for this sample
+    pub fn next(&mut self, depth_scale: f32, rate_scale: f32, sample_rate: f32) -> f32 {
+        self.age += 1.0;
+        self.phase = (self.phase + self.rate_hz * rate_scale / sample_rate) % 1.0;
+
+        let onset = ((self.age - self.delay_samps) / self.fade_samps).clamp(0.0, 1.0);
+        let cents =
+            (self.phase * std::f32::consts::TAU).sin() * self.depth_cents * depth_scale * onset;
+        // First-order approximation of 2^(cents / 1200), accurate to well below a cent at vibrato depths
+        1.0 + cents * (std::f32::consts::LN_2 / 1200.0)
+    }
+}
+
+// Levels are relative to the tonal part of the voice
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct NoiseSettings {
+    pub attack: f32,
+    pub sustain: f32,
+    pub click: f32,
+    pub color_hz: f32,
+}
+
+impl NoiseSettings {
+    pub const fn new(attack: f32, sustain: f32, click: f32, color_hz: f32) -> Self {
+        Self {
+            attack,
+            sustain,
+            click,
+            color_hz,
+        }
+    }
+}
+
+// Band-passed noise made of a decaying onset burst, a short click and a sustained part that follows
+// the voice envelope
+#[derive(Debug, Clone, Copy)]
+pub struct Noise {
+    settings: NoiseSettings,
+    seed: u32,
+    coefficient: f32,
+    low: f32,
+    band: f32,
+    burst: f32,
+    burst_decay: f32,
+    click: f32,
+    click_decay: f32,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Self {
+            settings: NoiseSettings::new(0.0, 0.0, 0.0, 1000.0),
+            seed: 1,
+            coefficient: 0.0,
+            low: 0.0,
+            band: 0.0,
+            burst: 0.0,
+            burst_decay: 0.0,
+            click: 0.0,
+            click_decay: 0.0,
+        }
+    }
+
+    pub fn trigger(
+        &mut self,
+        settings: NoiseSettings,
+        velocity: u8,
+        legato: bool,
+        seed: u32,
+        sample_rate: f32,
+    ) {
+        self.settings = settings;
+        self.seed = seed | 1;
+        // The state-variable filter is only stable well below Nyquist
+        let color_hz = settings.color_hz.min(sample_rate / 6.0);
+        self.coefficient = 2.0 * (std::f32::consts::PI * color_hz / sample_rate).sin();
+        self.low = 0.0;
+        self.band = 0.0;
+        // Harder attacks scrape and chiff more, while a legato start barely shows the bow change
+        let attack = if legato {
+            0.3
+        } else {
+            0.5 + velocity as f32 / 127.0
+        };
+        self.burst = settings.attack * attack;
+        self.burst_decay = decay_factor(NOISE_BURST_MS, sample_rate);
+        self.click = settings.click;
+        self.click_decay = decay_factor(NOISE_CLICK_MS, sample_rate);
+    }
+
+    // Key or valve noise when a legato line changes note
+    pub fn click(&mut self) {
+        self.click = self.settings.click;
+    }
+
+    // Short notes end with an audible stop of the bow or tongue
+    pub fn release(&mut self, articulation: Articulation) {
+        let stop = match articulation {
+            Articulation::Staccato => 0.3,
+            Articulation::Marcato => 0.15,
+            Articulation::Sustain => 0.0,
+        };
+        self.click = self.click.max(self.settings.attack * stop);
+    }
+
+    pub fn next(&mut self, level: f32) -> f32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        let white = self.seed as f32 / u32::MAX as f32 * 2.0 - 1.0;
+
+        self.low += self.coefficient * self.band;
+        let high = white - self.low - self.band;
+        self.band += self.coefficient * high;
+
+        let out = self.band * (self.burst + self.click + self.settings.sustain * level);
+        self.burst *= self.burst_decay;
+        self.click *= self.click_decay;
+        out
+    }
+}
+
+// Chamberlin state-variable filter, used for its band-pass output
+#[derive(Debug, Clone, Copy)]
+pub struct Resonator {
+    low: f32,
+    band: f32,
+}
+
+impl Resonator {
+    pub fn new() -> Self {
+        Self {
+            low: 0.0,
+            band: 0.0,
+        }
+    }
+
+    pub fn band_pass(&mut self, input: f32, centre_hz: f32, damping: f32, sample_rate: f32) -> f32 {
+        // Only stable well below Nyquist
+        let coefficient =
+            2.0 * (std::f32::consts::PI * centre_hz.min(sample_rate / 6.0) / sample_rate).sin();
+        self.low += coefficient * self.band;
+        let high = input - self.low - damping * self.band;
+        self.band += coefficient * high;
+        self.band
+    }
+}
+
+// Modified FM source, exp(k (cos φ - 1)), whose harmonics fan out as the index k grows just like a
+// brass tone brightens with lip tension, followed by the bell resonance and an optional mute
+#[derive(Debug, Clone, Copy)]
+pub struct Brass {
+    phase: f32,
+    formant_hz: f32,
+    mute: BrassMute,
+    seed: u32,
+    blat: f32,
+    blat_decay: f32,
+    dc: f32,
+    lowpass: f32,
+    bell: Resonator,
+    muted: Resonator,
+}
+
+impl Brass {
+    pub fn new() -> Self {
+        Self {
+            phase: 0.0,
+            formant_hz: 1000.0,
+            mute: BrassMute::Open,
+            seed: 1,
+            blat: 0.0,
+            blat_decay: 0.0,
+            dc: 0.0,
+            lowpass: 0.0,
+            bell: Resonator::new(),
+            muted: Resonator::new(),
+        }
+    }
+
+    pub fn trigger(
+        &mut self,
+        formant_hz: f32,
+        mute: BrassMute,
+        velocity: u8,
+        seed: u32,
+        sample_rate: f32,
+    ) {
+        self.phase = 0.0;
+        self.formant_hz = formant_hz;
+        self.mute = mute;
+        self.seed = seed | 1;
+        // Only fortissimo attacks split into the brassy blat before the lips settle
+        self.blat = ((velocity as f32 - 100.0) / 27.0).max(0.0) * 0.8;
+        self.blat_decay = decay_factor(BRASS_BLAT_MS, sample_rate);
+        self.dc = 0.0;
+        self.lowpass = 0.0;
+        self.bell = Resonator::new();
+        self.muted = Resonator::new();
+    }
+
+    // `brightness` is 0..1 and follows the dynamic layer, CC1 and the envelope
+    pub fn next(&mut self, freq_hz: f32, brightness: f32, cutoff_hz: f32, sample_rate: f32) -> f32 {
+        self.phase = (self.phase + freq_hz / sample_rate) % 1.0;
+
+        // At the top of the dynamic range the lips start to rasp, jittering the spectrum
+        let rasp = ((brightness - 0.85) / 0.15).clamp(0.0, 1.0);
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        let jitter = (self.seed as f32 / u32::MAX as f32 * 2.0 - 1.0) * 0.2 * rasp;
+
+        let index = 0.3 + 12.0 * brightness * brightness * (1.0 + self.blat + jitter);
+        self.blat *= self.blat_decay;
+        let pulse = (index * ((self.phase * std::f32::consts::TAU).cos() - 1.0)).exp();
+        // The pulse narrows as the index grows, so its level is restored along with removing the offset
+        self.dc += (pulse - self.dc) * (std::f32::consts::TAU * 20.0 / sample_rate);
+        let source = (pulse - self.dc) * (1.0 + index).sqrt() * 0.35;
+
+        let bell = source
+            + self
+                .bell
+                .band_pass(source, self.formant_hz, 1.0, sample_rate)
+                * 0.8;
+        let (centre_hz, damping, resonance, open) = self.mute.resonance();
+        let tone = if self.mute == BrassMute::Open {
+            bell
+        } else {
+            bell * open + self.muted.band_pass(bell, centre_hz, damping, sample_rate) * resonance
+        };
+
+        let coefficient = 1.0 - (-std::f32::consts::TAU * cutoff_hz / sample_rate).exp();
+        self.lowpass += (tone - self.lowpass) * coefficient;
+        self.lowpass
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VoiceControls<'a> {
+    pub cutoff_hz: f32,
+    pub vibrato_depth: f32,
+    pub vibrato_rate: f32,
+    pub pitch_ratio: f32,
+    pub amplitude: f32,
+    pub pan_offset: f32,
+    // Channel dynamics (CC1) and bow speed for the physical models, both 0..1
+    pub dynamics: f32,
+    pub bow_speed: f32,
+    // Emphasis at the cutoff, 0..1
+    pub resonance: f32,
+    // Recording played by a sampled voice, and the voice's ring for the part that streams from disk
+    pub zone: Option<&'a SampleZone>,
+    pub stream: Option<&'a StreamRing>,
+}
+
+pub const MOD_LFOS: usize = 3;
+pub const MOD_ENVELOPES: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModSource {
+    Lfo(usize),
+    Envelope(usize),
+    Velocity,
+    KeyTrack,
+    Aftertouch,
+    Cc(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModDestination {
+    // Semitones
+    Pitch,
+    // Octaves
+    Cutoff,
+    // Gain offset around unity
+    Amplitude,
+    // Offset from the voice position, where 1.0 spans the full stereo field
+    Pan,
+    // Offset added to the vibrato depth scale
+    VibratoDepth,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ModRoute {
+    pub source: ModSource,
+    pub destination: ModDestination,
+    pub amount: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Square,
+    Saw,
+    SampleAndHold,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LfoSettings {
+    pub shape: LfoShape,
+    pub rate_hz: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModMatrix {
+    pub lfos: [LfoSettings; MOD_LFOS],
+    pub envelopes: [EnvelopeShape; MOD_ENVELOPES],
+    pub routes: Vec<ModRoute>,
+}
+
+impl Default for ModMatrix {
+    fn default() -> Self {
+        Self {
+            lfos: [
+                LfoSettings {
+                    shape: LfoShape::Sine,
+                    rate_hz: 0.5,
+                },
+                LfoSettings {
+                    shape: LfoShape::Triangle,
+                    rate_hz: 2.0,
+                },
+                LfoSettings {
+                    shape: LfoShape::SampleAndHold,
+                    rate_hz: 4.0,
+                },
+            ],
+            envelopes: [
+                EnvelopeShape::new(5.0, 300.0, 0.0, 200.0),
+                EnvelopeShape::new(400.0, 800.0, 0.6, 500.0),
+            ],
+            routes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ModSources<'a> {
+    pub lfos: &'a [f32; MOD_LFOS],
+    pub envelopes: [f32; MOD_ENVELOPES],
+    pub velocity: f32,
+    pub key_track: f32,
+    pub aftertouch: f32,
+    pub controllers: &'a [f32; 128],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModOutputs {
+    pub pitch: f32,
+    pub cutoff: f32,
+    pub amplitude: f32,
+    pub pan: f32,
+    pub vibrato_depth: f32,
+}
+
+impl ModMatrix {
+    pub fn evaluate(&self, sources: &ModSources) -> ModOutputs {
+        let mut out = ModOutputs {
+            pitch: 0.0,
+            cutoff: 0.0,
+            amplitude: 0.0,
+            pan: 0.0,
+            vibrato_depth: 0.0,
+        };
+
+        for route in &self.routes {
+            let value = match route.source {
+                ModSource::Lfo(i) => sources.lfos.get(i).copied().unwrap_or(0.0),
+                ModSource::Envelope(i) => sources.envelopes.get(i).copied().unwrap_or(0.0),
+                ModSource::Velocity => sources.velocity,
+                ModSource::KeyTrack => sources.key_track,
+                ModSource::Aftertouch => sources.aftertouch,
+                ModSource::Cc(cc) => sources.controllers.get(cc as usize).copied().unwrap_or(0.0),
+            } * route.amount;
+
+            match route.destination {
+                ModDestination::Pitch => out.pitch += value,
+                ModDestination::Cutoff => out.cutoff += value,
+                ModDestination::Amplitude => out.amplitude += value,
+                ModDestination::Pan => out.pan += value,
+                ModDestination::VibratoDepth => out.vibrato_depth += value,
+            }
+        }
+
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Lfo {
+    phase: f32,
+    held: f32,
+    noise: u32,
+}
+
+impl Lfo {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            phase: 0.0,
+            held: 0.0,
+            noise: seed | 1,
+        }
+    }
+
+    // Bipolar output in -1..1
+    pub fn next(&mut self, settings: &LfoSettings, sample_rate: f32) -> f32 {
+        let previous = self.phase;
+        self.phase = (self.phase + settings.rate_hz / sample_rate) % 1.0;
+
+        match settings.shape {
+            LfoShape::Sine => (self.phase * std::f32::consts::TAU).sin(),
+            LfoShape::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            LfoShape::Square => {
+                if self.phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            LfoShape::Saw => self.phase * 2.0 - 1.0,
+            LfoShape::SampleAndHold => {
+                if self.phase < previous {
+                    // xorshift32, cheap enough to run on the audio thread without touching the shared RNG
+                    self.noise ^= self.noise << 13;
+                    self.noise ^= self.noise >> 17;
+                    self.noise ^= self.noise << 5;
+                    self.held = self.noise as f32 / u32::MAX as f32 * 2.0 - 1.0;
+                }
+                self.held
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct VibratoSettings {
+    pub rate_hz: f32,
+    pub depth_cents: f32,
+    pub delay_ms: f32,
+}
+
+impl VibratoSettings {
+    pub const fn new(rate_hz: f32, depth_cents: f32, delay_ms: f32) -> Self {
+        Self {
+            rate_hz,
+            depth_cents,
+            delay_ms,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EnvelopeShape {
+    pub attack_ms: f32,
+    pub decay_ms: f32,
+    pub sustain: f32,
+    pub release_ms: f32,
+}
+
+impl EnvelopeShape {
+    pub const fn new(attack_ms: f32, decay_ms: f32, sustain: f32, release_ms: f32) -> Self {
+        Self {
+            attack_ms,
+            decay_ms,
+            sustain,
+            release_ms,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct SmoothedValue {
     current: f32,
@@ -94,21 +894,11 @@ impl Envelope {
         }
     }
 
-    pub fn trigger(&mut self, articulation: Articulation, legato: bool, sample_rate: f32) {
-        let (attack_ms, decay_ms, sustain) = if legato {
-            (30.0, 160.0, 0.85)
-        } else {
-            match articulation {
-                Articulation::Staccato => (2.0, 45.0, 0.35),
-                Articulation::Marcato => (10.0, 90.0, 0.6),
-                Articulation::Sustain => (20.0, 160.0, 0.82),
-            }
-        };
-
-        self.attack_samps = ms_to_samples(attack_ms, sample_rate);
-        self.decay_samps = ms_to_samples(decay_ms, sample_rate);
-        self.release_samps = ms_to_samples(90.0, sample_rate);
-        self.sustain_level = sustain;
+    pub fn trigger(&mut self, shape: EnvelopeShape, sample_rate: f32) {
+        self.attack_samps = ms_to_samples(shape.attack_ms, sample_rate);
+        self.decay_samps = ms_to_samples(shape.decay_ms, sample_rate);
+        self.release_samps = ms_to_samples(shape.release_ms, sample_rate);
+        self.sustain_level = shape.sustain;
         self.stage = EnvelopeStage::Attack;
     }
 
@@ -117,6 +907,13 @@ impl Envelope {
         self.stage = EnvelopeStage::Release;
     }
 
+    // Releases using the time set by the last trigger
+    pub fn begin_release(&mut self) {
+        if self.stage != EnvelopeStage::Idle {
+            self.stage = EnvelopeStage::Release;
+        }
+    }
+
     pub fn next(&mut self) -> f32 {
         match self.stage {
             EnvelopeStage::Idle => 0.0,
@@ -152,6 +949,10 @@ impl Envelope {
     pub fn is_idle(&self) -> bool {
         self.stage == EnvelopeStage::Idle
     }
+
+    pub fn is_releasing(&self) -> bool {
+        self.stage == EnvelopeStage::Release
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -186,46 +987,132 @@ impl LegatoEngine {
     }
 }
 
+// Keys held on a channel, most recent last, so a solo line can fall back to the previous note
 #[derive(Debug, Clone, Copy)]
+pub struct NoteStack {
+    notes: [u8; NOTE_STACK_SIZE],
+    len: usize,
+}
+
+impl NoteStack {
+    pub fn new() -> Self {
+        Self {
+            notes: [0; NOTE_STACK_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, note: u8) {
+        self.remove(note);
+        if self.len == NOTE_STACK_SIZE {
+            self.notes.copy_within(1.., 0);
+            self.len -= 1;
+        }
+        self.notes[self.len] = note;
+        self.len += 1;
+    }
+
+    pub fn remove(&mut self, note: u8) {
+        if let Some(index) = self.notes[..self.len].iter().position(|&n| n == note) {
+            self.notes.copy_within(index + 1..self.len, index);
+            self.len -= 1;
+        }
+    }
+
+    pub fn last(&self) -> Option<u8> {
+        self.notes[..self.len].last().copied()
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Voice {
     pub active: bool,
+    pub channel: u8,
     pub note: u8,
+    pub patch: usize,
     velocity: u8,
     phase_saw: f32,
     phase_sine: f32,
     freq: f32,
+    base_hz: f32,
     pub envelope: Envelope,
     pub articulation: Articulation,
     pub start_sample: i64,
     pub legato_amount: f32,
     dynamic_gain: SmoothedValue,
+    intonation: SmoothedValue,
+    pub vibrato: Vibrato,
+    pub mod_envelopes: [Envelope; MOD_ENVELOPES],
+    pub noise: Noise,
+    model: SynthModel,
+    bowed: BowedString,
+    pub brass: Brass,
+    pub sampler: SamplePlayer,
+    pub pressure: f32,
+    resonators: [Resonator; 2],
     pan: f32,
+    gain: f32,
+    start_delay: u32,
 }
 
 impl Voice {
     pub fn new() -> Self {
+        Self::with_bowed(BowedString::new())
+    }
+
+    // Back to a silent voice, keeping the waveguide's buffers so the audio thread never allocates
+    pub fn reset(&mut self) {
+        let mut bowed = std::mem::replace(&mut self.bowed, BowedString::empty());
+        bowed.reset();
+        *self = Self::with_bowed(bowed);
+    }
+
+    fn with_bowed(bowed: BowedString) -> Self {
         Self {
             active: false,
+            channel: 0,
             note: 0,
+            patch: 0,
             velocity: 0,
             phase_saw: 0.0,
             phase_sine: 0.0,
             freq: 440.0,
+            base_hz: 440.0,
             envelope: Envelope::new(),
             articulation: Articulation::Sustain,
             start_sample: 0,
             legato_amount: 0.0,
             dynamic_gain: SmoothedValue::new(0.5),
+            intonation: SmoothedValue::new(1.0),
+            vibrato: Vibrato::new(),
+            mod_envelopes: [Envelope::new(); MOD_ENVELOPES],
+            noise: Noise::new(),
+            model: SynthModel::Subtractive,
+            bowed,
+            brass: Brass::new(),
+            sampler: SamplePlayer::new(),
+            pressure: 0.0,
+            resonators: [Resonator::new(); 2],
             pan: 0.5,
+            gain: 1.0,
+            start_delay: 0,
         }
     }
 
     #[allow(clippy::too_many_arguments)]
     pub fn start(
         &mut self,
+        channel: u8,
         note: u8,
+        base_hz: f32,
         velocity: u8,
+        patch: usize,
         articulation: Articulation,
+        shape: EnvelopeShape,
         layer_gain: f32,
         legato: bool,
         sample_rate: f32,
@@ -233,57 +1120,193 @@ impl Voice {
         humanization: f32,
     ) {
         self.active = true;
+        self.channel = channel;
         self.note = note;
+        self.patch = patch;
         self.velocity = velocity;
         self.articulation = articulation;
-        self.freq = midi_note_to_hz(note as f32 + humanization);
+        self.base_hz = base_hz;
+        self.freq = base_hz * semitones_to_ratio(humanization);
         self.start_sample = global_sample;
         self.legato_amount = if legato { 0.08 } else { 0.0 };
         self.dynamic_gain.set_immediate(layer_gain);
-        self.envelope.trigger(articulation, legato, sample_rate);
+        self.intonation.set_immediate(1.0);
+        self.pressure = 0.0;
+        self.resonators = [Resonator::new(); 2];
+        self.envelope.trigger(shape, sample_rate);
         self.pan = 0.5 + humanization * 0.03;
+        self.gain = 1.0;
+        self.start_delay = 0;
     }
 
-    pub fn note_off(&mut self, sample_rate: f32) {
-        let release = match self.articulation {
-            Articulation::Staccato => 45.0,
-            Articulation::Marcato => 100.0,
-            Articulation::Sustain => 180.0,
-        };
-        self.envelope.release(release, sample_rate);
+    pub fn set_model(&mut self, model: SynthModel) {
+        self.model = model;
+        if model == SynthModel::BowedString {
+            self.bowed.reset();
+        }
     }
 
-    pub fn render(&mut self, sample_rate: f32, cutoff_hz: f32) -> (f32, f32) {
-        if !self.active {
-            return (0.0, 0.0);
+    // Places one player of an ensemble note: its stereo position, share of the level and onset delay
+    pub fn set_player(&mut self, pan: f32, gain: f32, start_delay: u32) {
+        self.pan = pan.clamp(0.0, 1.0);
+        self.gain = gain;
+        self.start_delay = start_delay;
+    }
+
+    pub fn note_off(&mut self, release_ms: f32, sample_rate: f32) {
+        self.envelope.release(release_ms, sample_rate);
+        self.noise.release(self.articulation);
+        for envelope in &mut self.mod_envelopes {
+            envelope.begin_release();
         }
+    }
 
-        let glide_target = midi_note_to_hz(self.note as f32);
-        self.freq += (glide_target - self.freq) * self.legato_amount;
+    // Moves a held voice to another note without retriggering it, gliding from the current pitch
+    pub fn legato_to(
+        &mut self,
+        note: u8,
+        base_hz: f32,
+        velocity: u8,
+        glide_ms: f32,
+        sample_rate: f32,
+    ) {
+        self.note = note;
+        self.velocity = velocity;
+        self.base_hz = base_hz;
+        self.legato_amount = 1.0 - decay_factor(glide_ms, sample_rate);
+        self.noise.click();
+    }
+
+    pub fn fast_fade(&mut self, sample_rate: f32) {
+        self.envelope.release(5.0, sample_rate);
+    }
+
+    pub fn trigger_mod_envelopes(
+        &mut self,
+        shapes: &[EnvelopeShape; MOD_ENVELOPES],
+        sample_rate: f32,
+    ) {
+        for (envelope, shape) in self.mod_envelopes.iter_mut().zip(shapes) {
+            envelope.trigger(*shape, sample_rate);
+        }
+    }
+
+    pub fn velocity(&self) -> u8 {
+        self.velocity
+    }
+
+    pub fn retune(&mut self, base_hz: f32) {
+        // Scaling keeps the humanization and any glide in progress relative to the new pitch
+        self.freq *= base_hz / self.base_hz;
+        self.base_hz = base_hz;
+    }
 
-        let inc = self.freq / sample_rate;
-        self.phase_saw = (self.phase_saw + inc) % 1.0;
-        self.phase_sine = (self.phase_sine + inc) % 1.0;
+    pub fn render(&mut self, sample_rate: f32, controls: &VoiceControls) -> (f32, f32) {
+        if !self.active {
+            return (0.0, 0.0);
+        }
+        if self.start_delay > 0 {
+            self.start_delay -= 1;
+            return (0.0, 0.0);
+        }
 
-        let saw = self.phase_saw * 2.0 - 1.0;
-        let sine = (self.phase_sine * std::f32::consts::TAU).sin();
-        let mut sample = saw * 0.65 + sine * 0.35;
+        self.freq += (self.base_hz - self.freq) * self.legato_amount;
+
+        let vibrato = self
+            .vibrato
+            .next(controls.vibrato_depth, controls.vibrato_rate, sample_rate);
+        let hz = self.freq * self.intonation.next() * vibrato * controls.pitch_ratio;
+        let level = self.envelope.next();
+
+        let (left, right) = match self.model {
+            SynthModel::Subtractive => {
+                let inc = hz / sample_rate;
+                self.phase_saw = (self.phase_saw + inc) % 1.0;
+                self.phase_sine = (self.phase_sine + inc) % 1.0;
+
+                let saw = self.phase_saw * 2.0 - 1.0;
+                let sine = (self.phase_sine * std::f32::consts::TAU).sin();
+                let cutoff_norm = (controls.cutoff_hz / (sample_rate * 0.5)).clamp(0.001, 0.99);
+                let tone = (saw * 0.65 + sine * 0.35) * cutoff_norm * level;
+                (tone, tone)
+            }
+            // The envelope drives the bow instead of the output, so attacks and legato come from the
+            // string itself; the remaining ring fades with the tail of the release
+            SynthModel::BowedString => {
+                let tone = self.bowed.next(
+                    hz,
+                    controls.dynamics,
+                    level * controls.bow_speed,
+                    controls.cutoff_hz,
+                    sample_rate,
+                ) * (level * 4.0).min(1.0);
+                (tone, tone)
+            }
+            // Brass attacks speak dark and open up as the envelope rises
+            SynthModel::Brass => {
+                let dynamics = (self.velocity as f32 / 127.0 + controls.dynamics) * 0.5;
+                let brightness = dynamics * (0.3 + 0.7 * level);
+                let tone = self
+                    .brass
+                    .next(hz, brightness, controls.cutoff_hz, sample_rate)
+                    * level;
+                (tone, tone)
+            }
+            SynthModel::Sampled => match controls.zone {
+                Some(zone) => {
+                    let (left, right) = self.sampler.next(zone, controls.stream, hz, sample_rate);
+                    (left * level, right * level)
+                }
+                None => (0.0, 0.0),
+            },
+        };
 
-        let cutoff_norm = (cutoff_hz / (sample_rate * 0.5)).clamp(0.001, 0.99);
-        sample *= cutoff_norm;
+        // A band-pass at the cutoff added on top of the tone, narrowing as the resonance rises, so every
+        // model gets the same peak and zero resonance leaves it untouched
+        let (left, right) = if controls.resonance > 0.0 {
+            let damping = 1.0 - 0.75 * controls.resonance;
+            let emphasis = |resonator: &mut Resonator, input: f32| {
+                let band = resonator.band_pass(input, controls.cutoff_hz, damping, sample_rate);
+                input + band * controls.resonance * 0.5
+            };
+            let [l, r] = &mut self.resonators;
+            (emphasis(l, left), emphasis(r, right))
+        } else {
+            (left, right)
+        };
 
-        sample *= self.envelope.next() * self.dynamic_gain.next();
+        // The noise bursts sound before the tone has built up, so only its sustained part follows the envelope
+        let noise = self.noise.next(level);
+        let gain = self.dynamic_gain.next() * controls.amplitude * self.gain;
 
         if self.envelope.is_idle() {
             self.active = false;
             return (0.0, 0.0);
         }
 
-        let left = sample * (1.0 - self.pan).sqrt();
-        let right = sample * self.pan.sqrt();
+        // Balance rather than pan, so stereo recordings keep their image
+        let pan = (self.pan + controls.pan_offset).clamp(0.0, 1.0);
+        let left = (left + noise) * gain * (1.0 - pan).sqrt();
+        let right = (right + noise) * gain * pan.sqrt();
         (left, right)
     }
 
+    pub fn base_hz(&self) -> f32 {
+        self.base_hz
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.active && !self.envelope.is_releasing()
+    }
+
+    pub fn set_intonation(&mut self, ratio: f32, glide_ms: f32, sample_rate: f32) {
+        if glide_ms <= 0.0 {
+            self.intonation.set_immediate(ratio);
+        } else {
+            self.intonation.set_target(ratio, glide_ms, sample_rate);
+        }
+    }
+
     pub fn set_layer_gain(&mut self, target: f32, sample_rate: f32) {
         self.dynamic_gain.set_target(target, 5.0, sample_rate);
     }
@@ -293,45 +1316,1028 @@ impl Voice {
 pub struct MidiProcessor {
     pub legato_engine: LegatoEngine,
     rng: SmallRng,
+    seed: u64,
+    // Notes started so far, over all keys and per key. Both count up without a bound and wrap, so each
+    // user takes them modulo its own cycle length
     pub round_robin: usize,
+    key_round_robin: [usize; 128],
 }
 
 impl MidiProcessor {
     pub fn new() -> Self {
         Self {
             legato_engine: LegatoEngine::new(),
-            rng: SmallRng::seed_from_u64(0xA11CE55),
+            rng: SmallRng::seed_from_u64(HUMANIZE_SEED),
+            seed: HUMANIZE_SEED,
             round_robin: 0,
+            key_round_robin: [0; 128],
         }
     }
 
+    // Restarts humanization, ensemble spread and noise from a seed, so a reloaded project plays back the same
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = SmallRng::seed_from_u64(seed);
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Back to the first note of the song: the same random draws and round robin passes as the first time
+    pub fn restart(&mut self) {
+        self.reseed(self.seed);
+        self.round_robin = 0;
+        self.key_round_robin = [0; 128];
+    }
+
     pub fn detect_layer(&self, velocity: u8) -> (DynamicLayer, f32) {
-        match velocity {
-            0..=29 => (DynamicLayer::Pp, 0.20),
-            30..=49 => (DynamicLayer::P, 0.32),
-            50..=69 => (DynamicLayer::Mp, 0.45),
-            70..=89 => (DynamicLayer::Mf, 0.6),
-            90..=109 => (DynamicLayer::F, 0.78),
-            _ => (DynamicLayer::Ff, 0.95),
-        }
+        DynamicLayer::from_velocity(velocity)
     }
 
-    pub fn detect_articulation(&self, duration_ms: f32) -> Articulation {
-        if duration_ms < 120.0 {
-            Articulation::Staccato
-        } else if duration_ms <= 400.0 {
-            Articulation::Marcato
-        } else {
-            Articulation::Sustain
-        }
+    pub fn detect_articulation(&self, duration_ms: f32, map: &ArticulationMap) -> Articulation {
+        map.detect(duration_ms)
     }
 
     pub fn humanize(&mut self) -> f32 {
         self.rng.gen_range(-0.12..0.12)
     }
 
-    pub fn step_round_robin(&mut self) {
-        self.round_robin = (self.round_robin + 1) % 4;
+    // Rate factor and start phase, so an ensemble's vibratos never phase-lock
+    pub fn vibrato_variation(&mut self) -> (f32, f32) {
+        (self.rng.gen_range(0.92..1.08), self.rng.gen_range(0.0..1.0))
+    }
+
+    // Detune in semitones and onset delay in milliseconds for one ensemble player
+    pub fn player_variation(&mut self, detune_cents: f32, spread_ms: f32) -> (f32, f32) {
+        (
+            self.rng.gen_range(-1.0..1.0) * detune_cents / 100.0,
+            self.rng.gen::<f32>() * spread_ms,
+        )
+    }
+
+    pub fn noise_seed(&mut self) -> u32 {
+        self.rng.gen()
+    }
+
+    // Counts a note and returns how many times its key was played before, which picks the sample zone
+    pub fn step_round_robin(&mut self, note: u8) -> usize {
+        self.round_robin = self.round_robin.wrapping_add(1);
+        let count = &mut self.key_round_robin[note as usize % 128];
+        let pass = *count;
+        *count = count.wrapping_add(1);
+        pass
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelState {
+    dynamics: SmoothedValue,
+    // CC1 position the channel starts from and goes back to on Reset All Controllers
+    initial_dynamics: f32,
+    expression: SmoothedValue,
+    cutoff_mod: SmoothedValue,
+    vibrato_depth: SmoothedValue,
+    volume: SmoothedValue,
+    pan: SmoothedValue,
+    release_scale: f32,
+    pub legato_enabled: bool,
+    // Solo channels play a single monophonic player with a brighter, more expressive sound
+    pub solo: bool,
+    held_notes: NoteStack,
+    // Articulation chosen by the last keyswitch of a sampled instrument, or forced by a preset
+    pub keyswitch: Option<Articulation>,
+    // Note actually sounding for each key, which differs from the key when the range policy folded it
+    sounding: [u8; 128],
+    bank_msb: u8,
+    bank_lsb: u8,
+    pub patch: usize,
+    // MTS scale/octave tuning offsets per pitch class, in cents
+    octave_cents: [f32; 12],
+    // Per-sample values, refreshed once per frame before the voices are rendered
+    dyn_mod: f32,
+    gain_left: f32,
+    gain_right: f32,
+    cutoff_scale: f32,
+    dynamics_value: f32,
+    expression_value: f32,
+    vibrato_value: f32,
+    // Raw controller values and channel pressure, used as modulation sources
+    controllers: [f32; 128],
+    pressure: f32,
+}
+
+impl ChannelState {
+    pub fn new() -> Self {
+        Self {
+            dynamics: SmoothedValue::new(0.5),
+            initial_dynamics: 0.5,
+            expression: SmoothedValue::new(1.0),
+            cutoff_mod: SmoothedValue::new(1.0),
+            // Centre position of the vibrato controller leaves the patch depth unchanged
+            vibrato_depth: SmoothedValue::new(0.5),
+            volume: SmoothedValue::new(1.0),
+            pan: SmoothedValue::new(0.5),
+            release_scale: 1.0,
+            legato_enabled: true,
+            solo: false,
+            held_notes: NoteStack::new(),
+            keyswitch: None,
+            sounding: std::array::from_fn(|key| key as u8),
+            bank_msb: 0,
+            bank_lsb: 0,
+            patch: 0,
+            octave_cents: [0.0; 12],
+            dyn_mod: 0.0,
+            gain_left: 1.0,
+            gain_right: 1.0,
+            cutoff_scale: 1.0,
+            dynamics_value: 0.5,
+            expression_value: 1.0,
+            vibrato_value: 0.5,
+            controllers: [0.0; 128],
+            pressure: 0.0,
+        }
+    }
+
+    pub fn reset_controllers(&mut self) {
+        // Volume and pan survive a Reset All Controllers, as recommended by RP-015
+        self.dynamics.set_immediate(self.initial_dynamics);
+        self.expression.set_immediate(1.0);
+        self.cutoff_mod.set_immediate(1.0);
+        self.vibrato_depth.set_immediate(0.5);
+        self.pressure = 0.0;
+        self.release_scale = 1.0;
+        self.legato_enabled = true;
+    }
+
+    // `dynamic_range` scales how far CC1 pulls the level down from its loudest
+    fn advance(&mut self, dynamic_range: f32, full_dynamics: f32) {
+        self.dynamics_value = self.dynamics.next();
+        self.dyn_mod = full_dynamics - (1.0 - self.dynamics_value) * 0.75 * dynamic_range;
+        self.vibrato_value = self.vibrato_depth.next();
+        self.expression_value = self.expression.next();
+        let gain = self.expression_value * self.volume.next();
+        let angle = self.pan.next() * std::f32::consts::FRAC_PI_2;
+        self.gain_left = gain * angle.cos() * std::f32::consts::SQRT_2;
+        self.gain_right = gain * angle.sin() * std::f32::consts::SQRT_2;
+        self.cutoff_scale = self.cutoff_mod.next() * if self.solo { SOLO_BRIGHTNESS } else { 1.0 };
+    }
+}
+
+// What the engine plays from files: the bank with the sampled patches, their recordings and the hall's
+// impulse response. Loading them is slow, so they are put together apart from the engine and swapped in
+// whole with `OrchestraEngine::install`
+#[derive(Debug)]
+pub struct Instruments {
+    pub bank: PatchBank,
+    pub sample_sets: Vec<SampleSet>,
+    impulse_response: Option<ImpulseResponse>,
+    convolution: Option<Convolver>,
+    sample_rate: f32,
+}
+
+impl Instruments {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            bank: PatchBank::default(),
+            sample_sets: Vec::new(),
+            impulse_response: None,
+            convolution: None,
+            sample_rate,
+        }
+    }
+
+    // Plays a multisampled instrument on a program of bank 0, keeping the section of the patch it
+    // replaces so ranges and envelopes still fit
+    pub fn add_sample_instrument(&mut self, program: u8, samples: SampleSet) {
+        let section = self
+            .bank
+            .find(0, program)
+            .map_or(Section::Violins1, |index| self.bank.get(index).section);
+        self.add_sampled_patch(Patch::factory(program, section), samples);
+    }
+
+    // Every preset lands on its own bank and program, so General MIDI files find their sounds through
+    // ordinary bank select and program changes
+    pub fn add_sound_font(&mut self, font: SoundFont) {
+        for preset in font.presets {
+            let section = Section::from_gm_program(preset.program);
+            let mut patch = Patch::factory(preset.program, section.unwrap_or(Section::Violins1));
+            patch.bank = preset.bank;
+            patch.on_stage = section.is_some();
+            // The font knows which keys it can play, which for pianos or drums is no orchestral range
+            patch.range = preset.samples.key_range();
+            self.add_sampled_patch(patch, preset.samples);
+        }
+    }
+
+    fn add_sampled_patch(&mut self, mut patch: Patch, samples: SampleSet) {
+        patch.name = samples.name.clone();
+        patch.model = SynthModel::Sampled;
+        patch.sample_set = Some(self.sample_sets.len());
+        // Recordings carry their own vibrato
+        patch.vibrato.depth_cents = 0.0;
+        self.sample_sets.push(samples);
+        self.bank.insert(patch);
+    }
+
+    // Resamples and transforms the response for the sample rate the instruments were made for
+    pub fn set_impulse_response(&mut self, response: Option<ImpulseResponse>) {
+        self.convolution = response
+            .as_ref()
+            .map(|response| Convolver::new(response, self.sample_rate));
+        self.impulse_response = response;
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+}
+
+#[derive(Debug)]
+pub struct OrchestraEngine {
+    pub voices: Vec<Voice>,
+    pub midi: MidiProcessor,
+    pub cc_map: CcMap,
+    pub bank: PatchBank,
+    pub tuning: Tuning,
+    pub smart_intonation: bool,
+    pub vibrato_from_dynamics: bool,
+    pub vibrato_depth: f32,
+    pub vibrato_rate: f32,
+    pub vibrato_delay: f32,
+    pub section_size: usize,
+    pub range_policy: RangePolicy,
+    // Performance controls over the whole orchestra, the defaults leave the patches as designed
+    pub dynamic_range: f32,
+    // Dynamic gain with CC1 fully open
+    pub full_dynamics: f32,
+    pub attack_scale: f32,
+    pub release_scale: f32,
+    pub legato_glide_ms: f32,
+    pub humanize_amount: f32,
+    pub ensemble_detune_cents: f32,
+    pub ensemble_spread_ms: f32,
+    pub resonance: f32,
+    // 0 is mono, 1 leaves the mix as rendered, above 1 widens it
+    pub stereo_width: f32,
+    pub reverb_send: f32,
+    // Voices new notes may take, up to MAX_VOICES
+    pub voice_limit: usize,
+    // Out-of-range notes since the caller last drained the list, capped so the audio thread never allocates
+    pub range_violations: Vec<RangeViolation>,
+    // Multisampled instruments referenced by the patches' `sample_set`
+    pub sample_sets: Vec<SampleSet>,
+    // Rings feeding the voices from disk, one per voice, and the reads the host has to pass to the reader
+    // thread after each block
+    pub streams: Arc<Streams>,
+    pub stream_requests: Vec<StreamRequest>,
+    // When each slot last queued a request, to send again one that got lost on the way
+    stream_requested: [i64; MAX_VOICES],
+    // Seats the sections and carries each one's sound to the listener
+    pub stage: Stage,
+    pub reverb: Reverb,
+    // A loaded impulse response takes over from the algorithmic reverb
+    impulse_response: Option<ImpulseResponse>,
+    convolution: Option<Convolver>,
+    pub mod_matrix: ModMatrix,
+    lfos: [Lfo; MOD_LFOS],
+    lfo_values: [f32; MOD_LFOS],
+    pub channels: [ChannelState; MIDI_CHANNELS],
+    sample_rate: f32,
+    global_sample: i64,
+}
+
+impl OrchestraEngine {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            voices: (0..MAX_VOICES).map(|_| Voice::new()).collect(),
+            midi: MidiProcessor::new(),
+            cc_map: CcMap::default(),
+            bank: PatchBank::default(),
+            tuning: Tuning::default(),
+            smart_intonation: false,
+            vibrato_from_dynamics: false,
+            vibrato_depth: 1.0,
+            vibrato_rate: 1.0,
+            vibrato_delay: 1.0,
+            section_size: 1,
+            range_policy: RangePolicy::Flag,
+            dynamic_range: 1.0,
+            full_dynamics: FULL_DYNAMICS,
+            attack_scale: 1.0,
+            release_scale: 1.0,
+            legato_glide_ms: SOLO_GLIDE_MS,
+            humanize_amount: 1.0,
+            ensemble_detune_cents: ENSEMBLE_DETUNE_CENTS,
+            ensemble_spread_ms: ENSEMBLE_SPREAD_MS,
+            resonance: 0.0,
+            stereo_width: 1.0,
+            reverb_send: 0.5,
+            voice_limit: MAX_VOICES,
+            range_violations: Vec::with_capacity(MAX_RANGE_VIOLATIONS),
+            sample_sets: Vec::new(),
+            streams: Arc::new(Streams::new()),
+            stream_requests: Vec::with_capacity(MAX_VOICES * 2),
+            stream_requested: [i64::MIN; MAX_VOICES],
+            stage: Stage::new(sample_rate),
+            reverb: Reverb::new(sample_rate),
+            impulse_response: None,
+            convolution: None,
+            mod_matrix: ModMatrix::default(),
+            lfos: [Lfo::new(0x1F0), Lfo::new(0x2F0), Lfo::new(0x3F0)],
+            lfo_values: [0.0; MOD_LFOS],
+            channels: [ChannelState::new(); MIDI_CHANNELS],
+            sample_rate,
+            global_sample: 0,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.stage.set_sample_rate(sample_rate);
+        self.reverb.set_sample_rate(sample_rate);
+        self.convolution = self
+            .impulse_response
+            .as_ref()
+            .map(|response| Convolver::new(response, sample_rate));
+    }
+
+    // Swaps in instruments loaded elsewhere and hands back the ones they replace, so nothing is built or
+    // freed here and the audio thread can call it. Voices stop, as the zones they play may be gone, and
+    // each channel goes back to the bank and program it had selected
+    pub fn install(&mut self, instruments: &mut Instruments) {
+        let selected = self.channels.map(|state| {
+            let patch = self.bank.get(state.patch);
+            (patch.bank, patch.program)
+        });
+        for voice in &mut self.voices {
+            voice.reset();
+        }
+        std::mem::swap(&mut self.bank, &mut instruments.bank);
+        std::mem::swap(&mut self.sample_sets, &mut instruments.sample_sets);
+        std::mem::swap(&mut self.impulse_response, &mut instruments.impulse_response);
+        std::mem::swap(&mut self.convolution, &mut instruments.convolution);
+        for (state, (bank, program)) in self.channels.iter_mut().zip(selected) {
+            match self.bank.find(bank, program) {
+                Some(index) => state.patch = index,
+                None => {
+                    state.patch = self.bank.find(0, program).unwrap_or(0);
+                    state.keyswitch = None;
+                }
+            }
+        }
+    }
+
+    pub fn impulse_response(&self) -> Option<&ImpulseResponse> {
+        self.impulse_response.as_ref()
+    }
+
+    pub fn reset(&mut self) {
+        self.global_sample = 0;
+        // Keys retuned over MTS go back to the loaded tuning, a song sends its tuning again when it restarts
+        self.tuning.clear_overrides();
+        self.midi.restart();
+        self.stage.reset();
+        self.reverb.reset();
+        if let Some(convolver) = &mut self.convolution {
+            convolver.reset();
+        }
+        for voice in &mut self.voices {
+            voice.reset();
+        }
+    }
+
+    pub fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        if let Some(articulation) = self.keyswitch(channel, key) {
+            self.channels[channel as usize % MIDI_CHANNELS].keyswitch = Some(articulation);
+            return;
+        }
+        let Some((note, patch_index)) = self.resolve_range(channel, key) else {
+            return;
+        };
+        // Keys left unmapped by the keyboard mapping are silent
+        let Some(base_hz) = self.note_frequency(channel, note) else {
+            return;
+        };
+        let state = &mut self.channels[channel as usize % MIDI_CHANNELS];
+        state.sounding[key as usize % 128] = note;
+        state.held_notes.push(note);
+        let solo = state.solo;
+        if solo && self.solo_transition(channel, note, base_hz, velocity) {
+            self.update_intonation();
+            return;
+        }
+
+        let state = &self.channels[channel as usize % MIDI_CHANNELS];
+        let patch = self.bank.get(patch_index);
+        let (layer, layer_gain) = self.midi.detect_layer(velocity);
+        let legato = self.midi.legato_engine.note_on(note as i32, self.global_sample) && state.legato_enabled;
+
+        let articulation = match state.keyswitch {
+            _ if legato => Articulation::Sustain,
+            Some(articulation) => articulation,
+            None => self.midi.detect_articulation(500.0, &patch.articulation),
+        };
+        let mut shape = patch.envelopes.shape(articulation, legato);
+
+        let pass = self.midi.step_round_robin(note);
+        // Sampled patches need a zone for this key, velocity, layer and articulation or stay silent
+        let zone = match (patch.model, patch.sample_set) {
+            (SynthModel::Sampled, Some(set)) => self.sample_sets.get(set).and_then(|samples| {
+                samples
+                    .select(note, velocity, layer, articulation, pass)
+                    .map(|zone| (set, zone))
+            }),
+            _ => None,
+        };
+        if patch.model == SynthModel::Sampled && zone.is_none() {
+            return;
+        }
+        // A recording with its own amplitude envelope keeps it, except for legato transitions
+        if let Some(envelope) = zone
+            .and_then(|(set, zone)| self.sample_sets[set].zones[zone].envelope)
+            .filter(|_| !legato)
+        {
+            shape = envelope;
+        }
+        shape.attack_ms *= self.attack_scale;
+        // Synthesized notes step through a few detunings centred on the pitch
+        let step = (self.midi.round_robin % RR_DETUNE_STEPS) as f32;
+        let rr_detune = (step - (RR_DETUNE_STEPS - 1) as f32 * 0.5) * 0.03;
+        let humanization =
+            (self.midi.humanize() + rr_detune) * patch.humanize * self.humanize_amount;
+        let mut vibrato = patch.vibrato;
+        // Recordings already carry their own breath and bow noise
+        let noise = match patch.model {
+            SynthModel::Sampled => NoiseSettings::new(0.0, 0.0, 0.0, 1000.0),
+            _ => patch.noise,
+        };
+        let model = patch.model;
+        let (formant_hz, mute) = (patch.section.brass_formant(), patch.mute);
+        let stage_pan = self.stage.position(patch.stage_section()).pan();
+        vibrato.delay_ms *= self.vibrato_delay;
+        // A soloist leans into a wider, slightly faster vibrato that blooms sooner than a section's
+        if solo {
+            vibrato.depth_cents *= 1.4;
+            vibrato.rate_hz *= 1.05;
+            vibrato.delay_ms *= 0.7;
+        }
+        let players = if solo { 1 } else { self.players_per_note() };
+        let player_gain = 1.0 / (players as f32).sqrt();
+
+        for player in 0..players {
+            let limit = self.voice_limit.clamp(1, MAX_VOICES);
+            let Some(slot) = self.voices[..limit].iter().position(|v| !v.active) else {
+                break;
+            };
+            let voice = &mut self.voices[slot];
+            let (rate_variation, vibrato_phase) = self.midi.vibrato_variation();
+            // The first player stays on the beat and in tune so a solo section sounds as before
+            let (detune, delay_ms) = if player == 0 {
+                (0.0, 0.0)
+            } else {
+                self.midi
+                    .player_variation(self.ensemble_detune_cents, self.ensemble_spread_ms)
+            };
+
+            voice.start(
+                channel,
+                note,
+                base_hz,
+                velocity,
+                patch_index,
+                articulation,
+                shape,
+                layer_gain,
+                legato,
+                self.sample_rate,
+                self.global_sample,
+                humanization + detune,
+            );
+            voice
+                .vibrato
+                .trigger(vibrato, rate_variation, vibrato_phase, self.sample_rate);
+            voice.trigger_mod_envelopes(&self.mod_matrix.envelopes, self.sample_rate);
+            voice.set_model(model);
+            voice.sampler.start(zone);
+            if zone.is_some_and(|(set, zone)| self.sample_sets[set].zones[zone].data.stream.is_some()) {
+                // `poll_streams` sends the start, and sends it again until the reader has it
+                self.streams.ring(slot).restart();
+                self.stream_requested[slot] = i64::MIN;
+            }
+            // Only brass voices draw a seed, so the other models render the same with or without it
+            if model == SynthModel::Brass {
+                voice.brass.trigger(
+                    formant_hz,
+                    mute,
+                    velocity,
+                    self.midi.noise_seed(),
+                    self.sample_rate,
+                );
+            }
+            voice.noise.trigger(
+                noise,
+                velocity,
+                legato,
+                self.midi.noise_seed(),
+                self.sample_rate,
+            );
+
+            // The players of a section spread out around its seat
+            let seat = if players > 1 {
+                player as f32 / (players - 1) as f32 - 0.5
+            } else {
+                0.0
+            };
+            let delay = (delay_ms / 1000.0 * self.sample_rate) as u32;
+            voice.set_player(
+                stage_pan + humanization * 0.03 + seat * ENSEMBLE_WIDTH,
+                player_gain,
+                delay,
+            );
+        }
+
+        self.update_intonation();
+    }
+
+    fn keyswitch(&self, channel: u8, key: u8) -> Option<Articulation> {
+        let patch = self
+            .bank
+            .get(self.channels[channel as usize % MIDI_CHANNELS].patch);
+        self.sample_sets.get(patch.sample_set?)?.keyswitch(key)
+    }
+
+    // Picks the note and patch that play a key, applying the range policy when the channel's patch
+    // cannot reach it
+    fn resolve_range(&mut self, channel: u8, key: u8) -> Option<(u8, usize)> {
+        let patch_index = self.channels[channel as usize % MIDI_CHANNELS].patch;
+        let patch = self.bank.get(patch_index);
+        if patch.range.contains(key) {
+            return Some((key, patch_index));
+        }
+
+        let played = match self.range_policy {
+            RangePolicy::Ignore => None,
+            RangePolicy::Fold => Some((patch.range.fold(key), patch_index)),
+            // Without a neighbour that reaches the note, folding is the closest playable option
+            RangePolicy::HandOff => Some(
+                self.bank
+                    .neighbour(patch_index, key)
+                    .map_or((patch.range.fold(key), patch_index), |index| (key, index)),
+            ),
+            RangePolicy::Flag => Some((key, patch_index)),
+        };
+
+        if self.range_violations.len() < MAX_RANGE_VIOLATIONS {
+            self.range_violations.push(RangeViolation {
+                sample: self.global_sample,
+                channel,
+                note: key,
+                section: patch.section,
+                played,
+            });
+        }
+        played
+    }
+
+    // A solo channel plays one line, so a new note takes over the sounding voice: it glides there when
+    // legato is on, otherwise the old note is cut and the caller starts a fresh one
+    fn solo_transition(&mut self, channel: u8, note: u8, base_hz: f32, velocity: u8) -> bool {
+        let legato = self.channels[channel as usize % MIDI_CHANNELS].legato_enabled;
+        let mut glided = false;
+        for voice in self
+            .voices
+            .iter_mut()
+            .filter(|v| v.is_held() && v.channel == channel)
+        {
+            if legato {
+                voice.legato_to(
+                    note,
+                    base_hz,
+                    velocity,
+                    self.legato_glide_ms,
+                    self.sample_rate,
+                );
+                glided = true;
+            } else {
+                voice.fast_fade(self.sample_rate);
+            }
+        }
+        glided
+    }
+
+    // Shares the voice budget between the notes already held and the new one, so a tutti chord thins
+    // out its sections instead of running out of voices
+    fn players_per_note(&self) -> usize {
+        let size = self.section_size.clamp(1, MAX_SECTION_SIZE);
+        if size == 1 {
+            return 1;
+        }
+
+        let mut held_notes = 0u128;
+        let mut held_count = 0;
+        for voice in self.voices.iter().filter(|v| v.is_held()) {
+            let key = 1u128 << voice.note;
+            if held_notes & key == 0 {
+                held_notes |= key;
+                held_count += 1;
+            }
+        }
+
+        let voices = self.voice_limit.clamp(1, MAX_VOICES);
+        size.min(voices / (held_count + 1)).max(1)
+    }
+
+    pub fn note_off(&mut self, channel: u8, key: u8) {
+        let state = &mut self.channels[channel as usize % MIDI_CHANNELS];
+        let note = state.sounding[key as usize % 128];
+        state.held_notes.remove(note);
+        let release_scale = state.release_scale * self.release_scale;
+        // Releasing the sounding note of a legato solo line glides back to the key still held
+        let fallback = state
+            .held_notes
+            .last()
+            .filter(|_| state.solo && state.legato_enabled);
+        if let Some((previous, base_hz)) =
+            fallback.and_then(|n| Some((n, self.note_frequency(channel, n)?)))
+        {
+            let mut glided = false;
+            for voice in self
+                .voices
+                .iter_mut()
+                .filter(|v| v.is_held() && v.channel == channel && v.note == note)
+            {
+                let velocity = voice.velocity();
+                voice.legato_to(
+                    previous,
+                    base_hz,
+                    velocity,
+                    self.legato_glide_ms,
+                    self.sample_rate,
+                );
+                glided = true;
+            }
+            if glided {
+                self.update_intonation();
+                return;
+            }
+        }
+
+        self.midi.legato_engine.note_off(self.global_sample);
+        for voice in &mut self.voices {
+            if voice.active && voice.channel == channel && voice.note == note {
+                let patch = self.bank.get(voice.patch);
+                let duration_ms = ((self.global_sample - voice.start_sample) as f32 / self.sample_rate) * 1000.0;
+                voice.articulation = self.midi.detect_articulation(duration_ms, &patch.articulation);
+                let release_ms = patch.envelopes.shape(voice.articulation, false).release_ms;
+                voice.note_off(release_ms * release_scale, self.sample_rate);
+            }
+        }
+
+        self.update_intonation();
+    }
+
+    // Retunes the held voices towards just intervals above the detected chord root. Voices that
+    // started on this sample snap immediately, the others glide so sustained notes bend smoothly.
+    pub fn update_intonation(&mut self) {
+        if !self.smart_intonation {
+            for voice in &mut self.voices {
+                voice.set_intonation(1.0, INTONATION_GLIDE_MS, self.sample_rate);
+            }
+            return;
+        }
+
+        let mut pitch_classes = 0u16;
+        let mut bass: Option<&Voice> = None;
+        for voice in self.voices.iter().filter(|v| v.is_held()) {
+            pitch_classes |= 1 << (voice.note % 12);
+            if bass.is_none_or(|b| voice.note < b.note) {
+                bass = Some(voice);
+            }
+        }
+
+        let Some(bass) = bass else {
+            return;
+        };
+        let Some(root) = detect_chord_root(pitch_classes, bass.note % 12) else {
+            return;
+        };
+        // The lowest voice on the root pitch class anchors the chord at its tempered pitch
+        let Some(anchor) = self
+            .voices
+            .iter()
+            .filter(|v| v.is_held() && v.note % 12 == root)
+            .min_by_key(|v| v.note)
+            .map(|v| (v.note, v.base_hz()))
+        else {
+            return;
+        };
+
+        for voice in self.voices.iter_mut().filter(|v| v.is_held()) {
+            let semitones = voice.note as f32 - anchor.0 as f32;
+            let interval = (voice.note as i32 - anchor.0 as i32).rem_euclid(12) as u8;
+            let ideal_cents = semitones * 100.0 + just_deviation(interval);
+            let actual_cents = 1200.0 * (voice.base_hz() / anchor.1).log2();
+            let ratio = semitones_to_ratio((ideal_cents - actual_cents) / 100.0);
+            let glide_ms = if voice.start_sample == self.global_sample { 0.0 } else { INTONATION_GLIDE_MS };
+            voice.set_intonation(ratio, glide_ms, self.sample_rate);
+        }
+    }
+
+    pub fn all_notes_off(&mut self, channel: u8) {
+        let state = &mut self.channels[channel as usize % MIDI_CHANNELS];
+        state.held_notes.clear();
+        let release_scale = state.release_scale * self.release_scale;
+        self.midi.legato_engine.note_off(self.global_sample);
+        for voice in &mut self.voices {
+            if voice.active && voice.channel == channel {
+                let release_ms = self.bank.get(voice.patch).envelopes.shape(voice.articulation, false).release_ms;
+                voice.note_off(release_ms * release_scale, self.sample_rate);
+            }
+        }
+    }
+
+    pub fn all_sound_off(&mut self, channel: u8) {
+        self.channels[channel as usize % MIDI_CHANNELS]
+            .held_notes
+            .clear();
+        for voice in &mut self.voices {
+            if voice.active && voice.channel == channel {
+                voice.fast_fade(self.sample_rate);
+            }
+        }
+    }
+
+    pub fn note_frequency(&self, channel: u8, note: u8) -> Option<f32> {
+        let offset = self.channels[channel as usize % MIDI_CHANNELS].octave_cents[note as usize % 12];
+        self.tuning.frequency(note).map(|hz| hz * semitones_to_ratio(offset / 100.0))
+    }
+
+    pub fn handle_sysex(&mut self, message: &MtsSysEx) {
+        match *message {
+            MtsSysEx::SingleNote {
+                realtime,
+                count,
+                changes,
+            } => {
+                for group in &changes[..count as usize] {
+                    if let Some((key, Some(frequency))) = mts_frequency(group) {
+                        self.tuning.set_note_frequency(key, frequency);
+                        if realtime {
+                            self.retune_voices(|v| v.note == key);
+                        }
+                    }
+                }
+            }
+            MtsSysEx::Octave {
+                realtime,
+                channel_mask,
+                cents,
+            } => {
+                for (channel, state) in self.channels.iter_mut().enumerate() {
+                    if channel_mask & (1 << channel) != 0 {
+                        state.octave_cents = cents;
+                    }
+                }
+                if realtime {
+                    self.retune_voices(|v| channel_mask & (1 << v.channel) != 0);
+                }
+            }
+        }
+    }
+
+    // Non-real-time tuning messages only affect new notes, real-time ones also bend sounding voices
+    fn retune_voices(&mut self, filter: impl Fn(&Voice) -> bool) {
+        for i in 0..self.voices.len() {
+            let voice = &self.voices[i];
+            if !voice.active || !filter(voice) {
+                continue;
+            }
+            if let Some(base_hz) = self.note_frequency(voice.channel, voice.note) {
+                self.voices[i].retune(base_hz);
+            }
+        }
+    }
+
+    pub fn program_change(&mut self, channel: u8, program: u8) {
+        let state = &mut self.channels[channel as usize % MIDI_CHANNELS];
+        let bank = ((state.bank_msb as u16) << 7) | state.bank_lsb as u16;
+        // Sounding voices keep the patch they started with, so only new notes pick up the switch
+        if let Some(index) = self.bank.find(bank, program) {
+            state.patch = index;
+            state.keyswitch = None;
+        }
+    }
+
+    // Same as a bank select followed by a program change, e.g. for the General MIDI drum channel
+    pub fn select_patch(&mut self, channel: u8, bank: u16, program: u8) {
+        let state = &mut self.channels[channel as usize % MIDI_CHANNELS];
+        state.bank_msb = (bank >> 7) as u8 & 0x7f;
+        state.bank_lsb = bank as u8 & 0x7f;
+        self.program_change(channel, program);
+    }
+
+    // Queues the start of every new streaming voice and a read for those with room for another chunk, meant
+    // to run once per block. A request left out of a full queue, or dropped by the host, goes again once it
+    // has gone a chunk's worth of time unserved
+    pub fn poll_streams(&mut self) {
+        for (slot, voice) in self.voices.iter().enumerate() {
+            let Some(zone) = voice
+                .sampler
+                .zone()
+                .filter(|_| voice.active)
+                .and_then(|(set, zone)| self.sample_sets.get(set)?.zones.get(zone))
+                .filter(|zone| zone.data.stream.is_some())
+            else {
+                continue;
+            };
+            if self.stream_requests.len() == self.stream_requests.capacity() {
+                break;
+            }
+            let ring = self.streams.ring(slot);
+            let overdue = self.global_sample.saturating_sub(self.stream_requested[slot]) >= STREAM_CHUNK_FRAMES as i64;
+            let request = if ring.start_pending() {
+                overdue.then(|| StreamRequest::Start {
+                    slot,
+                    generation: ring.generation(),
+                    data: zone.data.clone(),
+                    loop_points: zone.loop_points,
+                })
+            } else {
+                (ring.free() >= STREAM_CHUNK_FRAMES && (ring.request_fill() || overdue))
+                    .then_some(StreamRequest::Fill { slot })
+            };
+            if let Some(request) = request {
+                self.stream_requests.push(request);
+                self.stream_requested[slot] = self.global_sample;
+            }
+        }
+    }
+
+    // CC1 position every channel starts from, before the first controller message
+    pub fn set_initial_dynamics(&mut self, value: f32) {
+        for state in &mut self.channels {
+            state.initial_dynamics = value;
+            state.dynamics.set_immediate(value);
+            state.dynamics_value = value;
+        }
+    }
+
+    pub fn set_solo(&mut self, channel: u8, solo: bool) {
+        self.channels[channel as usize % MIDI_CHANNELS].solo = solo;
+    }
+
+    pub fn channel_pressure(&mut self, channel: u8, pressure: f32) {
+        self.channels[channel as usize % MIDI_CHANNELS].pressure = pressure.clamp(0.0, 1.0);
+    }
+
+    pub fn poly_pressure(&mut self, channel: u8, key: u8, pressure: f32) {
+        let note = self.channels[channel as usize % MIDI_CHANNELS].sounding[key as usize % 128];
+        for voice in &mut self.voices {
+            if voice.active && voice.channel == channel && voice.note == note {
+                voice.pressure = pressure.clamp(0.0, 1.0);
+            }
+        }
+    }
+
+    pub fn handle_cc(&mut self, channel: u8, cc: u8, value: f32) {
+        let sample_rate = self.sample_rate;
+        let value = value.clamp(0.0, 1.0);
+        if let Some(slot) = self.channels[channel as usize % MIDI_CHANNELS]
+            .controllers
+            .get_mut(cc as usize)
+        {
+            *slot = value;
+        }
+
+        match cc {
+            0 => {
+                self.channels[channel as usize % MIDI_CHANNELS].bank_msb = (value * 127.0).round() as u8;
+                return;
+            }
+            32 => {
+                self.channels[channel as usize % MIDI_CHANNELS].bank_lsb = (value * 127.0).round() as u8;
+                return;
+            }
+            7 => {
+                let state = &mut self.channels[channel as usize % MIDI_CHANNELS];
+                state.volume.set_target(volume_to_gain(value), 5.0, sample_rate);
+                return;
+            }
+            10 => {
+                let state = &mut self.channels[channel as usize % MIDI_CHANNELS];
+                state.pan.set_target(value, 5.0, sample_rate);
+                return;
+            }
+            120 => return self.all_sound_off(channel),
+            121 => return self.channels[channel as usize % MIDI_CHANNELS].reset_controllers(),
+            // Omni and mono/poly mode changes imply All Notes Off
+            123..=127 => return self.all_notes_off(channel),
+            _ => {}
+        }
+
+        let Some((target, value)) = self.cc_map.handle(cc, value) else {
+            return;
+        };
+
+        let state = &mut self.channels[channel as usize % MIDI_CHANNELS];
+        match target {
+            CcTarget::Dynamics => state.dynamics.set_target(value, 5.0, sample_rate),
+            CcTarget::Expression => state.expression.set_target(value, 5.0, sample_rate),
+            CcTarget::Cutoff => state
+                .cutoff_mod
+                .set_target((2.0_f32).powf((value - 1.0) * 5.0), 5.0, sample_rate),
+            CcTarget::Release => state.release_scale = (2.0_f32).powf((value - 0.5) * 4.0),
+            CcTarget::Legato => state.legato_enabled = value >= 0.5,
+            CcTarget::Solo => state.solo = value >= 0.5,
+            CcTarget::Vibrato => state.vibrato_depth.set_target(value, 5.0, sample_rate),
+        }
+    }
+
+    pub fn render(&mut self, cutoff_hz: f32) -> (f32, f32) {
+        for state in &mut self.channels {
+            state.advance(self.dynamic_range, self.full_dynamics);
+        }
+        for (lfo, (value, settings)) in self
+            .lfos
+            .iter_mut()
+            .zip(self.lfo_values.iter_mut().zip(&self.mod_matrix.lfos))
+        {
+            *value = lfo.next(settings, self.sample_rate);
+        }
+
+        let mut buses = [[0.0; 2]; STAGE_BUSES];
+
+        for (slot, voice) in self.voices.iter_mut().enumerate() {
+            if voice.active {
+                let state = &self.channels[voice.channel as usize % MIDI_CHANNELS];
+                let vibrato_value = if self.vibrato_from_dynamics {
+                    state.dynamics_value
+                } else {
+                    state.vibrato_value
+                };
+
+                let mut envelopes = [0.0; MOD_ENVELOPES];
+                for (value, envelope) in envelopes.iter_mut().zip(&mut voice.mod_envelopes) {
+                    *value = envelope.next();
+                }
+                let modulation = self.mod_matrix.evaluate(&ModSources {
+                    lfos: &self.lfo_values,
+                    envelopes,
+                    velocity: voice.velocity() as f32 / 127.0,
+                    key_track: (voice.note as f32 - 60.0) / 60.0,
+                    aftertouch: state.pressure.max(voice.pressure),
+                    controllers: &state.controllers,
+                });
+
+                let controls = VoiceControls {
+                    cutoff_hz: cutoff_hz * state.cutoff_scale * octaves_to_ratio(modulation.cutoff),
+                    vibrato_depth: (vibrato_value * 2.0 * self.vibrato_depth
+                        + modulation.vibrato_depth)
+                        .max(0.0),
+                    vibrato_rate: self.vibrato_rate,
+                    pitch_ratio: octaves_to_ratio(modulation.pitch / 12.0),
+                    amplitude: (1.0 + modulation.amplitude).max(0.0),
+                    pan_offset: modulation.pan,
+                    // CC1 leans into the string and CC11 draws the bow faster
+                    dynamics: state.dynamics_value,
+                    zone: voice.sampler.zone().and_then(|(set, zone)| {
+                        self.sample_sets.get(set).and_then(|s| s.zones.get(zone))
+                    }),
+                    bow_speed: 0.3 + state.expression_value * 0.7,
+                    resonance: self.resonance,
+                    stream: Some(self.streams.ring(slot)),
+                };
+                voice.set_layer_gain(state.dyn_mod, self.sample_rate);
+                let (l, r) = voice.render(self.sample_rate, &controls);
+                let bus = &mut buses[Stage::bus(self.bank.get(voice.patch).stage_section())];
+                bus[0] += l * state.gain_left;
+                bus[1] += r * state.gain_right;
+            }
+        }
+
+        let stage = self.stage.process(&buses);
+        let early_level = self.reverb.settings.early_level;
+        let early = [stage.early[0] * early_level, stage.early[1] * early_level];
+        // The reflections build up into the late tail, as they would in the hall
+        let send = [
+            (stage.send[0] + early[0]) * self.reverb_send,
+            (stage.send[1] + early[1]) * self.reverb_send,
+        ];
+        let tail = match &mut self.convolution {
+            Some(convolver) => convolver.process(send),
+            None => self.reverb.process(send),
+        };
+        let (dry_gain, wet_gain) = self.reverb.settings.dry_wet();
+        let left = stage.direct[0] * dry_gain + (early[0] + tail[0]) * wet_gain;
+        let right = stage.direct[1] * dry_gain + (early[1] + tail[1]) * wet_gain;
+        self.global_sample += 1;
+        // Mid/side, so narrowing folds the sections towards the centre without changing their balance
+        let mid = (left + right) * 0.5;
+        let side = (right - left) * 0.5 * self.stereo_width;
+        (mid - side, mid + side)
     }
 }
 
@@ -340,7 +2346,307 @@ pub fn midi_note_to_hz(note: f32) -> f32 {
     440.0 * (2.0_f32).powf((note - 69.0) / 12.0)
 }
 
+#[inline]
+pub fn semitones_to_ratio(semitones: f32) -> f32 {
+    (2.0_f32).powf(semitones / 12.0)
+}
+
+#[inline]
+fn octaves_to_ratio(octaves: f32) -> f32 {
+    // Unmodulated voices are the common case, so skip the powf entirely
+    if octaves == 0.0 {
+        1.0
+    } else {
+        (2.0_f32).powf(octaves)
+    }
+}
+
+#[inline]
+fn volume_to_gain(value: f32) -> f32 {
+    // Squared law as in the General MIDI volume curve, normalized so the default of 100 is unity
+    let scaled = value * 127.0 / 100.0;
+    scaled * scaled
+}
+
 #[inline]
 fn ms_to_samples(ms: f32, sample_rate: f32) -> f32 {
     ((ms / 1000.0) * sample_rate).max(1.0)
 }
+
+// Per-sample multiplier of an exponential decay with the given time constant
+#[inline]
+fn decay_factor(ms: f32, sample_rate: f32) -> f32 {
+    (-1.0 / ms_to_samples(ms, sample_rate)).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LFOS: [f32; MOD_LFOS] = [0.25, -0.5, 0.75];
+
+    // Every source at a distinct value, so a route reading the wrong one shows up
+    fn evaluate(source: ModSource, destination: ModDestination) -> ModOutputs {
+        let mut controllers = [0.0; 128];
+        controllers[21] = 0.9;
+        let matrix = ModMatrix {
+            routes: vec![ModRoute {
+                source,
+                destination,
+                amount: 2.0,
+            }],
+            ..ModMatrix::default()
+        };
+        matrix.evaluate(&ModSources {
+            lfos: &LFOS,
+            envelopes: [0.4, 0.6],
+            velocity: 0.8,
+            key_track: -0.3,
+            aftertouch: 0.35,
+            controllers: &controllers,
+        })
+    }
+
+    fn assert_route(source: ModSource, destination: ModDestination, expected: f32) {
+        let out = evaluate(source, destination);
+        let values = [
+            (ModDestination::Pitch, out.pitch),
+            (ModDestination::Cutoff, out.cutoff),
+            (ModDestination::Amplitude, out.amplitude),
+            (ModDestination::Pan, out.pan),
+            (ModDestination::VibratoDepth, out.vibrato_depth),
+        ];
+        for (target, value) in values {
+            let wanted = if target == destination { expected } else { 0.0 };
+            assert!(
+                (value - wanted).abs() < 1e-6,
+                "{source:?} -> {destination:?}: {target:?} = {value}, expected {wanted}"
+            );
+        }
+    }
+
+    #[test]
+    fn every_source_reaches_every_destination() {
+        let sources = [
+            (ModSource::Lfo(0), LFOS[0]),
+            (ModSource::Lfo(1), LFOS[1]),
+            (ModSource::Lfo(2), LFOS[2]),
+            (ModSource::Envelope(0), 0.4),
+            (ModSource::Envelope(1), 0.6),
+            (ModSource::Velocity, 0.8),
+            (ModSource::KeyTrack, -0.3),
+            (ModSource::Aftertouch, 0.35),
+            (ModSource::Cc(21), 0.9),
+        ];
+        let destinations = [
+            ModDestination::Pitch,
+            ModDestination::Cutoff,
+            ModDestination::Amplitude,
+            ModDestination::Pan,
+            ModDestination::VibratoDepth,
+        ];
+        for (source, value) in sources {
+            for destination in destinations {
+                assert_route(source, destination, value * 2.0);
+            }
+        }
+    }
+
+    #[test]
+    fn missing_sources_read_as_zero() {
+        assert_route(ModSource::Lfo(MOD_LFOS), ModDestination::Pitch, 0.0);
+        assert_route(
+            ModSource::Envelope(MOD_ENVELOPES),
+            ModDestination::Cutoff,
+            0.0,
+        );
+        assert_route(ModSource::Cc(22), ModDestination::Amplitude, 0.0);
+    }
+
+    #[test]
+    fn routes_to_one_destination_add_up() {
+        let mut matrix = ModMatrix::default();
+        for source in [ModSource::Lfo(0), ModSource::Velocity] {
+            matrix.routes.push(ModRoute {
+                source,
+                destination: ModDestination::Pitch,
+                amount: 1.0,
+            });
+        }
+        let out = matrix.evaluate(&ModSources {
+            lfos: &LFOS,
+            envelopes: [0.0; MOD_ENVELOPES],
+            velocity: 0.5,
+            key_track: 0.0,
+            aftertouch: 0.0,
+            controllers: &[0.0; 128],
+        });
+        assert!((out.pitch - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn note_on_triggers_the_mod_envelopes() {
+        let mut engine = OrchestraEngine::new(48_000.0);
+        engine.note_on(0, 60, 100);
+        for _ in 0..480 {
+            engine.render(12_000.0);
+        }
+        let voice = engine
+            .voices
+            .iter_mut()
+            .find(|voice| voice.active)
+            .expect("a voice plays the note");
+        for envelope in &mut voice.mod_envelopes {
+            assert!(envelope.next() > 0.0);
+        }
+    }
+
+    // Renders one note on a plain engine, with the reverb and vibrato out of the way
+    fn render_note(route: Option<ModRoute>) -> Vec<f32> {
+        let mut engine = OrchestraEngine::new(48_000.0);
+        engine.vibrato_depth = 0.0;
+        engine.reverb_send = 0.0;
+        engine.reverb.settings.mix = 0.0;
+        engine.mod_matrix.routes.extend(route);
+        engine.note_on(0, 57, 127);
+        (0..24_000).map(|_| engine.render(8_000.0).0).skip(4_800).collect()
+    }
+
+    #[test]
+    fn voices_follow_pitch_and_cutoff_modulation() {
+        let route = |destination, amount| {
+            Some(ModRoute {
+                source: ModSource::Velocity,
+                destination,
+                amount,
+            })
+        };
+        let plain = render_note(None);
+        // Period as the first lag where the signal lines up with itself again
+        let period = |samples: &[f32]| {
+            let correlation = |lag: usize| samples.iter().zip(&samples[lag..]).map(|(a, b)| a * b).sum::<f32>();
+            let peak = (20..1_000).map(correlation).fold(0.0, f32::max);
+            (21..999)
+                .find(|&lag| {
+                    let c = correlation(lag);
+                    c > 0.8 * peak && c >= correlation(lag - 1) && c >= correlation(lag + 1)
+                })
+                .unwrap() as f32
+        };
+        let rms = |samples: &[f32]| (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+
+        // Full velocity sends the pitch up an octave
+        let octave_up = render_note(route(ModDestination::Pitch, 12.0));
+        let ratio = period(&plain) / period(&octave_up);
+        assert!((ratio - 2.0).abs() < 0.05, "pitch ratio {ratio}");
+
+        // Two octaves down on the cutoff, which the saw and sine follow in level. The bow noise keeps its own
+        let darker = render_note(route(ModDestination::Cutoff, -2.0));
+        let ratio = rms(&darker) / rms(&plain);
+        assert!((0.25..0.35).contains(&ratio), "level ratio {ratio}");
+    }
+
+    #[test]
+    fn installed_instruments_keep_each_channel_on_its_program() {
+        let mut engine = OrchestraEngine::new(48_000.0);
+        engine.program_change(0, 3);
+        engine.program_change(1, 5);
+        engine.note_on(1, 60, 100);
+        assert!(engine.voices.iter().any(|voice| voice.active));
+
+        let mut instruments = Instruments::new(48_000.0);
+        let samples = SampleSet {
+            name: "Cellos SFZ".to_string(),
+            ..SampleSet::default()
+        };
+        instruments.add_sample_instrument(3, samples);
+        engine.install(&mut instruments);
+
+        let patch = engine.bank.get(engine.channels[0].patch);
+        assert_eq!((patch.program, patch.model), (3, SynthModel::Sampled));
+        assert_eq!(patch.section, Section::ALL[3]);
+        assert_eq!(engine.bank.get(engine.channels[1].patch).program, 5);
+        assert!(engine.voices.iter().all(|voice| !voice.active));
+        // The replaced bank comes back to be freed by the caller
+        assert!(instruments.bank.patches.iter().all(|p| p.sample_set.is_none()));
+        assert_eq!(engine.sample_sets.len(), 1);
+        assert!(instruments.sample_sets.is_empty());
+    }
+
+    #[test]
+    fn stream_starts_lost_on_the_way_are_sent_again() {
+        use crate::sampler::{SampleData, SampleZone};
+        use crate::streaming::{StreamReader, StreamSource};
+
+        let data = SampleData {
+            frames: vec![[0.1; 2]; 1_000],
+            sample_rate: 48_000.0,
+            stream: Some(StreamSource {
+                path: "missing.wav".into(),
+                frames: 100_000,
+            }),
+        };
+        let samples = SampleSet {
+            name: "Streamed".to_string(),
+            zones: vec![SampleZone::new(Arc::new(data), 60)],
+            ..SampleSet::default()
+        };
+        let mut instruments = Instruments::new(48_000.0);
+        instruments.add_sample_instrument(0, samples);
+        let mut engine = OrchestraEngine::new(48_000.0);
+        engine.install(&mut instruments);
+        engine.note_on(0, 60, 100);
+        let starts = |engine: &OrchestraEngine| {
+            engine
+                .stream_requests
+                .iter()
+                .filter(|r| matches!(r, StreamRequest::Start { .. }))
+                .count()
+        };
+
+        engine.poll_streams();
+        assert_eq!(starts(&engine), 1);
+        // The host dropped it, and the next block is too soon to tell
+        engine.stream_requests.clear();
+        engine.render(10_000.0);
+        engine.poll_streams();
+        assert!(engine.stream_requests.is_empty());
+
+        for _ in 0..STREAM_CHUNK_FRAMES {
+            engine.render(10_000.0);
+        }
+        engine.poll_streams();
+        assert_eq!(starts(&engine), 1);
+
+        // Once the reader has the start, only refills follow
+        let mut reader = StreamReader::new(engine.streams.clone());
+        for request in engine.stream_requests.drain(..) {
+            assert!(reader.run(request).is_err());
+        }
+        for _ in 0..STREAM_CHUNK_FRAMES {
+            engine.render(10_000.0);
+        }
+        engine.poll_streams();
+        assert_eq!(starts(&engine), 0);
+        assert!(matches!(engine.stream_requests[..], [StreamRequest::Fill { .. }]));
+    }
+
+    #[test]
+    fn reset_plays_a_song_back_the_same() {
+        let mut engine = OrchestraEngine::new(48_000.0);
+        engine.midi.reseed(77);
+        let play = |engine: &mut OrchestraEngine| {
+            let mut out = Vec::new();
+            for note in [48, 55, 60, 64] {
+                engine.note_on(0, note, 90);
+                out.extend((0..2_400).map(|_| engine.render(8_000.0)));
+            }
+            out
+        };
+        let first = play(&mut engine);
+        engine.reset();
+        // Humanization and the ensemble start over from the seed the song was played with
+        assert_eq!(engine.midi.seed(), 77);
+        assert_eq!(play(&mut engine), first);
+    }
+}
@@ -1,39 +1,490 @@
 use nih_plug::prelude::*;
-use std::sync::Arc;
+use nih_plug_egui::EguiState;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
+pub mod cc_map;
+pub mod convolution;
+mod editor;
 pub mod engine;
+pub mod patch;
+pub mod preset;
+pub mod reflections;
+pub mod reverb;
+pub mod sampler;
+pub mod sf2;
+pub mod sfz;
+pub mod stage;
+pub mod state;
+pub mod streaming;
+pub mod tuning;
+pub mod waveguide;
 
-use engine::{Articulation, MAX_VOICES, MidiProcessor, SmoothedValue, Voice};
+use cc_map::{CcBinding, CcTarget};
+use preset::{apply_channel_layout, ChannelPreset};
+use convolution::ImpulseResponse;
+use reverb::ReverbSettings;
+use stage::Seating;
+use state::EngineState;
+use streaming::{StreamReader, StreamRequest};
+use engine::{
+    BrassMute, Family, Instruments, OrchestraEngine, RangePolicy, Section, SynthModel,
+    ENSEMBLE_DETUNE_CENTS, ENSEMBLE_SPREAD_MS, MAX_SECTION_SIZE, MAX_VOICES, MIDI_CHANNELS, SOLO_GLIDE_MS,
+};
+use tuning::{MtsSysEx, Temperament, Tuning, MTS_MAX_LEN};
+
+// Sections with their own choice of string model
+const STRING_SECTIONS: [Section; 5] = [
+    Section::Violins1,
+    Section::Violins2,
+    Section::Violas,
+    Section::Cellos,
+    Section::Basses,
+];
 
 pub struct SmartOrchestraVST {
     params: Arc<SmartParams>,
-    voices: Vec<Voice>,
-    midi: MidiProcessor,
-    cc1: SmoothedValue,
-    cc11: SmoothedValue,
-    sample_rate: f32,
-    global_sample: i64,
+    engine: OrchestraEngine,
+    learn_target: LearnTarget,
+    string_models: [StringModelChoice; STRING_SECTIONS.len()],
+    brass_model: BrassModelChoice,
+    brass_mute: BrassMuteChoice,
+    pending_learn: Option<CcBinding>,
+    // Channel layout of a preset picked in the editor, for the audio thread to apply
+    pending_layout: Arc<Mutex<Option<[ChannelPreset; MIDI_CHANNELS]>>>,
+    instruments: Arc<Mutex<InstrumentSwap>>,
+    // Bits of the rate the background thread loads instruments for, set by `initialize`
+    sample_rate: Arc<AtomicU32>,
+}
+
+// Work for nih-plug's background thread
+pub enum Task {
+    Stream(StreamRequest),
+    // Reads every file the engine state points at, for the audio thread to swap in
+    LoadInstruments,
+    // Frees the instruments the audio thread swapped out
+    ReleaseInstruments,
+}
+
+// Instruments loaded on the background thread wait here for the audio thread, which leaves the ones they
+// replace behind to be freed back on the background thread
+#[derive(Default)]
+struct InstrumentSwap {
+    incoming: Option<Instruments>,
+    outgoing: Option<Instruments>,
+    // SoundFont of the latest load. A new one puts channel 10 on its drum kit, as General MIDI does
+    sound_font: Option<PathBuf>,
+    new_sound_font: bool,
 }
 
+// Parameters are grouped for the host, the ids stay flat so automation from earlier versions still finds them
 #[derive(Params)]
 struct SmartParams {
-    #[id = "output"]
-    pub output_gain: FloatParam,
+    #[nested(group = "Dynamics")]
+    pub dynamics: DynamicsParams,
+
+    #[nested(group = "Articulation")]
+    pub articulation: ArticulationParams,
+
+    #[nested(group = "Legato")]
+    pub legato: LegatoParams,
+
+    #[nested(group = "Humanize")]
+    pub humanize: HumanizeParams,
+
+    #[nested(group = "Tone")]
+    pub tone: ToneParams,
+
+    #[nested(group = "Stage")]
+    pub stage: StageParams,
+
+    #[nested(group = "Reverb")]
+    pub reverb: ReverbParams,
+
+    #[nested(group = "Mix")]
+    pub mix: MixParams,
+
+    #[id = "learn"]
+    pub learn_target: EnumParam<LearnTarget>,
+
+    #[id = "range"]
+    pub range_policy: EnumParam<RangePolicyChoice>,
+
+    // Same key as `state::STATE_KEY`, which the migration of older projects writes to
+    #[persist = "engine"]
+    pub engine_state: RwLock<EngineState>,
+
+    #[persist = "editor-state"]
+    pub editor_state: Arc<EguiState>,
+}
+
+#[derive(Params)]
+struct DynamicsParams {
+    #[id = "dynrange"]
+    pub dynamic_range: FloatParam,
+}
+
+#[derive(Params)]
+struct ArticulationParams {
+    #[id = "atkscale"]
+    pub attack_scale: FloatParam,
+
+    #[id = "relscale"]
+    pub release_scale: FloatParam,
+
+    #[id = "vibdepth"]
+    pub vibrato_depth: FloatParam,
+
+    #[id = "vibrate"]
+    pub vibrato_rate: FloatParam,
+
+    #[id = "vibdelay"]
+    pub vibrato_delay: FloatParam,
+
+    #[id = "vibsrc"]
+    pub vibrato_source: EnumParam<VibratoSource>,
+}
 
+#[derive(Params)]
+struct LegatoParams {
+    #[id = "glide"]
+    pub glide_ms: FloatParam,
+}
+
+#[derive(Params)]
+struct HumanizeParams {
+    #[id = "humanize"]
+    pub amount: FloatParam,
+
+    #[id = "ensdetune"]
+    pub ensemble_detune: FloatParam,
+
+    #[id = "ensspread"]
+    pub ensemble_spread: FloatParam,
+
+    #[id = "secsize"]
+    pub section_size: IntParam,
+}
+
+#[derive(Params)]
+struct ToneParams {
     #[id = "cutoff"]
     pub cutoff_hz: FloatParam,
+
+    #[id = "reso"]
+    pub resonance: FloatParam,
+
+    #[id = "concert"]
+    pub concert_pitch: FloatParam,
+
+    #[id = "temper"]
+    pub temperament: EnumParam<TemperamentChoice>,
+
+    #[id = "justint"]
+    pub smart_intonation: BoolParam,
+
+    #[id = "strmodel"]
+    pub string_model: EnumParam<StringModelChoice>,
+
+    // Sections can leave the strings' model for the other one, in STRING_SECTIONS order
+    #[id = "vn1model"]
+    pub violins1_model: EnumParam<SectionModelChoice>,
+
+    #[id = "vn2model"]
+    pub violins2_model: EnumParam<SectionModelChoice>,
+
+    #[id = "vlamodel"]
+    pub violas_model: EnumParam<SectionModelChoice>,
+
+    #[id = "vcmodel"]
+    pub cellos_model: EnumParam<SectionModelChoice>,
+
+    #[id = "cbmodel"]
+    pub basses_model: EnumParam<SectionModelChoice>,
+
+    #[id = "brsmodel"]
+    pub brass_model: EnumParam<BrassModelChoice>,
+
+    #[id = "brsmute"]
+    pub brass_mute: EnumParam<BrassMuteChoice>,
+}
+
+#[derive(Params)]
+struct StageParams {
+    #[id = "seating"]
+    pub seating: EnumParam<SeatingChoice>,
+
+    #[id = "stgdepth"]
+    pub depth: FloatParam,
+
+    #[id = "width"]
+    pub stereo_width: FloatParam,
+}
+
+#[derive(Params)]
+struct ReverbParams {
+    #[id = "revpre"]
+    pub pre_delay_ms: FloatParam,
+
+    #[id = "revdecay"]
+    pub decay_s: FloatParam,
+
+    #[id = "revdamp"]
+    pub damping: FloatParam,
+
+    #[id = "revsize"]
+    pub size: FloatParam,
+
+    #[id = "revmix"]
+    pub mix: FloatParam,
+
+    #[id = "revearly"]
+    pub early_level: FloatParam,
+}
+
+#[derive(Params)]
+struct MixParams {
+    #[id = "output"]
+    pub output_gain: FloatParam,
+
+    #[id = "revsend"]
+    pub reverb_send: FloatParam,
+
+    #[id = "voices"]
+    pub voice_count: IntParam,
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LearnTarget {
+    Off,
+    Dynamics,
+    Expression,
+    Cutoff,
+    Release,
+    Legato,
+    Vibrato,
+    Solo,
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum VibratoSource {
+    #[name = "Vibrato CC"]
+    VibratoCc,
+    #[name = "Dynamics"]
+    Dynamics,
+}
+
+impl LearnTarget {
+    fn cc_target(self) -> Option<CcTarget> {
+        match self {
+            LearnTarget::Off => None,
+            LearnTarget::Dynamics => Some(CcTarget::Dynamics),
+            LearnTarget::Expression => Some(CcTarget::Expression),
+            LearnTarget::Cutoff => Some(CcTarget::Cutoff),
+            LearnTarget::Release => Some(CcTarget::Release),
+            LearnTarget::Legato => Some(CcTarget::Legato),
+            LearnTarget::Vibrato => Some(CcTarget::Vibrato),
+            LearnTarget::Solo => Some(CcTarget::Solo),
+        }
+    }
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum StringModelChoice {
+    #[name = "Saw + Sine"]
+    Subtractive,
+    #[name = "Bowed Waveguide"]
+    Bowed,
+}
+
+impl StringModelChoice {
+    fn model(self) -> SynthModel {
+        match self {
+            StringModelChoice::Subtractive => SynthModel::Subtractive,
+            StringModelChoice::Bowed => SynthModel::BowedString,
+        }
+    }
+
+    fn from_model(model: SynthModel) -> Self {
+        match model {
+            SynthModel::BowedString => StringModelChoice::Bowed,
+            _ => StringModelChoice::Subtractive,
+        }
+    }
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum SectionModelChoice {
+    #[name = "String Model"]
+    Strings,
+    #[name = "Saw + Sine"]
+    Subtractive,
+    #[name = "Bowed Waveguide"]
+    Bowed,
+}
+
+impl SectionModelChoice {
+    fn resolve(self, strings: StringModelChoice) -> StringModelChoice {
+        match self {
+            SectionModelChoice::Strings => strings,
+            SectionModelChoice::Subtractive => StringModelChoice::Subtractive,
+            SectionModelChoice::Bowed => StringModelChoice::Bowed,
+        }
+    }
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum BrassModelChoice {
+    #[name = "Saw + Sine"]
+    Subtractive,
+    #[name = "Brass Formant"]
+    Brass,
+}
+
+impl BrassModelChoice {
+    fn model(self) -> SynthModel {
+        match self {
+            BrassModelChoice::Subtractive => SynthModel::Subtractive,
+            BrassModelChoice::Brass => SynthModel::Brass,
+        }
+    }
+
+    fn from_model(model: SynthModel) -> Self {
+        match model {
+            SynthModel::Brass => BrassModelChoice::Brass,
+            _ => BrassModelChoice::Subtractive,
+        }
+    }
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum BrassMuteChoice {
+    #[name = "Open"]
+    Open,
+    #[name = "Straight"]
+    Straight,
+    #[name = "Cup"]
+    Cup,
+    #[name = "Stopped"]
+    Stopped,
+}
+
+impl BrassMuteChoice {
+    fn mute(self) -> BrassMute {
+        match self {
+            BrassMuteChoice::Open => BrassMute::Open,
+            BrassMuteChoice::Straight => BrassMute::Straight,
+            BrassMuteChoice::Cup => BrassMute::Cup,
+            BrassMuteChoice::Stopped => BrassMute::Stopped,
+        }
+    }
+
+    fn from_mute(mute: BrassMute) -> Self {
+        match mute {
+            BrassMute::Open => BrassMuteChoice::Open,
+            BrassMute::Straight => BrassMuteChoice::Straight,
+            BrassMute::Cup => BrassMuteChoice::Cup,
+            BrassMute::Stopped => BrassMuteChoice::Stopped,
+        }
+    }
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum SeatingChoice {
+    #[name = "American"]
+    American,
+    #[name = "European"]
+    European,
+}
+
+impl SeatingChoice {
+    fn seating(self) -> Seating {
+        match self {
+            SeatingChoice::American => Seating::American,
+            SeatingChoice::European => Seating::European,
+        }
+    }
+
+    fn from_seating(seating: Seating) -> Self {
+        match seating {
+            Seating::American => SeatingChoice::American,
+            Seating::European => SeatingChoice::European,
+        }
+    }
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum RangePolicyChoice {
+    #[name = "Ignore"]
+    Ignore,
+    #[name = "Octave Fold"]
+    Fold,
+    #[name = "Hand Off"]
+    HandOff,
+    #[name = "Flag"]
+    Flag,
+}
+
+impl RangePolicyChoice {
+    fn policy(self) -> RangePolicy {
+        match self {
+            RangePolicyChoice::Ignore => RangePolicy::Ignore,
+            RangePolicyChoice::Fold => RangePolicy::Fold,
+            RangePolicyChoice::HandOff => RangePolicy::HandOff,
+            RangePolicyChoice::Flag => RangePolicy::Flag,
+        }
+    }
+
+    fn from_policy(policy: RangePolicy) -> Self {
+        match policy {
+            RangePolicy::Ignore => RangePolicyChoice::Ignore,
+            RangePolicy::Fold => RangePolicyChoice::Fold,
+            RangePolicy::HandOff => RangePolicyChoice::HandOff,
+            RangePolicy::Flag => RangePolicyChoice::Flag,
+        }
+    }
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+enum TemperamentChoice {
+    #[name = "Equal"]
+    Equal,
+    #[name = "Meantone (1/4 comma)"]
+    Meantone,
+    #[name = "Werckmeister III"]
+    Werckmeister,
+    #[name = "Vallotti"]
+    Vallotti,
+    #[name = "Kirnberger III"]
+    Kirnberger,
+}
+
+impl TemperamentChoice {
+    fn temperament(self) -> Temperament {
+        match self {
+            TemperamentChoice::Equal => Temperament::Equal,
+            TemperamentChoice::Meantone => Temperament::Meantone,
+            TemperamentChoice::Werckmeister => Temperament::Werckmeister,
+            TemperamentChoice::Vallotti => Temperament::Vallotti,
+            TemperamentChoice::Kirnberger => Temperament::Kirnberger,
+        }
+    }
 }
 
 impl Default for SmartOrchestraVST {
     fn default() -> Self {
         Self {
             params: Arc::new(SmartParams::default()),
-            voices: (0..MAX_VOICES).map(|_| Voice::new()).collect(),
-            midi: MidiProcessor::new(),
-            cc1: SmoothedValue::new(0.5),
-            cc11: SmoothedValue::new(1.0),
-            sample_rate: 44100.0,
-            global_sample: 0,
+            engine: OrchestraEngine::new(44100.0),
+            learn_target: LearnTarget::Off,
+            string_models: [StringModelChoice::Subtractive; STRING_SECTIONS.len()],
+            brass_model: BrassModelChoice::Subtractive,
+            brass_mute: BrassMuteChoice::Open,
+            pending_learn: None,
+            pending_layout: Arc::new(Mutex::new(None)),
+            instruments: Arc::new(Mutex::new(InstrumentSwap::default())),
+            sample_rate: Arc::new(AtomicU32::new(44100f32.to_bits())),
         }
     }
 }
@@ -41,16 +492,187 @@ impl Default for SmartOrchestraVST {
 impl Default for SmartParams {
     fn default() -> Self {
         Self {
-            output_gain: FloatParam::new(
-                "Output",
-                0.0,
+            dynamics: DynamicsParams::default(),
+            articulation: ArticulationParams::default(),
+            legato: LegatoParams::default(),
+            humanize: HumanizeParams::default(),
+            tone: ToneParams::default(),
+            stage: StageParams::default(),
+            reverb: ReverbParams::default(),
+            mix: MixParams::default(),
+            learn_target: EnumParam::new("MIDI Learn", LearnTarget::Off),
+            range_policy: EnumParam::new("Range Policy", RangePolicyChoice::Flag),
+            // Each new instance humanizes differently, a saved project keeps the seed it was played with
+            engine_state: RwLock::new(EngineState {
+                humanize_seed: rand::random(),
+                ..EngineState::default()
+            }),
+            editor_state: editor::default_state(),
+        }
+    }
+}
+
+impl Default for DynamicsParams {
+    fn default() -> Self {
+        Self {
+            // How far CC1 takes the level down from fortissimo, 100% being the original curve
+            dynamic_range: FloatParam::new(
+                "Dynamic Range",
+                1.0,
                 FloatRange::Linear {
-                    min: -24.0,
-                    max: 6.0,
+                    min: 0.0,
+                    max: 1.5,
                 },
             )
-            .with_unit(" dB")
-            .with_smoother(SmoothingStyle::Logarithmic(50.0)),
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_unit("%"),
+        }
+    }
+}
+
+impl Default for ArticulationParams {
+    fn default() -> Self {
+        // Multipliers of the patch envelopes, with 100% in the middle of the knob
+        let time_scale = |name| {
+            FloatParam::new(
+                name,
+                1.0,
+                FloatRange::SymmetricalSkewed {
+                    min: 0.25,
+                    max: 4.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                    center: 1.0,
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_unit("%")
+        };
+
+        Self {
+            attack_scale: time_scale("Attack Scale"),
+            release_scale: time_scale("Release Scale"),
+            vibrato_depth: FloatParam::new(
+                "Vibrato Depth",
+                1.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 2.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_unit("%"),
+            vibrato_rate: FloatParam::new(
+                "Vibrato Rate",
+                1.0,
+                FloatRange::Linear {
+                    min: 0.5,
+                    max: 2.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_unit("%"),
+            vibrato_delay: FloatParam::new(
+                "Vibrato Delay",
+                1.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 3.0,
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_unit("%"),
+            vibrato_source: EnumParam::new("Vibrato Source", VibratoSource::VibratoCc),
+        }
+    }
+}
+
+impl Default for LegatoParams {
+    fn default() -> Self {
+        Self {
+            glide_ms: FloatParam::new(
+                "Legato Glide",
+                SOLO_GLIDE_MS,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 500.0,
+                    factor: FloatRange::skew_factor(-1.5),
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_rounded(0))
+            .with_unit(" ms"),
+        }
+    }
+}
+
+impl Default for HumanizeParams {
+    fn default() -> Self {
+        Self {
+            amount: FloatParam::new(
+                "Humanize",
+                1.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 2.0,
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_unit("%"),
+            ensemble_detune: FloatParam::new(
+                "Ensemble Detune",
+                ENSEMBLE_DETUNE_CENTS,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 25.0,
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_rounded(1))
+            .with_unit(" cents"),
+            ensemble_spread: FloatParam::new(
+                "Ensemble Spread",
+                ENSEMBLE_SPREAD_MS,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 50.0,
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_rounded(0))
+            .with_unit(" ms"),
+            section_size: IntParam::new(
+                "Section Size",
+                1,
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_SECTION_SIZE as i32,
+                },
+            )
+            .with_unit(" players"),
+        }
+    }
+}
+
+impl ToneParams {
+    fn section_models(&self) -> [&EnumParam<SectionModelChoice>; STRING_SECTIONS.len()] {
+        [
+            &self.violins1_model,
+            &self.violins2_model,
+            &self.violas_model,
+            &self.cellos_model,
+            &self.basses_model,
+        ]
+    }
+}
+
+impl Default for ToneParams {
+    fn default() -> Self {
+        Self {
             cutoff_hz: FloatParam::new(
                 "LP Cutoff",
                 10000.0,
@@ -60,8 +682,189 @@ impl Default for SmartParams {
                     factor: FloatRange::skew_factor(-2.0),
                 },
             )
+            .with_smoother(SmoothingStyle::Logarithmic(50.0))
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(1))
+            .with_string_to_value(formatters::s2v_f32_hz_then_khz()),
+            resonance: FloatParam::new(
+                "Resonance",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_unit("%"),
+            concert_pitch: FloatParam::new(
+                "Concert Pitch",
+                440.0,
+                FloatRange::Linear {
+                    min: 380.0,
+                    max: 480.0,
+                },
+            )
             .with_unit(" Hz")
-            .with_smoother(SmoothingStyle::Logarithmic(50.0)),
+            .with_step_size(0.1)
+            .with_value_to_string(formatters::v2s_f32_rounded(1)),
+            temperament: EnumParam::new("Temperament", TemperamentChoice::Equal),
+            smart_intonation: BoolParam::new("Smart Intonation", false),
+            string_model: EnumParam::new("String Model", StringModelChoice::Subtractive),
+            violins1_model: EnumParam::new("Violins I Model", SectionModelChoice::Strings),
+            violins2_model: EnumParam::new("Violins II Model", SectionModelChoice::Strings),
+            violas_model: EnumParam::new("Violas Model", SectionModelChoice::Strings),
+            cellos_model: EnumParam::new("Cellos Model", SectionModelChoice::Strings),
+            basses_model: EnumParam::new("Basses Model", SectionModelChoice::Strings),
+            brass_model: EnumParam::new("Brass Model", BrassModelChoice::Subtractive),
+            brass_mute: EnumParam::new("Brass Mute", BrassMuteChoice::Open),
+        }
+    }
+}
+
+impl Default for StageParams {
+    fn default() -> Self {
+        Self {
+            seating: EnumParam::new("Seating", SeatingChoice::American),
+            // Moves the pre-delay of the back rows, so it is set per block rather than smoothed
+            depth: FloatParam::new(
+                "Stage Depth",
+                1.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_unit("%"),
+            stereo_width: FloatParam::new(
+                "Stereo Width",
+                1.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 2.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_unit("%"),
+        }
+    }
+}
+
+impl Default for ReverbParams {
+    fn default() -> Self {
+        let defaults = ReverbSettings::default();
+        Self {
+            // The room is set per block, only the mix is smooth enough to sweep
+            pre_delay_ms: FloatParam::new(
+                "Pre-Delay",
+                defaults.pre_delay_ms,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: reverb::MAX_PRE_DELAY_MS,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_rounded(0))
+            .with_unit(" ms"),
+            decay_s: FloatParam::new(
+                "Decay",
+                defaults.decay_s,
+                FloatRange::Skewed {
+                    min: 0.2,
+                    max: 10.0,
+                    factor: FloatRange::skew_factor(-1.5),
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_rounded(2))
+            .with_unit(" s"),
+            damping: FloatParam::new(
+                "Damping",
+                defaults.damping,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_unit("%"),
+            size: FloatParam::new(
+                "Size",
+                defaults.size,
+                FloatRange::Linear {
+                    min: reverb::MIN_SIZE,
+                    max: reverb::MAX_SIZE,
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_unit("%"),
+            mix: FloatParam::new(
+                "Reverb Mix",
+                defaults.mix,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_unit("%"),
+            early_level: FloatParam::new(
+                "Early Reflections",
+                defaults.early_level,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_unit("%"),
+        }
+    }
+}
+
+impl Default for MixParams {
+    fn default() -> Self {
+        Self {
+            output_gain: FloatParam::new(
+                "Output",
+                0.0,
+                FloatRange::Linear {
+                    min: -24.0,
+                    max: 6.0,
+                },
+            )
+            .with_unit(" dB")
+            .with_smoother(SmoothingStyle::Logarithmic(50.0))
+            .with_value_to_string(formatters::v2s_f32_rounded(1)),
+            reverb_send: FloatParam::new(
+                "Reverb Send",
+                0.5,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_unit("%"),
+            voice_count: IntParam::new(
+                "Voice Count",
+                MAX_VOICES as i32,
+                IntRange::Linear {
+                    min: 8,
+                    max: MAX_VOICES as i32,
+                },
+            )
+            .with_unit(" voices"),
         }
     }
 }
@@ -85,36 +888,155 @@ impl Plugin for SmartOrchestraVST {
     const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
-    type SysExMessage = ();
-    type BackgroundTask = ();
+    type SysExMessage = MtsSysEx;
+    type BackgroundTask = Task;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
 
+    fn editor(&mut self, async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+        editor::create(self.params.clone(), self.pending_layout.clone(), async_executor)
+    }
+
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        // nih-plug serves the tasks one at a time on its background thread, as the reader expects
+        let reader = Mutex::new(StreamReader::new(self.engine.streams.clone()));
+        let streams = self.engine.streams.clone();
+        let reported = AtomicU64::new(0);
+        let params = self.params.clone();
+        let instruments = self.instruments.clone();
+        let sample_rate = self.sample_rate.clone();
+        Box::new(move |task| match task {
+            Task::Stream(request) => {
+                if let Ok(mut reader) = reader.lock() {
+                    if let Err(err) = reader.run(request) {
+                        nih_log!("Sample streaming failed: {err:#}");
+                    }
+                }
+                let underruns = streams.underruns();
+                if underruns > reported.swap(underruns, Ordering::Relaxed) {
+                    nih_log!("Sample streaming underruns: {underruns} frame(s)");
+                }
+            }
+            Task::LoadInstruments => {
+                let state = params.engine_state.read().map(|state| state.clone()).unwrap_or_default();
+                let loaded = load_instruments(&state, f32::from_bits(sample_rate.load(Ordering::Relaxed)));
+                let Ok(mut swap) = instruments.lock() else {
+                    return;
+                };
+                swap.new_sound_font |= state.sound_font.is_some() && swap.sound_font != state.sound_font;
+                swap.sound_font = state.sound_font;
+                // A load the audio thread has not taken yet is out of date, and what the last swap left
+                // behind goes too, in case its release task was dropped
+                let stale = (swap.incoming.replace(loaded), swap.outgoing.take());
+                drop(swap);
+                drop(stale);
+            }
+            Task::ReleaseInstruments => {
+                let released = instruments.lock().map(|mut swap| swap.outgoing.take());
+                drop(released);
+            }
+        })
+    }
+
     fn initialize(
         &mut self,
         _audio_io_layout: &AudioIOLayout,
         buffer_config: &BufferConfig,
         _context: &mut impl InitContext<Self>,
     ) -> bool {
-        self.sample_rate = buffer_config.sample_rate;
+        self.engine.set_sample_rate(buffer_config.sample_rate);
+        self.sample_rate.store(buffer_config.sample_rate.to_bits(), Ordering::Relaxed);
+        let state = self
+            .params
+            .engine_state
+            .read()
+            .map(|state| state.clone())
+            .unwrap_or_default();
+        self.engine.cc_map = state.cc_map.clone();
+        match Tuning::from_config(&state.tuning) {
+            Ok(tuning) => self.engine.tuning = tuning,
+            Err(err) => nih_log!("Invalid tuning in plugin state: {err:#}"),
+        }
+        self.engine.mod_matrix = state.mod_matrix.clone();
+        // Loads made for the previous sample rate are of no use now, the engine gets its instruments here
+        if let Ok(mut swap) = self.instruments.lock() {
+            *swap = InstrumentSwap {
+                sound_font: state.sound_font.clone(),
+                ..InstrumentSwap::default()
+            };
+        }
+        let mut instruments = load_instruments(&state, buffer_config.sample_rate);
+        self.engine.install(&mut instruments);
+        self.apply_models(true);
+        state.restore_layout(&mut self.engine);
+        self.learn_target = self.params.learn_target.value();
         true
     }
 
-    fn reset(&mut self) {
-        self.global_sample = 0;
-        for voice in &mut self.voices {
-            *voice = Voice::new();
+    fn filter_state(state: &mut PluginState) {
+        if let Err(err) = state::migrate_fields(&mut state.fields) {
+            nih_log!("Failed to migrate plugin state: {err:#}");
         }
     }
 
+    fn reset(&mut self) {
+        self.engine.reset();
+    }
+
     fn process(
         &mut self,
         buffer: &mut Buffer,
         _aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
+        self.swap_instruments(context);
+        let learn_target = self.params.learn_target.value();
+        if learn_target != self.learn_target {
+            self.learn_target = learn_target;
+            self.engine.cc_map.arm_learn(learn_target.cc_target());
+        }
+        self.apply_models(false);
+
+        // The editor sets the other settings of a preset as parameters
+        if let Some(channels) = self.pending_layout.try_lock().ok().and_then(|mut layout| layout.take()) {
+            apply_channel_layout(&mut self.engine, &channels);
+        }
+
+        let (tone, articulation) = (&self.params.tone, &self.params.articulation);
+        let (humanize, mix) = (&self.params.humanize, &self.params.mix);
+        self.engine.tuning.set_concert_pitch(tone.concert_pitch.value());
+        self.engine.tuning.set_temperament(tone.temperament.value().temperament());
+        self.engine.vibrato_delay = articulation.vibrato_delay.value();
+        self.engine.attack_scale = articulation.attack_scale.value();
+        self.engine.release_scale = articulation.release_scale.value();
+        self.engine.dynamic_range = self.params.dynamics.dynamic_range.value();
+        self.engine.legato_glide_ms = self.params.legato.glide_ms.value();
+        self.engine.humanize_amount = humanize.amount.value();
+        self.engine.ensemble_detune_cents = humanize.ensemble_detune.value();
+        self.engine.ensemble_spread_ms = humanize.ensemble_spread.value();
+        self.engine.section_size = humanize.section_size.value() as usize;
+        self.engine.voice_limit = mix.voice_count.value() as usize;
+        self.engine.stage.seating = self.params.stage.seating.value().seating();
+        self.engine.stage.depth = self.params.stage.depth.value();
+        let reverb = &self.params.reverb;
+        let settings = &mut self.engine.reverb.settings;
+        settings.pre_delay_ms = reverb.pre_delay_ms.value();
+        settings.decay_s = reverb.decay_s.value();
+        settings.damping = reverb.damping.value();
+        settings.size = reverb.size.value();
+        settings.early_level = reverb.early_level.value();
+        self.engine.range_policy = self.params.range_policy.value().policy();
+        // Flagged notes are only reported by the test host, the plugin just keeps the list from filling up
+        self.engine.range_violations.clear();
+        self.engine.vibrato_from_dynamics = articulation.vibrato_source.value() == VibratoSource::Dynamics;
+        let smart_intonation = tone.smart_intonation.value();
+        if smart_intonation != self.engine.smart_intonation {
+            self.engine.smart_intonation = smart_intonation;
+            self.engine.update_intonation();
+        }
+
         let mut next_event = context.next_event();
 
         for (sample_idx, mut channel_samples) in buffer.iter_samples().enumerate() {
@@ -124,40 +1046,56 @@ impl Plugin for SmartOrchestraVST {
                 }
 
                 match event {
-                    NoteEvent::NoteOn { note, velocity, .. } => self.handle_note_on(note, velocity),
-                    NoteEvent::NoteOff { note, .. } => self.handle_note_off(note),
-                    NoteEvent::MidiCC { cc, value, .. } => self.handle_cc(cc, value),
+                    NoteEvent::NoteOn {
+                        channel,
+                        note,
+                        velocity,
+                        ..
+                    } => self.handle_note_on(channel, note, velocity),
+                    NoteEvent::NoteOff { channel, note, .. } => self.engine.note_off(channel, note),
+                    NoteEvent::MidiCC { channel, cc, value, .. } => self.handle_cc(channel, cc, value),
+                    NoteEvent::MidiProgramChange { channel, program, .. } => {
+                        self.engine.program_change(channel, program)
+                    }
+                    NoteEvent::MidiSysEx { message, .. } => self.engine.handle_sysex(&message),
+                    NoteEvent::MidiChannelPressure { channel, pressure, .. } => {
+                        self.engine.channel_pressure(channel, pressure)
+                    }
+                    NoteEvent::PolyPressure {
+                        channel,
+                        note,
+                        pressure,
+                        ..
+                    } => self.engine.poly_pressure(channel, note, pressure),
                     _ => {}
                 }
                 next_event = context.next_event();
             }
 
-            let cc1 = self.cc1.next();
-            let cc11 = self.cc11.next();
-            let dyn_mod = 0.4 + cc1 * 0.75;
-            let expression = cc11;
-            let cutoff_hz = self.params.cutoff_hz.smoothed.next();
-            let output_amp = util::db_to_gain(self.params.output_gain.smoothed.next());
-
-            let mut left = 0.0;
-            let mut right = 0.0;
-
-            for voice in &mut self.voices {
-                if voice.active {
-                    voice.set_layer_gain(dyn_mod, self.sample_rate);
-                    let (l, r) = voice.render(self.sample_rate, cutoff_hz);
-                    left += l;
-                    right += r;
-                }
-            }
+            let (tone, articulation) = (&self.params.tone, &self.params.articulation);
+            self.engine.vibrato_depth = articulation.vibrato_depth.smoothed.next();
+            self.engine.vibrato_rate = articulation.vibrato_rate.smoothed.next();
+            self.engine.resonance = tone.resonance.smoothed.next();
+            self.engine.stereo_width = self.params.stage.stereo_width.smoothed.next();
+            self.engine.reverb_send = self.params.mix.reverb_send.smoothed.next();
+            self.engine.reverb.settings.mix = self.params.reverb.mix.smoothed.next();
+            let cutoff_hz = tone.cutoff_hz.smoothed.next();
+            let output_amp = util::db_to_gain(self.params.mix.output_gain.smoothed.next());
+            let (left, right) = self.engine.render(cutoff_hz);
 
             if let Some(s) = channel_samples.get_mut(0) {
-                *s = left * expression * output_amp;
+                *s = left * output_amp;
             }
             if let Some(s) = channel_samples.get_mut(1) {
-                *s = right * expression * output_amp;
+                *s = right * output_amp;
             }
-            self.global_sample += 1;
+        }
+
+        self.store_learned_binding();
+        self.store_engine_state();
+        self.engine.poll_streams();
+        for request in self.engine.stream_requests.drain(..) {
+            context.execute_background(Task::Stream(request));
         }
 
         ProcessStatus::Normal
@@ -165,53 +1103,131 @@ impl Plugin for SmartOrchestraVST {
 }
 
 impl SmartOrchestraVST {
-    fn handle_note_on(&mut self, note: u8, velocity_norm: f32) {
+    // Only a change of the parameters touches the bank, so models set elsewhere survive. A bank that was
+    // just swapped in has the factory models and takes all of them
+    fn apply_models(&mut self, force: bool) {
+        let tone = &self.params.tone;
+        let string_model = tone.string_model.value();
+        for ((section, param), applied) in STRING_SECTIONS
+            .into_iter()
+            .zip(tone.section_models())
+            .zip(&mut self.string_models)
+        {
+            let model = param.value().resolve(string_model);
+            if force || model != *applied {
+                *applied = model;
+                self.engine.bank.set_model(section, model.model());
+            }
+        }
+        let brass_model = tone.brass_model.value();
+        let brass_mute = tone.brass_mute.value();
+        if force || brass_model != self.brass_model || brass_mute != self.brass_mute {
+            self.brass_model = brass_model;
+            self.brass_mute = brass_mute;
+            for section in Section::ALL.into_iter().filter(|s| s.family() == Family::Brass) {
+                self.engine.bank.set_model(section, brass_model.model());
+                self.engine.bank.set_mute(section, brass_mute.mute());
+            }
+        }
+    }
+
+    // Takes in instruments the background thread finished loading. It waits until the ones replaced last
+    // time are gone, so the audio thread neither builds nor frees any
+    fn swap_instruments(&mut self, context: &mut impl ProcessContext<Self>) {
+        let Ok(mut swap) = self.instruments.try_lock() else {
+            return;
+        };
+        if swap.outgoing.is_some() {
+            return;
+        }
+        let Some(mut instruments) = swap.incoming.take() else {
+            return;
+        };
+        // A load that started before a sample rate change was made for the old rate
+        if instruments.sample_rate().to_bits() == self.sample_rate.load(Ordering::Relaxed) {
+            self.engine.install(&mut instruments);
+            self.apply_models(true);
+            if std::mem::take(&mut swap.new_sound_font) {
+                self.engine.select_patch(9, 128, 0);
+            }
+        }
+        swap.outgoing = Some(instruments);
+        drop(swap);
+        context.execute_background(Task::ReleaseInstruments);
+    }
+
+    fn handle_note_on(&mut self, channel: u8, note: u8, velocity_norm: f32) {
         let velocity = (velocity_norm.clamp(0.0, 1.0) * 127.0) as u8;
-        let (_, layer_gain) = self.midi.detect_layer(velocity);
-        let legato = self.midi.legato_engine.note_on(note as i32, self.global_sample);
+        self.engine.note_on(channel, note, velocity);
+    }
 
-        let articulation = if legato {
-            Articulation::Sustain
-        } else {
-            self.midi.detect_articulation(500.0)
+    fn handle_cc(&mut self, channel: u8, cc: u8, value: f32) {
+        self.engine.handle_cc(channel, cc, value);
+        if let Some(binding) = self.engine.cc_map.take_learned() {
+            self.pending_learn = Some(binding);
+        }
+    }
+
+    fn store_learned_binding(&mut self) {
+        let Some(binding) = self.pending_learn else {
+            return;
         };
+        if let Ok(mut state) = self.params.engine_state.try_write() {
+            state.cc_map.bind(binding.msb, binding.target);
+            self.pending_learn = None;
+        }
+    }
 
-        self.midi.step_round_robin();
-        let rr_detune = (self.midi.round_robin as f32 - 1.5) * 0.03;
-        let humanization = self.midi.humanize() + rr_detune;
-
-        if let Some(voice) = self.voices.iter_mut().find(|v| !v.active) {
-            voice.start(
-                note,
-                velocity,
-                articulation,
-                layer_gain,
-                legato,
-                self.sample_rate,
-                self.global_sample,
-                humanization,
-            );
-        }
-    }
-
-    fn handle_note_off(&mut self, note: u8) {
-        self.midi.legato_engine.note_off(self.global_sample);
-        for voice in &mut self.voices {
-            if voice.active && voice.note == note {
-                let duration_ms = ((self.global_sample - voice.start_sample) as f32 / self.sample_rate) * 1000.0;
-                voice.articulation = self.midi.detect_articulation(duration_ms);
-                voice.note_off(self.sample_rate);
-            }
+    // Program changes, keyswitches and the like end up in the saved state. A block where the host is
+    // reading it just skips the update
+    fn store_engine_state(&mut self) {
+        if let Ok(mut state) = self.params.engine_state.try_write() {
+            state.capture(&self.engine);
         }
     }
+}
 
-    fn handle_cc(&mut self, cc: u8, value: f32) {
-        match cc {
-            1 => self.cc1.set_target(value.clamp(0.0, 1.0), 5.0, self.sample_rate),
-            11 => self.cc11.set_target(value.clamp(0.0, 1.0), 5.0, self.sample_rate),
-            _ => {}
+// Everything the engine state points at on disk. Reloading in the same order gives the patches back the
+// sample sets they point at, files that fail to load are logged and left out
+fn load_instruments(state: &EngineState, sample_rate: f32) -> Instruments {
+    let mut instruments = Instruments::new(sample_rate);
+    if let Some(path) = &state.sound_font {
+        match sf2::load(path) {
+            Ok(font) => instruments.add_sound_font(font),
+            Err(err) => nih_log!("Failed to load SF2 {path:?}: {err:#}"),
         }
     }
+    let response = state.impulse_response.as_ref().and_then(|path| {
+        ImpulseResponse::load(path)
+            .map_err(|err| nih_log!("Failed to load impulse response {path:?}: {err:#}"))
+            .ok()
+    });
+    instruments.set_impulse_response(response);
+    // SFZ instruments go last so they win over SoundFont presets on the same program
+    for instrument in &state.sfz_instruments {
+        match sfz::load(&instrument.path) {
+            Ok(samples) => instruments.add_sample_instrument(instrument.program, samples),
+            Err(err) => nih_log!("Failed to load SFZ {:?}: {err:#}", instrument.path),
+        }
+    }
+    instruments
+}
+
+impl SysExMessage for MtsSysEx {
+    type Buffer = [u8; MTS_MAX_LEN + 2];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        MtsSysEx::from_bytes(buffer)
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        let (bytes, len) = self.to_bytes();
+        let mut buffer = [0; MTS_MAX_LEN + 2];
+        buffer[0] = 0xF0;
+        buffer[1..=len].copy_from_slice(&bytes[..len]);
+        buffer[len + 1] = 0xF7;
+        (buffer, len + 2)
+    }
 }
 
 impl ClapPlugin for SmartOrchestraVST {
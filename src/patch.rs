@@ -0,0 +1,222 @@
+use serde::{Deserialize, Serialize};
+
+use crate::engine::{
+    Articulation, BrassMute, EnvelopeShape, NoiseSettings, Section, SynthModel, VibratoSettings,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ArticulationMap {
+    pub staccato_below_ms: f32,
+    pub sustain_above_ms: f32,
+}
+
+impl Default for ArticulationMap {
+    fn default() -> Self {
+        Self {
+            staccato_below_ms: 120.0,
+            sustain_above_ms: 400.0,
+        }
+    }
+}
+
+impl ArticulationMap {
+    pub fn detect(&self, duration_ms: f32) -> Articulation {
+        if duration_ms < self.staccato_below_ms {
+            Articulation::Staccato
+        } else if duration_ms <= self.sustain_above_ms {
+            Articulation::Marcato
+        } else {
+            Articulation::Sustain
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoteRange {
+    pub low: u8,
+    pub high: u8,
+}
+
+impl NoteRange {
+    pub const fn new(low: u8, high: u8) -> Self {
+        Self { low, high }
+    }
+
+    pub fn contains(&self, note: u8) -> bool {
+        (self.low..=self.high).contains(&note)
+    }
+
+    // Moves the note by whole octaves until it lands in the range, stopping at the edge for ranges
+    // narrower than an octave
+    pub fn fold(&self, note: u8) -> u8 {
+        let mut note = note;
+        while note > self.high && note >= self.low + 12 {
+            note -= 12;
+        }
+        while note < self.low && note + 12 <= self.high {
+            note += 12;
+        }
+        note.clamp(self.low, self.high)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EnvelopeSet {
+    pub staccato: EnvelopeShape,
+    pub marcato: EnvelopeShape,
+    pub sustain: EnvelopeShape,
+    pub legato: EnvelopeShape,
+}
+
+impl Default for EnvelopeSet {
+    fn default() -> Self {
+        Self {
+            staccato: EnvelopeShape::new(2.0, 45.0, 0.35, 45.0),
+            marcato: EnvelopeShape::new(10.0, 90.0, 0.6, 100.0),
+            sustain: EnvelopeShape::new(20.0, 160.0, 0.82, 180.0),
+            legato: EnvelopeShape::new(30.0, 160.0, 0.85, 180.0),
+        }
+    }
+}
+
+impl EnvelopeSet {
+    pub fn shape(&self, articulation: Articulation, legato: bool) -> EnvelopeShape {
+        if legato {
+            return self.legato;
+        }
+        match articulation {
+            Articulation::Staccato => self.staccato,
+            Articulation::Marcato => self.marcato,
+            Articulation::Sustain => self.sustain,
+        }
+    }
+
+    fn scaled(mut self, attack: f32, release: f32) -> Self {
+        for shape in [&mut self.staccato, &mut self.marcato, &mut self.sustain, &mut self.legato] {
+            shape.attack_ms *= attack;
+            shape.release_ms *= release;
+        }
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Patch {
+    pub name: String,
+    pub bank: u16,
+    pub program: u8,
+    pub section: Section,
+    pub model: SynthModel,
+    pub mute: BrassMute,
+    pub sample_set: Option<usize>,
+    // Seated where its section sits on the stage, otherwise front centre
+    pub on_stage: bool,
+    pub range: NoteRange,
+    pub articulation: ArticulationMap,
+    pub envelopes: EnvelopeSet,
+    pub vibrato: VibratoSettings,
+    pub noise: NoiseSettings,
+    pub humanize: f32,
+}
+
+impl Patch {
+    pub fn stage_section(&self) -> Option<Section> {
+        self.on_stage.then_some(self.section)
+    }
+
+    pub fn factory(program: u8, section: Section) -> Self {
+        // Low and brass instruments speak slower and ring longer than the default string shapes
+        let envelopes = match section {
+            Section::Violins1 | Section::Violins2 | Section::Violas => EnvelopeSet::default(),
+            Section::Cellos => EnvelopeSet::default().scaled(1.2, 1.3),
+            Section::Basses => EnvelopeSet::default().scaled(1.6, 1.6),
+            Section::Flutes | Section::Oboes | Section::Clarinets => EnvelopeSet::default().scaled(0.8, 0.7),
+            Section::Bassoons => EnvelopeSet::default().scaled(1.1, 0.9),
+            Section::Horns => EnvelopeSet::default().scaled(1.5, 1.4),
+            Section::Trumpets => EnvelopeSet::default().scaled(0.9, 1.0),
+            Section::Trombones | Section::Tuba => EnvelopeSet::default().scaled(1.4, 1.2),
+        };
+
+        Self {
+            name: section.name().to_string(),
+            bank: 0,
+            program,
+            section,
+            // The string and brass models are opt-in, per section or from a preset
+            model: SynthModel::Subtractive,
+            mute: BrassMute::Open,
+            sample_set: None,
+            on_stage: true,
+            range: section.range(),
+            articulation: ArticulationMap::default(),
+            envelopes,
+            vibrato: section.vibrato(),
+            noise: section.noise(),
+            humanize: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PatchBank {
+    pub patches: Vec<Patch>,
+}
+
+impl Default for PatchBank {
+    fn default() -> Self {
+        Self {
+            patches: Section::ALL
+                .iter()
+                .enumerate()
+                .map(|(program, &section)| Patch::factory(program as u8, section))
+                .collect(),
+        }
+    }
+}
+
+impl PatchBank {
+    pub fn find(&self, bank: u16, program: u8) -> Option<usize> {
+        self.patches.iter().position(|p| p.bank == bank && p.program == program)
+    }
+
+    // Closest patch of the same instrument family that can play the note
+    pub fn neighbour(&self, index: usize, note: u8) -> Option<usize> {
+        let family = self.get(index).section.family();
+        self.patches
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.section.family() == family && p.range.contains(note))
+            .min_by_key(|(i, _)| i.abs_diff(index))
+            .map(|(i, _)| i)
+    }
+
+    // Patches playing a loaded instrument keep their samples
+    pub fn set_model(&mut self, section: Section, model: SynthModel) {
+        for patch in self
+            .patches
+            .iter_mut()
+            .filter(|p| p.section == section && p.sample_set.is_none())
+        {
+            patch.model = model;
+        }
+    }
+
+    pub fn set_mute(&mut self, section: Section, mute: BrassMute) {
+        for patch in self.patches.iter_mut().filter(|p| p.section == section) {
+            patch.mute = mute;
+        }
+    }
+
+    // Replaces the patch on the same bank and program, or adds it
+    pub fn insert(&mut self, patch: Patch) {
+        match self.find(patch.bank, patch.program) {
+            Some(index) => self.patches[index] = patch,
+            None => self.patches.push(patch),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> &Patch {
+        // Indices held by voices may outlive a bank replacement, so fall back to the first patch
+        self.patches.get(index).or(self.patches.first()).expect("patch bank is empty")
+    }
+}
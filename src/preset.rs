@@ -0,0 +1,395 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::engine::{
+    Articulation, BrassMute, Family, OrchestraEngine, RangePolicy, Section, SynthModel,
+    MIDI_CHANNELS,
+};
+use crate::reverb::ReverbSettings;
+use crate::stage::Seating;
+
+// Orchestra setup a piece starts from: engine settings plus the instrument on each MIDI channel.
+// Stored as pretty JSON, every field is optional and falls back to the engine default
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preset {
+    pub name: String,
+    pub section_size: usize,
+    pub vibrato_depth: f32,
+    pub vibrato_rate: f32,
+    pub vibrato_delay: f32,
+    pub smart_intonation: bool,
+    pub range_policy: RangePolicy,
+    // Subtractive or BowedString for the strings, Subtractive or Brass for the brass
+    pub string_model: SynthModel,
+    pub brass_model: SynthModel,
+    pub brass_mute: BrassMute,
+    pub seating: Seating,
+    pub reverb: ReverbSettings,
+    pub channels: Vec<ChannelPreset>,
+}
+
+impl Default for Preset {
+    fn default() -> Self {
+        Self {
+            name: "Init".to_string(),
+            section_size: 1,
+            vibrato_depth: 1.0,
+            vibrato_rate: 1.0,
+            vibrato_delay: 1.0,
+            smart_intonation: false,
+            range_policy: RangePolicy::Flag,
+            string_model: SynthModel::Subtractive,
+            brass_model: SynthModel::Subtractive,
+            brass_mute: BrassMute::Open,
+            seating: Seating::American,
+            reverb: ReverbSettings::default(),
+            channels: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelPreset {
+    // MIDI channel, 1..16
+    pub channel: u8,
+    pub bank: u16,
+    pub program: u8,
+    pub solo: bool,
+    pub legato: bool,
+    // Played as if this keyswitch were held, e.g. staccato for pizzicato strings
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub articulation: Option<Articulation>,
+    // CC7 and CC10 values, 0..1, left alone when missing. The stage already pans each section to its seat
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pan: Option<f32>,
+}
+
+impl Default for ChannelPreset {
+    fn default() -> Self {
+        Self {
+            channel: 1,
+            bank: 0,
+            program: 0,
+            solo: false,
+            legato: true,
+            articulation: None,
+            volume: None,
+            pan: None,
+        }
+    }
+}
+
+impl ChannelPreset {
+    fn section(channel: u8, section: Section) -> Self {
+        Self {
+            channel,
+            program: program(section),
+            ..Self::default()
+        }
+    }
+}
+
+impl Preset {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Falha ao ler preset: {path:?}"))?;
+        serde_json::from_str(&text).with_context(|| format!("Preset inválido: {path:?}"))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, text + "\n")
+            .with_context(|| format!("Falha ao gravar preset: {path:?}"))
+    }
+
+    // Factory preset by name, ignoring case, spaces and dashes
+    pub fn factory(name: &str) -> Option<Self> {
+        let key = |s: &str| {
+            s.chars()
+                .filter(|c| c.is_alphanumeric())
+                .collect::<String>()
+                .to_lowercase()
+        };
+        factory_presets()
+            .into_iter()
+            .find(|preset| key(&preset.name) == key(name))
+    }
+
+    // Current engine setup, for exporting what a session ended up with
+    pub fn capture(name: &str, engine: &OrchestraEngine) -> Self {
+        let defaults = Self::default();
+        let model = |section: Section, fallback: SynthModel| {
+            engine
+                .bank
+                .patches
+                .iter()
+                .find(|p| p.bank == 0 && p.section == section)
+                .map(|p| p.model)
+                .filter(|&model| model != SynthModel::Sampled)
+                .unwrap_or(fallback)
+        };
+        let mute = engine
+            .bank
+            .patches
+            .iter()
+            .find(|p| p.section == Section::Trumpets)
+            .map_or(BrassMute::Open, |p| p.mute);
+
+        Self {
+            name: name.to_string(),
+            section_size: engine.section_size,
+            vibrato_depth: engine.vibrato_depth,
+            vibrato_rate: engine.vibrato_rate,
+            vibrato_delay: engine.vibrato_delay,
+            smart_intonation: engine.smart_intonation,
+            range_policy: engine.range_policy,
+            string_model: model(Section::Violins1, defaults.string_model),
+            brass_model: model(Section::Trumpets, defaults.brass_model),
+            brass_mute: mute,
+            seating: engine.stage.seating,
+            reverb: engine.reverb.settings,
+            channels: changed_channels(&channel_layout(engine)),
+        }
+    }
+
+    pub fn apply(&self, engine: &mut OrchestraEngine) {
+        engine.section_size = self.section_size;
+        engine.vibrato_depth = self.vibrato_depth;
+        engine.vibrato_rate = self.vibrato_rate;
+        engine.vibrato_delay = self.vibrato_delay;
+        engine.smart_intonation = self.smart_intonation;
+        engine.range_policy = self.range_policy;
+        engine.stage.seating = self.seating;
+        engine.reverb.settings = self.reverb;
+        // Sampled patches are only made by loading an instrument, never by a preset
+        for section in Section::ALL {
+            match section.family() {
+                Family::Strings if self.string_model != SynthModel::Sampled => {
+                    engine.bank.set_model(section, self.string_model)
+                }
+                Family::Brass => {
+                    if self.brass_model != SynthModel::Sampled {
+                        engine.bank.set_model(section, self.brass_model);
+                    }
+                    engine.bank.set_mute(section, self.brass_mute);
+                }
+                _ => {}
+            }
+        }
+        apply_channel_layout(engine, &self.channels);
+        engine.update_intonation();
+    }
+}
+
+// Channels of a layout that are not on their defaults, which is all a preset needs to keep
+pub fn changed_channels(layout: &[ChannelPreset]) -> Vec<ChannelPreset> {
+    layout
+        .iter()
+        .filter(|channel| {
+            **channel
+                != ChannelPreset {
+                    channel: channel.channel,
+                    ..ChannelPreset::default()
+                }
+        })
+        .copied()
+        .collect()
+}
+
+// Patch, solo, legato and forced articulation of every MIDI channel, without allocating
+pub fn channel_layout(engine: &OrchestraEngine) -> [ChannelPreset; MIDI_CHANNELS] {
+    std::array::from_fn(|channel| {
+        let state = &engine.channels[channel];
+        let patch = engine.bank.get(state.patch);
+        ChannelPreset {
+            channel: channel as u8 + 1,
+            bank: patch.bank,
+            program: patch.program,
+            solo: state.solo,
+            legato: state.legato_enabled,
+            articulation: state.keyswitch,
+            volume: None,
+            pan: None,
+        }
+    })
+}
+
+pub fn apply_channel_layout(engine: &mut OrchestraEngine, channels: &[ChannelPreset]) {
+    for preset in channels {
+        let channel = preset.channel.clamp(1, MIDI_CHANNELS as u8) - 1;
+        engine.select_patch(channel, preset.bank, preset.program);
+        engine.set_solo(channel, preset.solo);
+        let state = &mut engine.channels[channel as usize];
+        state.legato_enabled = preset.legato;
+        state.keyswitch = preset.articulation;
+        if let Some(volume) = preset.volume {
+            engine.handle_cc(channel, 7, volume);
+        }
+        if let Some(pan) = preset.pan {
+            engine.handle_cc(channel, 10, pan);
+        }
+    }
+}
+
+pub fn factory_presets() -> Vec<Preset> {
+    let strings = || {
+        [
+            Section::Violins1,
+            Section::Violins2,
+            Section::Violas,
+            Section::Cellos,
+            Section::Basses,
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, section)| ChannelPreset::section(i as u8 + 1, section))
+        .collect::<Vec<_>>()
+    };
+
+    vec![
+        Preset {
+            name: "Strings Tutti".to_string(),
+            section_size: 6,
+            channels: strings(),
+            ..Preset::default()
+        },
+        Preset {
+            name: "Pizzicato Strings".to_string(),
+            section_size: 4,
+            vibrato_depth: 0.0,
+            channels: strings()
+                .into_iter()
+                .map(|channel| ChannelPreset {
+                    legato: false,
+                    articulation: Some(Articulation::Staccato),
+                    ..channel
+                })
+                .collect(),
+            ..Preset::default()
+        },
+        Preset {
+            name: "Chamber Winds".to_string(),
+            section_size: 1,
+            // A smaller room keeps the solo lines clear
+            reverb: ReverbSettings {
+                decay_s: 1.4,
+                size: 0.7,
+                ..ReverbSettings::default()
+            },
+            vibrato_depth: 0.8,
+            channels: [
+                Section::Flutes,
+                Section::Oboes,
+                Section::Clarinets,
+                Section::Bassoons,
+                Section::Horns,
+            ]
+            .into_iter()
+            .enumerate()
+            .map(|(i, section)| ChannelPreset {
+                solo: true,
+                ..ChannelPreset::section(i as u8 + 1, section)
+            })
+            .collect(),
+            ..Preset::default()
+        },
+        Preset {
+            name: "Brass Choral".to_string(),
+            section_size: 3,
+            // Chorales are played with an almost straight tone
+            vibrato_depth: 0.3,
+            vibrato_delay: 1.5,
+            smart_intonation: true,
+            brass_model: SynthModel::Brass,
+            reverb: ReverbSettings {
+                decay_s: 3.0,
+                size: 1.3,
+                ..ReverbSettings::default()
+            },
+            channels: vec![
+                ChannelPreset::section(1, Section::Horns),
+                ChannelPreset::section(2, Section::Trumpets),
+                ChannelPreset::section(3, Section::Trombones),
+                ChannelPreset::section(4, Section::Tuba),
+            ],
+            ..Preset::default()
+        },
+        Preset {
+            name: "Solo Violin".to_string(),
+            section_size: 1,
+            vibrato_depth: 1.2,
+            string_model: SynthModel::BowedString,
+            channels: vec![ChannelPreset {
+                solo: true,
+                ..ChannelPreset::section(1, Section::Violins1)
+            }],
+            ..Preset::default()
+        },
+        Preset {
+            name: "Full Orchestra".to_string(),
+            section_size: 4,
+            range_policy: RangePolicy::HandOff,
+            // Antiphonal violins, so the seconds answer the firsts from the other side
+            seating: Seating::European,
+            channels: Section::ALL
+                .into_iter()
+                .enumerate()
+                .map(|(i, section)| ChannelPreset::section(i as u8 + 1, section))
+                .collect(),
+            ..Preset::default()
+        },
+    ]
+}
+
+// Factory program of a section, in the order of the default patch bank
+fn program(section: Section) -> u8 {
+    Section::ALL
+        .iter()
+        .position(|&s| s == section)
+        .unwrap_or_default() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn factory_presets_survive_a_json_round_trip() {
+        let dir = std::env::temp_dir().join(format!("preset-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for preset in factory_presets() {
+            let path = dir.join(format!("{}.json", preset.name));
+            preset.save(&path).unwrap();
+            assert_eq!(Preset::load(&path).unwrap(), preset);
+            assert_eq!(Preset::factory(&preset.name.to_uppercase()), Some(preset));
+        }
+    }
+
+    #[test]
+    fn a_captured_preset_sets_up_the_same_orchestra() {
+        for preset in factory_presets() {
+            let mut engine = OrchestraEngine::new(48_000.0);
+            preset.apply(&mut engine);
+            let captured = Preset::capture(&preset.name, &engine);
+            // Channels left on their defaults are not kept
+            assert_eq!(
+                Preset {
+                    channels: changed_channels(&preset.channels),
+                    ..preset.clone()
+                },
+                captured
+            );
+
+            let mut restored = OrchestraEngine::new(48_000.0);
+            captured.apply(&mut restored);
+            assert_eq!(channel_layout(&restored), channel_layout(&engine));
+            assert_eq!(restored.section_size, preset.section_size);
+            assert_eq!(restored.stage.seating, preset.seating);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use crate::stage::{StagePosition, SPEED_OF_SOUND, STAGE_BUSES, STAGE_FAR_M, STAGE_NEAR_M};
+
+// Shoebox hall with the back wall of the stage at y = 0 and the audience towards +y
+pub const HALL_WIDTH_M: f32 = 26.0;
+pub const HALL_LENGTH_M: f32 = 42.0;
+pub const HALL_HEIGHT_M: f32 = 16.0;
+// Image sources up to this many bounces
+const ORDER: i32 = 2;
+// Share of the pressure a wall gives back on each bounce
+const REFLECTIVITY: f32 = 0.8;
+// The listener sits a little in front of the first desks, at ear height, and the players half a
+// stage width either side of the centre line
+const LISTENER: [f32; 3] = [HALL_WIDTH_M * 0.5, STAGE_FAR_M + 2.0, 1.2];
+const STAGE_HALF_WIDTH_M: f32 = 9.0;
+const PLAYER_HEIGHT_M: f32 = 1.5;
+// Walls and seats take the highs off every reflection
+const WALL_DAMPING_HZ: f32 = 6_000.0;
+
+#[derive(Debug, Clone, Copy)]
+struct Tap {
+    delay: usize,
+    left: f32,
+    right: f32,
+}
+
+// Image-source model of the hall: each stage bus gets the echoes the walls return from its seat, timed
+// against the same front-desk reference as the stage's direct sound
+#[derive(Debug)]
+pub struct EarlyReflections {
+    // Mono sum of each bus, with room for the longest path
+    lines: Vec<Vec<f32>>,
+    write: usize,
+    taps: Vec<Vec<Tap>>,
+    lowpass: [f32; 2],
+    coefficient: f32,
+    sample_rate: f32,
+}
+
+impl EarlyReflections {
+    pub fn new(sample_rate: f32) -> Self {
+        let diagonal =
+            (HALL_WIDTH_M.powi(2) + HALL_LENGTH_M.powi(2) + HALL_HEIGHT_M.powi(2)).sqrt();
+        let length = ((ORDER + 1) as f32 * diagonal / SPEED_OF_SOUND * sample_rate) as usize + 1;
+        let images = image_orders().count();
+        Self {
+            lines: vec![vec![0.0; length]; STAGE_BUSES],
+            write: 0,
+            taps: (0..STAGE_BUSES)
+                .map(|_| Vec::with_capacity(images))
+                .collect(),
+            lowpass: [0.0; 2],
+            coefficient: 1.0 - (-std::f32::consts::TAU * WALL_DAMPING_HZ / sample_rate).exp(),
+            sample_rate,
+        }
+    }
+
+    // Works out the echoes of a seat, without allocating
+    pub fn place(&mut self, bus: usize, position: StagePosition, depth_amount: f32) {
+        let source = [
+            LISTENER[0] + position.azimuth * STAGE_HALF_WIDTH_M,
+            LISTENER[1] - position.distance_m(depth_amount),
+            PLAYER_HEIGHT_M,
+        ];
+        let size = [HALL_WIDTH_M, HALL_LENGTH_M, HALL_HEIGHT_M];
+        let length = self.lines[bus].len();
+        let taps = &mut self.taps[bus];
+        taps.clear();
+        for orders in image_orders() {
+            let mut offset = [0.0; 3];
+            for axis in 0..3 {
+                offset[axis] = image(source[axis], size[axis], orders[axis]) - LISTENER[axis];
+            }
+            let distance = (offset[0].powi(2) + offset[1].powi(2) + offset[2].powi(2)).sqrt();
+            let bounces = orders.iter().map(|n| n.abs()).sum::<i32>();
+            let gain = REFLECTIVITY.powi(bounces) * STAGE_NEAR_M / distance;
+            // Panned by where the image lies to the side of the listener
+            let angle = (0.5 + 0.5 * offset[0] / distance) * std::f32::consts::FRAC_PI_2;
+            let delay = ((distance - STAGE_NEAR_M) / SPEED_OF_SOUND * self.sample_rate) as usize;
+            taps.push(Tap {
+                delay: delay.min(length - 1),
+                left: gain * angle.cos(),
+                right: gain * angle.sin(),
+            });
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for line in &mut self.lines {
+            line.fill(0.0);
+        }
+        self.lowpass = [0.0; 2];
+    }
+
+    // Takes the sum of each bus for one sample and returns the reflections of all of them
+    pub fn next(&mut self, buses: &[[f32; 2]; STAGE_BUSES]) -> [f32; 2] {
+        let mut out = [0.0; 2];
+        for ((line, taps), &[left, right]) in self.lines.iter_mut().zip(&self.taps).zip(buses) {
+            let length = line.len();
+            line[self.write] = (left + right) * 0.5;
+            for tap in taps {
+                let sample = line[(self.write + length - tap.delay) % length];
+                out[0] += sample * tap.left;
+                out[1] += sample * tap.right;
+            }
+        }
+        self.write = (self.write + 1) % self.lines[0].len();
+        for (state, sample) in self.lowpass.iter_mut().zip(out) {
+            *state += (sample - *state) * self.coefficient;
+        }
+        self.lowpass
+    }
+}
+
+// Reflection counts along each axis of every image up to ORDER bounces, the source itself left out
+fn image_orders() -> impl Iterator<Item = [i32; 3]> {
+    (-ORDER..=ORDER).flat_map(|x| {
+        (-ORDER..=ORDER).flat_map(move |y| {
+            (-ORDER..=ORDER)
+                .map(move |z| [x, y, z])
+                .filter(|orders| (1..=ORDER).contains(&orders.iter().map(|n| n.abs()).sum()))
+        })
+    })
+}
+
+// Coordinate of the source mirrored `n` times between walls at 0 and `size`
+fn image(coordinate: f32, size: f32, n: i32) -> f32 {
+    if n % 2 == 0 {
+        n as f32 * size + coordinate
+    } else {
+        (n + 1) as f32 * size - coordinate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Section;
+    use crate::stage::{Seating, Stage};
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    // Echoes of a click from one seat
+    fn echoes(section: Section) -> Vec<[f32; 2]> {
+        let mut reflections = EarlyReflections::new(SAMPLE_RATE);
+        let bus = Stage::bus(Some(section));
+        reflections.place(bus, Seating::American.position(section), 1.0);
+        let mut buses = [[0.0; 2]; STAGE_BUSES];
+        buses[bus] = [1.0; 2];
+        let mut out = vec![reflections.next(&buses)];
+        out.extend((1..20_000).map(|_| reflections.next(&[[0.0; 2]; STAGE_BUSES])));
+        out
+    }
+
+    fn delay_after_direct(section: Section) -> f32 {
+        let distance = Seating::American.position(section).distance_m(1.0);
+        let direct = (distance - STAGE_NEAR_M) / SPEED_OF_SOUND;
+        let first = echoes(section)
+            .iter()
+            .position(|[left, right]| left.abs() + right.abs() > 1e-6)
+            .unwrap();
+        first as f32 / SAMPLE_RATE - direct
+    }
+
+    #[test]
+    fn echoes_come_after_the_direct_sound() {
+        for section in Section::ALL {
+            assert!(delay_after_direct(section) > 0.0, "{section:?}");
+        }
+    }
+
+    #[test]
+    fn back_rows_hear_their_echoes_sooner_and_louder() {
+        assert!(delay_after_direct(Section::Trumpets) < delay_after_direct(Section::Violins1));
+        // Level of the echoes against the direct sound of the same seat
+        let relative = |section: Section| {
+            let distance = Seating::American.position(section).distance_m(1.0);
+            let energy = echoes(section)
+                .iter()
+                .map(|[left, right]| left * left + right * right)
+                .sum::<f32>();
+            energy.sqrt() / (STAGE_NEAR_M / distance)
+        };
+        assert!(relative(Section::Trumpets) > relative(Section::Violins1));
+    }
+
+    #[test]
+    fn echoes_lean_towards_the_side_of_the_seat() {
+        let balance = |section| {
+            let [left, right] = echoes(section)
+                .iter()
+                .fold([0.0; 2], |[l, r], [left, right]| {
+                    [l + left * left, r + right * right]
+                });
+            left - right
+        };
+        assert!(balance(Section::Violins1) > 0.0);
+        assert!(balance(Section::Basses) < 0.0);
+    }
+
+    #[test]
+    fn reset_silences_the_echoes_on_their_way() {
+        let mut reflections = EarlyReflections::new(SAMPLE_RATE);
+        reflections.place(0, Seating::American.position(Section::Violins1), 1.0);
+        let mut buses = [[0.0; 2]; STAGE_BUSES];
+        buses[0] = [1.0; 2];
+        reflections.next(&buses);
+        reflections.reset();
+        let silent = [[0.0; 2]; STAGE_BUSES];
+        assert!((0..20_000).all(|_| reflections.next(&silent) == [0.0; 2]));
+    }
+}
@@ -0,0 +1,286 @@
+use serde::{Deserialize, Serialize};
+
+// Feedback delay network of eight lines, mixed by a Hadamard matrix so every line feeds every other
+const LINES: usize = 8;
+// Line lengths at size 1, mutually prime-ish so the echoes never line up
+const LINE_MS: [f32; LINES] = [29.7, 37.1, 41.1, 43.7, 53.0, 59.3, 67.1, 73.3];
+// Allpasses smearing the input before it enters the network, per channel
+const DIFFUSER_MS: [[f32; 2]; 2] = [[4.7, 3.6], [5.3, 2.9]];
+const DIFFUSION: f32 = 0.7;
+pub const MAX_PRE_DELAY_MS: f32 = 250.0;
+pub const MIN_SIZE: f32 = 0.4;
+pub const MAX_SIZE: f32 = 1.6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReverbSettings {
+    pub pre_delay_ms: f32,
+    // Time the tail takes to fall by 60 dB at low frequencies, in seconds
+    pub decay_s: f32,
+    // 0 keeps the highs as long as the lows, 1 makes them die several times faster
+    pub damping: f32,
+    // Scales the room, MIN_SIZE..MAX_SIZE
+    pub size: f32,
+    // Dry/wet balance: the dry signal stays at full level up to 0.5 and the tail from there on
+    pub mix: f32,
+    // Level of the early reflections of the stage, which also feed the tail
+    pub early_level: f32,
+}
+
+impl Default for ReverbSettings {
+    fn default() -> Self {
+        Self {
+            pre_delay_ms: 20.0,
+            decay_s: 2.2,
+            damping: 0.5,
+            size: 1.0,
+            mix: 0.35,
+            early_level: 0.6,
+        }
+    }
+}
+
+impl ReverbSettings {
+    // What the delay network is built from. Mix and early level can move every sample without a rebuild
+    fn network(&self) -> [f32; 4] {
+        [self.pre_delay_ms, self.decay_s, self.damping, self.size]
+    }
+
+    pub fn dry_wet(&self) -> (f32, f32) {
+        let mix = self.mix.clamp(0.0, 1.0);
+        ((2.0 - 2.0 * mix).min(1.0), (2.0 * mix).min(1.0))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DelayLine {
+    buffer: Vec<f32>,
+    write: usize,
+    length: usize,
+}
+
+impl DelayLine {
+    fn new(capacity: usize) -> Self {
+        Self {
+            buffer: vec![0.0; capacity.max(2)],
+            write: 0,
+            length: 1,
+        }
+    }
+
+    fn set_length(&mut self, length: usize) {
+        self.length = length.clamp(1, self.buffer.len() - 1);
+    }
+
+    fn read(&self) -> f32 {
+        let capacity = self.buffer.len();
+        self.buffer[(self.write + capacity - self.length) % capacity]
+    }
+
+    fn write(&mut self, value: f32) {
+        self.buffer[self.write] = value;
+        self.write = (self.write + 1) % self.buffer.len();
+    }
+
+    fn clear(&mut self) {
+        self.buffer.fill(0.0);
+    }
+}
+
+// Schroeder allpass, flat in magnitude so it only spreads the input in time
+#[derive(Debug, Clone)]
+struct Allpass {
+    delay: DelayLine,
+}
+
+impl Allpass {
+    fn next(&mut self, input: f32) -> f32 {
+        let delayed = self.delay.read();
+        let fed = input + delayed * DIFFUSION;
+        self.delay.write(fed);
+        delayed - fed * DIFFUSION
+    }
+}
+
+#[derive(Debug)]
+pub struct Reverb {
+    pub settings: ReverbSettings,
+    pre_delay: [DelayLine; 2],
+    diffusers: [[Allpass; 2]; 2],
+    lines: [DelayLine; LINES],
+    // Per line: broadband gain for the decay time, and the lowpass that shortens the highs
+    gains: [f32; LINES],
+    damping: f32,
+    lowpass: [f32; LINES],
+    sample_rate: f32,
+    applied: Option<[f32; 4]>,
+}
+
+impl Reverb {
+    pub fn new(sample_rate: f32) -> Self {
+        let samples = |ms: f32| (ms / 1000.0 * sample_rate) as usize + 2;
+        Self {
+            settings: ReverbSettings::default(),
+            pre_delay: std::array::from_fn(|_| DelayLine::new(samples(MAX_PRE_DELAY_MS))),
+            diffusers: std::array::from_fn(|channel| {
+                std::array::from_fn(|stage| {
+                    let mut delay = DelayLine::new(samples(DIFFUSER_MS[channel][stage]));
+                    delay.set_length(samples(DIFFUSER_MS[channel][stage]) - 2);
+                    Allpass { delay }
+                })
+            }),
+            lines: std::array::from_fn(|line| DelayLine::new(samples(LINE_MS[line] * MAX_SIZE))),
+            gains: [0.0; LINES],
+            damping: 0.0,
+            lowpass: [0.0; LINES],
+            sample_rate,
+            applied: None,
+        }
+    }
+
+    // Rebuilds the delay lines, outside the audio thread
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        *self = Self {
+            settings: self.settings,
+            ..Self::new(sample_rate)
+        };
+    }
+
+    pub fn reset(&mut self) {
+        for line in self.pre_delay.iter_mut().chain(&mut self.lines) {
+            line.clear();
+        }
+        for allpass in self.diffusers.iter_mut().flatten() {
+            allpass.delay.clear();
+        }
+        self.lowpass = [0.0; LINES];
+    }
+
+    // The tail for one stereo sample of the send, without the dry signal
+    pub fn process(&mut self, input: [f32; 2]) -> [f32; 2] {
+        if self.applied != Some(self.settings.network()) {
+            self.apply();
+        }
+
+        let mut diffused = [0.0; 2];
+        for (channel, sample) in input.into_iter().enumerate() {
+            let pre_delay = &mut self.pre_delay[channel];
+            pre_delay.write(sample);
+            let mut value = pre_delay.read();
+            for allpass in &mut self.diffusers[channel] {
+                value = allpass.next(value);
+            }
+            diffused[channel] = value;
+        }
+
+        let mut outputs = [0.0; LINES];
+        for (line, output) in outputs.iter_mut().enumerate() {
+            // The lowpass runs on what leaves the line, so its loss compounds once per trip round the loop
+            let lowpass = &mut self.lowpass[line];
+            *lowpass += (self.lines[line].read() - *lowpass) * (1.0 - self.damping);
+            *output = *lowpass * self.gains[line];
+        }
+
+        let mut feedback = outputs;
+        hadamard(&mut feedback);
+        for (line, value) in feedback.into_iter().enumerate() {
+            // Left feeds the even lines and right the odd ones, so the tail keeps some of the image
+            self.lines[line].write(value + diffused[line % 2]);
+        }
+
+        // Two orthogonal sign patterns over the lines decorrelate the outputs
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (line, value) in outputs.into_iter().enumerate() {
+            left += value;
+            right += if line % 4 < 2 { value } else { -value };
+        }
+        let scale = 1.0 / (LINES as f32).sqrt();
+        [left * scale, right * scale]
+    }
+
+    fn apply(&mut self) {
+        let settings = self.settings;
+        self.applied = Some(settings.network());
+        let samples = |ms: f32| (ms / 1000.0 * self.sample_rate) as usize;
+
+        for line in &mut self.pre_delay {
+            line.set_length(samples(settings.pre_delay_ms.clamp(0.0, MAX_PRE_DELAY_MS)));
+        }
+        let size = settings.size.clamp(MIN_SIZE, MAX_SIZE);
+        let decay_s = settings.decay_s.max(0.05);
+        for (line, (delay, gain)) in self.lines.iter_mut().zip(&mut self.gains).enumerate() {
+            let length = samples(LINE_MS[line] * size);
+            delay.set_length(length);
+            // -60 dB after `decay_s`, spread over the trips a sample makes through this line
+            *gain = 10.0_f32.powf(-3.0 * length as f32 / (decay_s * self.sample_rate));
+        }
+        self.damping = settings.damping.clamp(0.0, 1.0) * 0.7;
+    }
+}
+
+// In-place fast Walsh-Hadamard transform, normalized so it is unitary and the loop stays lossless
+fn hadamard(values: &mut [f32; LINES]) {
+    let mut span = 1;
+    while span < LINES {
+        for start in (0..LINES).step_by(span * 2) {
+            for i in start..start + span {
+                let (a, b) = (values[i], values[i + span]);
+                values[i] = a + b;
+                values[i + span] = a - b;
+            }
+        }
+        span *= 2;
+    }
+    let scale = 1.0 / (LINES as f32).sqrt();
+    for value in values.iter_mut() {
+        *value *= scale;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Time the backward-integrated energy of the impulse response takes to fall by 60 dB
+    fn measured_rt60(settings: ReverbSettings, sample_rate: f32) -> f32 {
+        let mut reverb = Reverb::new(sample_rate);
+        reverb.settings = settings;
+        let length = (settings.decay_s * 2.5 * sample_rate) as usize;
+        let energy: Vec<f32> = (0..length)
+            .map(|n| {
+                let input = if n == 0 { 1.0 } else { 0.0 };
+                let [left, right] = reverb.process([input, input]);
+                left * left + right * right
+            })
+            .collect();
+        let total: f64 = energy.iter().map(|&e| e as f64).sum();
+        let mut remaining = total;
+        for (n, &e) in energy.iter().enumerate() {
+            if remaining < total * 1e-6 {
+                return n as f32 / sample_rate;
+            }
+            remaining -= e as f64;
+        }
+        panic!("the tail never fell by 60 dB");
+    }
+
+    #[test]
+    fn impulse_decays_by_60_db_in_the_decay_time() {
+        for (decay_s, size) in [(1.0, 0.6), (2.2, 1.0), (3.5, 1.5)] {
+            let settings = ReverbSettings {
+                pre_delay_ms: 0.0,
+                decay_s,
+                // Damping shortens the highs on purpose, the decay time is set for the lows
+                damping: 0.0,
+                size,
+                ..ReverbSettings::default()
+            };
+            let rt60 = measured_rt60(settings, 48_000.0);
+            assert!(
+                (rt60 / decay_s - 1.0).abs() < 0.1,
+                "decay {decay_s}s at size {size}: measured {rt60}s"
+            );
+        }
+    }
+}
@@ -0,0 +1,429 @@
+use anyhow::{bail, Context, Result};
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::engine::{midi_note_to_hz, Articulation, DynamicLayer, EnvelopeShape};
+use crate::patch::NoteRange;
+use crate::streaming::{stream_start, StreamRing, StreamSource, STREAM_HEAD_FRAMES};
+
+// Recording as stereo frames, mono files are duplicated to both sides. Streamed recordings only hold
+// their head here and read the rest from disk while playing
+#[derive(Debug)]
+pub struct SampleData {
+    pub frames: Vec<[f32; 2]>,
+    pub sample_rate: f32,
+    pub stream: Option<StreamSource>,
+}
+
+impl SampleData {
+    pub fn from_wav(path: &Path) -> Result<Self> {
+        let mut reader = open_wav(path)?;
+        let mut frames = Vec::new();
+        read_frames(&mut reader, usize::MAX, &mut frames)?;
+        Ok(Self {
+            frames,
+            sample_rate: reader.spec().sample_rate as f32,
+            stream: None,
+        })
+    }
+
+    // Loads short files whole and only the head of long ones, which are then streamed
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut reader = open_wav(path)?;
+        let length = reader.duration() as usize;
+        if length <= STREAM_HEAD_FRAMES * 2 {
+            return Self::from_wav(path);
+        }
+
+        let mut frames = Vec::with_capacity(STREAM_HEAD_FRAMES);
+        read_frames(&mut reader, STREAM_HEAD_FRAMES, &mut frames)?;
+        Ok(Self {
+            frames,
+            sample_rate: reader.spec().sample_rate as f32,
+            stream: Some(StreamSource {
+                path: path.to_path_buf(),
+                frames: length,
+            }),
+        })
+    }
+
+    // Length of the whole recording, including the part still on disk
+    pub fn len(&self) -> usize {
+        self.stream
+            .as_ref()
+            .map_or(self.frames.len(), |source| source.frames)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub fn open_wav(path: &Path) -> Result<hound::WavReader<std::io::BufReader<std::fs::File>>> {
+    let reader =
+        hound::WavReader::open(path).with_context(|| format!("Falha ao abrir WAV: {path:?}"))?;
+    if reader.spec().channels == 0 {
+        bail!("WAV sem canais: {path:?}");
+    }
+    Ok(reader)
+}
+
+// Appends up to `count` frames from the reader's current position
+pub fn read_frames<R: Read>(
+    reader: &mut hound::WavReader<R>,
+    count: usize,
+    frames: &mut Vec<[f32; 2]>,
+) -> Result<()> {
+    let channels = reader.spec().channels as usize;
+    let samples = read_samples(reader, count)?;
+    // Anything past the first two channels is dropped
+    frames.extend(
+        samples
+            .chunks_exact(channels)
+            .map(|frame| [frame[0], frame[channels.min(2) - 1]]),
+    );
+    Ok(())
+}
+
+// Up to `count` frames with all their channels, interleaved as in the file and scaled to -1..1
+pub fn read_samples<R: Read>(reader: &mut hound::WavReader<R>, count: usize) -> Result<Vec<f32>> {
+    let spec = reader.spec();
+    let channels = spec.channels as usize;
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .samples::<f32>()
+            .take(count.saturating_mul(channels))
+            .collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            if !(1..=32).contains(&spec.bits_per_sample) {
+                bail!(
+                    "WAV com {} bits por amostra não suportado",
+                    spec.bits_per_sample
+                );
+            }
+            let scale = 1.0 / (1u32 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .take(count.saturating_mul(channels))
+                .map(|s| s.map(|s| s as f32 * scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    Ok(samples)
+}
+
+#[derive(Debug, Clone)]
+pub struct SampleZone {
+    pub data: Arc<SampleData>,
+    pub root_key: u8,
+    pub keys: NoteRange,
+    pub velocity_low: u8,
+    pub velocity_high: u8,
+    // Dynamic layer of the velocity window, only used for velocities no window covers
+    pub layer: Option<DynamicLayer>,
+    // Zones without an articulation answer to all of them
+    pub articulation: Option<Articulation>,
+    // First and last frame of the loop, inclusive
+    pub loop_points: Option<(usize, usize)>,
+    // Position in the round-robin cycle, zones without one play on every pass
+    pub round_robin: Option<usize>,
+    pub tune_cents: f32,
+    pub gain: f32,
+    // Replaces the patch envelope for this recording
+    pub envelope: Option<EnvelopeShape>,
+}
+
+impl SampleZone {
+    pub fn new(data: Arc<SampleData>, root_key: u8) -> Self {
+        Self {
+            data,
+            root_key,
+            keys: NoteRange::new(0, 127),
+            velocity_low: 0,
+            velocity_high: 127,
+            layer: None,
+            articulation: None,
+            loop_points: None,
+            round_robin: None,
+            tune_cents: 0.0,
+            gain: 1.0,
+            envelope: None,
+        }
+    }
+
+    // Sets the velocity window as the file gives it, along with the layer of its middle
+    pub fn set_velocity_window(&mut self, low: u8, high: u8) {
+        self.velocity_low = low;
+        self.velocity_high = high;
+        self.layer = ((low, high) != (0, 127))
+            .then(|| DynamicLayer::from_velocity(((low as u16 + high as u16) / 2) as u8).0);
+    }
+
+    fn matches(&self, note: u8, articulation: Articulation) -> bool {
+        self.keys.contains(note) && self.articulation.is_none_or(|a| a == articulation)
+    }
+
+    fn covers(&self, velocity: u8) -> bool {
+        (self.velocity_low..=self.velocity_high).contains(&velocity)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SampleSet {
+    pub name: String,
+    pub zones: Vec<SampleZone>,
+    // Keys that select an articulation instead of sounding, lowest first
+    pub keyswitches: Vec<(u8, Articulation)>,
+}
+
+impl SampleSet {
+    pub fn keyswitch(&self, key: u8) -> Option<Articulation> {
+        self.keyswitches
+            .iter()
+            .find(|&&(k, _)| k == key)
+            .map(|&(_, articulation)| articulation)
+    }
+
+    // Lowest to highest key any zone answers to
+    pub fn key_range(&self) -> NoteRange {
+        let low = self.zones.iter().map(|z| z.keys.low).min().unwrap_or(0);
+        let high = self.zones.iter().map(|z| z.keys.high).max().unwrap_or(127);
+        NoteRange::new(low, high)
+    }
+
+    pub fn select(
+        &self,
+        note: u8,
+        velocity: u8,
+        layer: DynamicLayer,
+        articulation: Articulation,
+        round_robin: usize,
+    ) -> Option<usize> {
+        let playable = || {
+            self.zones
+                .iter()
+                .enumerate()
+                .filter(move |(_, z)| z.matches(note, articulation))
+        };
+        // A velocity that falls between the windows recorded for this note plays the zones of the nearest
+        // dynamic layer, so a set with gaps still sounds at every velocity
+        let distance = |zone: &SampleZone| zone.layer.map(|l| (l as i32 - layer as i32).abs());
+        let nearest = match playable().any(|(_, z)| z.covers(velocity)) {
+            true => None,
+            false => playable().filter_map(|(_, z)| distance(z)).min(),
+        };
+        let candidates = || {
+            playable().filter(move |(_, z)| match nearest {
+                Some(nearest) => distance(z) == Some(nearest),
+                None => z.covers(velocity),
+            })
+        };
+
+        // The cycle length comes from the zones that can play this note and `round_robin` counts up without
+        // a bound, so groups of any size rotate evenly
+        let cycle = candidates()
+            .filter_map(|(_, z)| z.round_robin)
+            .max()
+            .map_or(1, |last| last + 1);
+        let pass = round_robin % cycle;
+        candidates()
+            .find(|(_, z)| z.round_robin.is_none_or(|rr| rr == pass))
+            .map(|(index, _)| index)
+    }
+}
+
+// Playback position of one voice, the zone itself is looked up by index when rendering
+#[derive(Debug, Clone, Copy)]
+pub struct SamplePlayer {
+    zone: Option<(usize, usize)>,
+    position: f64,
+}
+
+impl SamplePlayer {
+    pub fn new() -> Self {
+        Self {
+            zone: None,
+            position: 0.0,
+        }
+    }
+
+    // `zone` is the sample set index and the zone index inside it
+    pub fn start(&mut self, zone: Option<(usize, usize)>) {
+        self.zone = zone;
+        self.position = 0.0;
+    }
+
+    pub fn zone(&self) -> Option<(usize, usize)> {
+        self.zone
+    }
+
+    // `stream` is the voice's ring, which only streamed recordings read from
+    pub fn next(
+        &mut self,
+        zone: &SampleZone,
+        stream: Option<&StreamRing>,
+        freq_hz: f32,
+        sample_rate: f32,
+    ) -> (f32, f32) {
+        // The sounding frequency already carries the tuning, so the root key is taken in equal temperament
+        let root_hz = midi_note_to_hz(zone.root_key as f32 - zone.tune_cents / 100.0);
+        let step = (freq_hz / root_hz * zone.data.sample_rate / sample_rate) as f64;
+        match stream.filter(|_| zone.data.stream.is_some()) {
+            Some(ring) => self.next_streamed(zone, ring, step),
+            None => self.next_in_memory(zone, step),
+        }
+    }
+
+    fn next_in_memory(&mut self, zone: &SampleZone, step: f64) -> (f32, f32) {
+        let frames = &zone.data.frames;
+        let index = self.position as usize;
+        if index >= frames.len() {
+            return (0.0, 0.0);
+        }
+
+        let loop_points = zone
+            .loop_points
+            .filter(|&(start, end)| start < end && end < frames.len());
+        let next = match loop_points {
+            Some((start, end)) if index == end => start,
+            _ => (index + 1).min(frames.len() - 1),
+        };
+        let frac = (self.position - index as f64) as f32;
+        let [al, ar] = frames[index];
+        let [bl, br] = frames[next];
+        let left = (al + (bl - al) * frac) * zone.gain;
+        let right = (ar + (br - ar) * frac) * zone.gain;
+
+        self.position += step;
+        if let Some((start, end)) = loop_points {
+            while self.position >= (end + 1) as f64 {
+                self.position -= (end + 1 - start) as f64;
+            }
+        }
+
+        (left, right)
+    }
+
+    // The position keeps growing through loop repeats: the head comes from memory and everything after
+    // it arrives in order through the ring, which the reader fills with the loop already unrolled
+    fn next_streamed(&mut self, zone: &SampleZone, ring: &StreamRing, step: f64) -> (f32, f32) {
+        let data = &zone.data;
+        let index = self.position as usize;
+        let loop_points = zone
+            .loop_points
+            .filter(|&(start, end)| start < end && end < data.len());
+        if loop_points.is_none() && index >= data.len() {
+            return (0.0, 0.0);
+        }
+
+        let start = stream_start(data, loop_points);
+        let frame = |i: usize| match i < start {
+            true => Some(data.frames[i]),
+            false => ring.get(i - start),
+        };
+        let next = match loop_points {
+            Some(_) => index + 1,
+            None => (index + 1).min(data.len() - 1),
+        };
+        let frames = (frame(index), frame(next));
+        let frac = (self.position - index as f64) as f32;
+
+        self.position += step;
+        let played = self.position as usize;
+        if played > start {
+            ring.release(played - start);
+        }
+
+        // A late disk plays silence but keeps time, so the voice does not drift from the others
+        let (Some([al, ar]), Some([bl, br])) = frames else {
+            ring.count_underrun();
+            return (0.0, 0.0);
+        };
+        let left = (al + (bl - al) * frac) * zone.gain;
+        let right = (ar + (br - ar) * frac) * zone.gain;
+        (left, right)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::MidiProcessor;
+
+    fn round_robin_set(positions: usize) -> SampleSet {
+        let data = Arc::new(SampleData {
+            frames: vec![[0.0; 2]; 16],
+            sample_rate: 48_000.0,
+            stream: None,
+        });
+        SampleSet {
+            name: "rr".to_string(),
+            zones: (0..positions)
+                .map(|position| SampleZone {
+                    round_robin: Some(position),
+                    ..SampleZone::new(data.clone(), 60)
+                })
+                .collect(),
+            keyswitches: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn round_robin_cycles_evenly_through_any_group_size() {
+        let mut midi = MidiProcessor::new();
+        for positions in [2, 3, 5, 8] {
+            let set = round_robin_set(positions);
+            let played: Vec<usize> = (0..positions * 3)
+                .map(|_| {
+                    let pass = midi.step_round_robin(60 + positions as u8);
+                    set.select(60, 100, DynamicLayer::F, Articulation::Sustain, pass)
+                        .unwrap()
+                })
+                .collect();
+            let expected: Vec<usize> = (0..positions * 3).map(|n| n % positions).collect();
+            assert_eq!(played, expected, "{positions} zones");
+        }
+    }
+
+    // A mono PCM file with the given sample size, built by hand as hound will not write odd ones
+    fn wav_bytes(bits: u16, frames: u32) -> Vec<u8> {
+        let bytes_per_sample = bits.div_ceil(8).max(1);
+        let data_len = frames * bytes_per_sample as u32;
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend((36 + data_len).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(48_000u32.to_le_bytes());
+        wav.extend((48_000 * bytes_per_sample as u32).to_le_bytes());
+        wav.extend(bytes_per_sample.to_le_bytes());
+        wav.extend(bits.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend(data_len.to_le_bytes());
+        wav.resize(wav.len() + data_len as usize, 0x40);
+        wav
+    }
+
+    #[test]
+    fn unsupported_bit_depths_are_errors() {
+        for bits in [8, 16, 24, 32] {
+            let mut reader =
+                hound::WavReader::new(std::io::Cursor::new(wav_bytes(bits, 4))).unwrap();
+            let mut frames = Vec::new();
+            read_frames(&mut reader, 4, &mut frames).unwrap();
+            assert_eq!(frames.len(), 4, "{bits} bits");
+        }
+        // hound already refuses 0 and sizes that are not whole bytes, but opens wider integer files
+        for bits in [40, 48, 64] {
+            let mut reader =
+                hound::WavReader::new(std::io::Cursor::new(wav_bytes(bits, 4))).unwrap();
+            assert!(
+                read_frames(&mut reader, 4, &mut Vec::new()).is_err(),
+                "{bits} bits"
+            );
+        }
+    }
+}
@@ -0,0 +1,697 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::engine::EnvelopeShape;
+use crate::patch::NoteRange;
+use crate::sampler::{SampleData, SampleSet, SampleZone};
+
+// Generator operators used by the engine, numbered as in the SoundFont 2.04 specification
+const START_OFFSET: usize = 0;
+const END_OFFSET: usize = 1;
+const LOOP_START_OFFSET: usize = 2;
+const LOOP_END_OFFSET: usize = 3;
+const START_COARSE_OFFSET: usize = 4;
+const END_COARSE_OFFSET: usize = 12;
+const DELAY_VOL_ENV: usize = 33;
+const ATTACK_VOL_ENV: usize = 34;
+const HOLD_VOL_ENV: usize = 35;
+const DECAY_VOL_ENV: usize = 36;
+const SUSTAIN_VOL_ENV: usize = 37;
+const RELEASE_VOL_ENV: usize = 38;
+const INSTRUMENT: usize = 41;
+const KEY_RANGE: usize = 43;
+const VEL_RANGE: usize = 44;
+const LOOP_START_COARSE_OFFSET: usize = 45;
+const INITIAL_ATTENUATION: usize = 48;
+const LOOP_END_COARSE_OFFSET: usize = 50;
+const COARSE_TUNE: usize = 51;
+const FINE_TUNE: usize = 52;
+const SAMPLE_ID: usize = 53;
+const SAMPLE_MODES: usize = 54;
+const OVERRIDING_ROOT_KEY: usize = 58;
+const GENERATOR_COUNT: usize = 61;
+
+const SAMPLE_RIGHT: u16 = 2;
+const SAMPLE_LEFT: u16 = 4;
+const SAMPLE_ROM: u16 = 0x8000;
+
+type Generators = [Option<u16>; GENERATOR_COUNT];
+
+#[derive(Debug)]
+pub struct SoundFont {
+    pub presets: Vec<SoundFontPreset>,
+}
+
+#[derive(Debug)]
+pub struct SoundFontPreset {
+    pub bank: u16,
+    pub program: u8,
+    pub samples: SampleSet,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SampleHeader {
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    sample_rate: u32,
+    original_pitch: u8,
+    pitch_correction: i8,
+    link: u16,
+    kind: u16,
+}
+
+// The hydra: preset, instrument and sample tables of the pdta list
+struct Hydra<'a> {
+    phdr: &'a [u8],
+    pbag: &'a [u8],
+    pgen: &'a [u8],
+    inst: &'a [u8],
+    ibag: &'a [u8],
+    igen: &'a [u8],
+    shdr: &'a [u8],
+}
+
+pub fn load(path: &Path) -> Result<SoundFont> {
+    let bytes = std::fs::read(path).with_context(|| format!("Falha ao ler SF2: {path:?}"))?;
+    parse(&bytes)
+}
+
+pub fn parse(bytes: &[u8]) -> Result<SoundFont> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"sfbk" {
+        bail!("Arquivo não é um SoundFont 2");
+    }
+
+    let mut smpl: &[u8] = &[];
+    let mut pdta = HashMap::new();
+    for (id, body) in chunks(&bytes[12..])? {
+        if id != b"LIST" || body.len() < 4 {
+            continue;
+        }
+        for (sub_id, sub_body) in chunks(&body[4..])? {
+            match (&body[0..4], sub_id) {
+                (b"sdta", b"smpl") => smpl = sub_body,
+                (b"pdta", _) => {
+                    pdta.insert(*sub_id, sub_body);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let table = |id: &[u8; 4]| {
+        pdta.get(id)
+            .copied()
+            .with_context(|| format!("SF2 sem a tabela {}", String::from_utf8_lossy(id)))
+    };
+    let hydra = Hydra {
+        phdr: table(b"phdr")?,
+        pbag: table(b"pbag")?,
+        pgen: table(b"pgen")?,
+        inst: table(b"inst")?,
+        ibag: table(b"ibag")?,
+        igen: table(b"igen")?,
+        shdr: table(b"shdr")?,
+    };
+
+    let headers: Vec<SampleHeader> = hydra.shdr.chunks_exact(46).map(sample_header).collect();
+    let mut cache: HashMap<(u32, u32, Option<u16>), Arc<SampleData>> = HashMap::new();
+    let mut presets = Vec::new();
+
+    // The last record of each header table only terminates the previous one
+    let preset_count = (hydra.phdr.len() / 38).saturating_sub(1);
+    for index in 0..preset_count {
+        let record = &hydra.phdr[index * 38..(index + 2) * 38];
+        let name = record_name(&record[..20]);
+        let program = u16_at(record, 20);
+        let bank = u16_at(record, 22);
+        let bags = u16_at(record, 24) as usize..u16_at(record, 38 + 24) as usize;
+
+        let mut samples = SampleSet {
+            name,
+            ..SampleSet::default()
+        };
+        for preset_zone in zones(hydra.pbag, hydra.pgen, bags, INSTRUMENT)? {
+            let Some(instrument) = preset_zone[INSTRUMENT] else {
+                continue;
+            };
+            instrument_zones(
+                &hydra,
+                instrument as usize,
+                &preset_zone,
+                &headers,
+                smpl,
+                &mut cache,
+                &mut samples,
+            )?;
+        }
+
+        if !samples.zones.is_empty() && program < 128 {
+            presets.push(SoundFontPreset {
+                bank,
+                program: program as u8,
+                samples,
+            });
+        }
+    }
+
+    if presets.is_empty() {
+        bail!("SF2 sem presets tocáveis");
+    }
+    Ok(SoundFont { presets })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn instrument_zones(
+    hydra: &Hydra,
+    instrument: usize,
+    preset_zone: &Generators,
+    headers: &[SampleHeader],
+    smpl: &[u8],
+    cache: &mut HashMap<(u32, u32, Option<u16>), Arc<SampleData>>,
+    samples: &mut SampleSet,
+) -> Result<()> {
+    let Some(record) = hydra.inst.get(instrument * 22..(instrument + 2) * 22) else {
+        bail!("SF2 aponta para instrumento inexistente: {instrument}");
+    };
+    let bags = u16_at(record, 20) as usize..u16_at(record, 22 + 20) as usize;
+    let zones = zones(hydra.ibag, hydra.igen, bags, SAMPLE_ID)?;
+    let used: Vec<u16> = zones.iter().filter_map(|z| z[SAMPLE_ID]).collect();
+
+    for zone in &zones {
+        let Some(sample_id) = zone[SAMPLE_ID] else {
+            continue;
+        };
+        let Some(&header) = headers.get(sample_id as usize) else {
+            bail!("SF2 aponta para sample inexistente: {sample_id}");
+        };
+        if header.kind & SAMPLE_ROM != 0 {
+            continue;
+        }
+        // Stereo pairs are played from the left zone, which also reads its linked right sample
+        if header.kind & SAMPLE_RIGHT != 0 && used.contains(&header.link) {
+            continue;
+        }
+        let right = (header.kind & SAMPLE_LEFT != 0)
+            .then(|| headers.get(header.link as usize).copied())
+            .flatten();
+
+        let keys = intersect(range(zone, KEY_RANGE), range(preset_zone, KEY_RANGE));
+        let velocities = intersect(range(zone, VEL_RANGE), range(preset_zone, VEL_RANGE));
+        let (Some(keys), Some(velocities)) = (keys, velocities) else {
+            continue;
+        };
+
+        // Preset generators are offsets added to the instrument's values
+        let value = |generator: usize, default: i16| {
+            signed(zone, generator).unwrap_or(default) as i32
+                + signed(preset_zone, generator).unwrap_or(0) as i32
+        };
+        let offset = |fine: usize, coarse: usize| {
+            signed(zone, fine).unwrap_or(0) as i64
+                + signed(zone, coarse).unwrap_or(0) as i64 * 32_768
+        };
+
+        let start = (header.start as i64 + offset(START_OFFSET, START_COARSE_OFFSET)).max(0) as u32;
+        let end = (header.end as i64 + offset(END_OFFSET, END_COARSE_OFFSET)).max(0) as u32;
+        if end <= start {
+            continue;
+        }
+        let data = match cache.get(&(start, end, right.map(|_| header.link))) {
+            Some(data) => data.clone(),
+            None => {
+                let data = Arc::new(sample_data(smpl, start, end, right, header.sample_rate)?);
+                cache.insert((start, end, right.map(|_| header.link)), data.clone());
+                data
+            }
+        };
+
+        let root = match signed(zone, OVERRIDING_ROOT_KEY) {
+            Some(key @ 0..=127) => key as u8,
+            _ if header.original_pitch < 128 => header.original_pitch,
+            _ => 60,
+        };
+        let mut sample_zone = SampleZone::new(data, root);
+        sample_zone.keys = keys;
+        sample_zone.set_velocity_window(velocities.low, velocities.high);
+        sample_zone.tune_cents = (value(COARSE_TUNE, 0) * 100
+            + value(FINE_TUNE, 0)
+            + header.pitch_correction as i32) as f32;
+        sample_zone.gain = 10f32.powf(-(value(INITIAL_ATTENUATION, 0).max(0) as f32) / 200.0);
+
+        // Modes 1 and 3 loop, the latter also plays the tail after release, which the engine does not model
+        if matches!(signed(zone, SAMPLE_MODES).unwrap_or(0) & 3, 1 | 3) {
+            let loop_start = header.loop_start as i64
+                + offset(LOOP_START_OFFSET, LOOP_START_COARSE_OFFSET)
+                - start as i64;
+            // The SoundFont loop end is the first frame after the loop
+            let loop_end = header.loop_end as i64 + offset(LOOP_END_OFFSET, LOOP_END_COARSE_OFFSET)
+                - start as i64
+                - 1;
+            if loop_start >= 0 && loop_end > loop_start {
+                sample_zone.loop_points = Some((loop_start as usize, loop_end as usize));
+            }
+        }
+
+        // Envelope times are in timecents, the sustain is an attenuation in centibels
+        let seconds = |generator: usize| timecents_to_ms(value(generator, -12_000));
+        sample_zone.envelope = Some(EnvelopeShape::new(
+            seconds(DELAY_VOL_ENV) + seconds(ATTACK_VOL_ENV),
+            seconds(HOLD_VOL_ENV) + seconds(DECAY_VOL_ENV),
+            10f32.powf(-(value(SUSTAIN_VOL_ENV, 0).clamp(0, 1440) as f32) / 200.0),
+            seconds(RELEASE_VOL_ENV),
+        ));
+
+        samples.zones.push(sample_zone);
+    }
+
+    Ok(())
+}
+
+// Resolves the zones of a preset or instrument, each with the global zone's generators underneath
+fn zones(
+    bags: &[u8],
+    generators: &[u8],
+    range: std::ops::Range<usize>,
+    terminal: usize,
+) -> Result<Vec<Generators>> {
+    let mut global: Generators = [None; GENERATOR_COUNT];
+    let mut out = Vec::new();
+
+    for (position, bag) in range.enumerate() {
+        let Some(record) = bags.get(bag * 4..(bag + 2) * 4) else {
+            bail!("SF2 com tabela de zonas truncada");
+        };
+        let first = u16_at(record, 0) as usize;
+        let last = u16_at(record, 4) as usize;
+
+        let mut zone = global;
+        let mut own: Generators = [None; GENERATOR_COUNT];
+        for generator in first..last {
+            let Some(record) = generators.get(generator * 4..generator * 4 + 4) else {
+                bail!("SF2 com tabela de geradores truncada");
+            };
+            let operator = u16_at(record, 0) as usize;
+            if operator < GENERATOR_COUNT {
+                own[operator] = Some(u16_at(record, 2));
+            }
+        }
+        for (slot, value) in zone.iter_mut().zip(own) {
+            if value.is_some() {
+                *slot = value;
+            }
+        }
+
+        // Only the first zone can be global, and it is the one without the terminal generator
+        if position == 0 && own[terminal].is_none() {
+            global = zone;
+        } else {
+            out.push(zone);
+        }
+    }
+
+    Ok(out)
+}
+
+fn sample_data(
+    smpl: &[u8],
+    start: u32,
+    end: u32,
+    right: Option<SampleHeader>,
+    sample_rate: u32,
+) -> Result<SampleData> {
+    let read = |start: u32, end: u32| -> Result<Vec<f32>> {
+        let bytes = smpl
+            .get(start as usize * 2..end as usize * 2)
+            .context("SF2 com sample fora do bloco de dados")?;
+        Ok(bytes
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32_768.0)
+            .collect())
+    };
+
+    let left = read(start, end)?;
+    let right = match right {
+        Some(header) => read(header.start, header.end)?,
+        None => left.clone(),
+    };
+    Ok(SampleData {
+        frames: left.into_iter().zip(right).map(|(l, r)| [l, r]).collect(),
+        sample_rate: sample_rate as f32,
+        stream: None,
+    })
+}
+
+fn sample_header(record: &[u8]) -> SampleHeader {
+    SampleHeader {
+        start: u32_at(record, 20),
+        end: u32_at(record, 24),
+        loop_start: u32_at(record, 28),
+        loop_end: u32_at(record, 32),
+        sample_rate: u32_at(record, 36),
+        original_pitch: record[40],
+        pitch_correction: record[41] as i8,
+        link: u16_at(record, 42),
+        kind: u16_at(record, 44),
+    }
+}
+
+fn chunks(mut data: &[u8]) -> Result<Vec<(&[u8; 4], &[u8])>> {
+    let mut out = Vec::new();
+    while data.len() >= 8 {
+        let id: &[u8; 4] = data[0..4].try_into().expect("slice of four bytes");
+        let size = u32_at(data, 4) as usize;
+        let body = data
+            .get(8..8 + size)
+            .context("SF2 com bloco RIFF truncado")?;
+        out.push((id, body));
+        // Chunks are padded to an even length
+        data = data.get(8 + size + size % 2..).unwrap_or_default();
+    }
+    Ok(out)
+}
+
+fn range(zone: &Generators, generator: usize) -> NoteRange {
+    zone[generator].map_or(NoteRange::new(0, 127), |value| {
+        let [low, high] = value.to_le_bytes();
+        NoteRange::new(low, high.min(127))
+    })
+}
+
+fn intersect(a: NoteRange, b: NoteRange) -> Option<NoteRange> {
+    let range = NoteRange::new(a.low.max(b.low), a.high.min(b.high));
+    (range.low <= range.high).then_some(range)
+}
+
+fn signed(zone: &Generators, generator: usize) -> Option<i16> {
+    zone[generator].map(|value| value as i16)
+}
+
+fn timecents_to_ms(timecents: i32) -> f32 {
+    1000.0 * 2f32.powf(timecents as f32 / 1200.0)
+}
+
+fn record_name(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::DynamicLayer;
+
+    // Generators of one zone, a list of zones per preset or instrument
+    type Zone = Vec<(usize, u16)>;
+
+    struct Sample {
+        frames: Vec<i16>,
+        root: u8,
+        link: u16,
+        kind: u16,
+    }
+
+    fn mono(value: i16, root: u8) -> Sample {
+        Sample {
+            frames: vec![value; 8],
+            root,
+            link: 0,
+            kind: 1,
+        }
+    }
+
+    fn keys(low: u8, high: u8) -> u16 {
+        u16::from_le_bytes([low, high])
+    }
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend((body.len() as u32).to_le_bytes());
+        out.extend(body);
+        if body.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = kind.to_vec();
+        for chunk in chunks {
+            body.extend(chunk);
+        }
+        chunk(b"LIST", &body)
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut out = name.as_bytes().to_vec();
+        out.resize(20, 0);
+        out
+    }
+
+    // Bag and generator tables for groups of zones, and the first bag of each group followed by the total
+    fn zone_tables(groups: &[Vec<Zone>]) -> (Vec<u8>, Vec<u8>, Vec<u16>) {
+        let (mut bags, mut generators, mut starts) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut bag_count, mut generator_count) = (0u16, 0u16);
+        for zones in groups {
+            starts.push(bag_count);
+            for zone in zones {
+                bags.extend(generator_count.to_le_bytes());
+                bags.extend([0, 0]);
+                bag_count += 1;
+                for &(operator, amount) in zone {
+                    generators.extend((operator as u16).to_le_bytes());
+                    generators.extend(amount.to_le_bytes());
+                    generator_count += 1;
+                }
+            }
+        }
+        starts.push(bag_count);
+        bags.extend(generator_count.to_le_bytes());
+        bags.extend([0, 0]);
+        generators.extend([0; 4]);
+        (bags, generators, starts)
+    }
+
+    // Presets are (name, bank, program, zones) and each instrument is its list of zones
+    fn build(
+        presets: &[(&str, u16, u16, Vec<Zone>)],
+        instruments: &[Vec<Zone>],
+        samples: &[Sample],
+    ) -> Vec<u8> {
+        let preset_zones: Vec<_> = presets.iter().map(|p| p.3.clone()).collect();
+        let (pbag, pgen, preset_bags) = zone_tables(&preset_zones);
+        let mut phdr = Vec::new();
+        for (index, (preset, bank, program, _)) in presets.iter().enumerate() {
+            phdr.extend(name(preset));
+            phdr.extend(program.to_le_bytes());
+            phdr.extend(bank.to_le_bytes());
+            phdr.extend(preset_bags[index].to_le_bytes());
+            phdr.extend([0; 12]);
+        }
+        phdr.extend(name("EOP"));
+        phdr.extend([0; 4]);
+        phdr.extend(preset_bags[presets.len()].to_le_bytes());
+        phdr.extend([0; 12]);
+
+        let (ibag, igen, instrument_bags) = zone_tables(instruments);
+        let mut inst = Vec::new();
+        for (index, &bag) in instrument_bags.iter().enumerate() {
+            let label = if index < instruments.len() {
+                "Instrument"
+            } else {
+                "EOI"
+            };
+            inst.extend(name(label));
+            inst.extend(bag.to_le_bytes());
+        }
+
+        let (mut smpl, mut shdr) = (Vec::new(), Vec::new());
+        for sample in samples {
+            let start = (smpl.len() / 2) as u32;
+            for frame in &sample.frames {
+                smpl.extend(frame.to_le_bytes());
+            }
+            let end = (smpl.len() / 2) as u32;
+            shdr.extend(name("Sample"));
+            for value in [start, end, start, end, 44_100] {
+                shdr.extend(value.to_le_bytes());
+            }
+            shdr.extend([sample.root, 0]);
+            shdr.extend(sample.link.to_le_bytes());
+            shdr.extend(sample.kind.to_le_bytes());
+        }
+        shdr.extend(name("EOS"));
+        shdr.extend([0; 26]);
+
+        let mut body = b"sfbk".to_vec();
+        body.extend(list(b"sdta", &[chunk(b"smpl", &smpl)]));
+        body.extend(list(
+            b"pdta",
+            &[
+                chunk(b"phdr", &phdr),
+                chunk(b"pbag", &pbag),
+                chunk(b"pgen", &pgen),
+                chunk(b"pmod", &[0; 10]),
+                chunk(b"inst", &inst),
+                chunk(b"ibag", &ibag),
+                chunk(b"igen", &igen),
+                chunk(b"imod", &[0; 10]),
+                chunk(b"shdr", &shdr),
+            ],
+        ));
+        chunk(b"RIFF", &body)
+    }
+
+    #[test]
+    fn presets_land_on_their_bank_and_program() {
+        let bytes = build(
+            &[
+                ("Strings", 0, 48, vec![vec![(INSTRUMENT, 0)]]),
+                ("Kit", 128, 0, vec![vec![(INSTRUMENT, 1)]]),
+            ],
+            &[
+                vec![
+                    vec![(KEY_RANGE, keys(0, 59)), (SAMPLE_ID, 0)],
+                    vec![(KEY_RANGE, keys(60, 127)), (SAMPLE_ID, 1)],
+                ],
+                vec![vec![(SAMPLE_ID, 1)]],
+            ],
+            &[mono(1000, 48), mono(-2000, 72)],
+        );
+        let font = parse(&bytes).unwrap();
+
+        let found: Vec<_> = font
+            .presets
+            .iter()
+            .map(|p| {
+                (
+                    p.samples.name.as_str(),
+                    p.bank,
+                    p.program,
+                    p.samples.zones.len(),
+                )
+            })
+            .collect();
+        assert_eq!(found, [("Strings", 0, 48, 2), ("Kit", 128, 0, 1)]);
+        let zones = &font.presets[0].samples.zones;
+        assert_eq!((zones[0].keys.low, zones[0].keys.high), (0, 59));
+        assert_eq!((zones[1].keys.low, zones[1].keys.high), (60, 127));
+        assert_eq!((zones[0].root_key, zones[1].root_key), (48, 72));
+        assert_eq!(zones[0].data.frames.len(), 8);
+        assert_eq!(zones[0].data.frames[0], [1000.0 / 32_768.0; 2]);
+        // Both presets play the second sample from the same data
+        assert!(Arc::ptr_eq(
+            &zones[1].data,
+            &font.presets[1].samples.zones[0].data
+        ));
+    }
+
+    #[test]
+    fn global_zones_sit_under_the_local_ones() {
+        let bytes = build(
+            &[(
+                "Layers",
+                0,
+                0,
+                vec![vec![(FINE_TUNE, 10)], vec![(INSTRUMENT, 0)]],
+            )],
+            &[vec![
+                vec![(COARSE_TUNE, 2), (VEL_RANGE, keys(0, 63))],
+                vec![(SAMPLE_ID, 0)],
+                vec![
+                    (COARSE_TUNE, -1i16 as u16),
+                    (VEL_RANGE, keys(64, 127)),
+                    (SAMPLE_ID, 0),
+                ],
+            ]],
+            &[mono(1000, 60)],
+        );
+        let font = parse(&bytes).unwrap();
+
+        let zones = &font.presets[0].samples.zones;
+        assert_eq!(zones.len(), 2);
+        // Instrument tuning plus the preset's offset, the local coarse tune replacing the global one
+        assert_eq!(zones[0].tune_cents, 210.0);
+        assert_eq!(zones[1].tune_cents, -90.0);
+        assert_eq!((zones[0].velocity_low, zones[0].velocity_high), (0, 63));
+        assert_eq!((zones[1].velocity_low, zones[1].velocity_high), (64, 127));
+        assert_eq!(zones[0].layer, Some(DynamicLayer::from_velocity(31).0));
+        assert_eq!(zones[1].layer, Some(DynamicLayer::from_velocity(95).0));
+    }
+
+    #[test]
+    fn stereo_pairs_play_from_the_left_zone() {
+        let bytes = build(
+            &[("Stereo", 0, 0, vec![vec![(INSTRUMENT, 0)]])],
+            &[vec![vec![(SAMPLE_ID, 0)], vec![(SAMPLE_ID, 1)]]],
+            &[
+                Sample {
+                    frames: vec![100; 4],
+                    root: 60,
+                    link: 1,
+                    kind: SAMPLE_LEFT,
+                },
+                Sample {
+                    frames: vec![-100; 4],
+                    root: 60,
+                    link: 0,
+                    kind: SAMPLE_RIGHT,
+                },
+            ],
+        );
+        let font = parse(&bytes).unwrap();
+
+        let zones = &font.presets[0].samples.zones;
+        assert_eq!(zones.len(), 1);
+        assert_eq!(
+            zones[0].data.frames,
+            [[100.0 / 32_768.0, -100.0 / 32_768.0]; 4]
+        );
+    }
+
+    #[test]
+    fn truncated_or_dangling_data_is_an_error() {
+        let preset = [("Strings", 0, 0, vec![vec![(INSTRUMENT, 0)]])];
+        let instrument = [vec![vec![(SAMPLE_ID, 0)]]];
+        let bytes = build(&preset, &instrument, &[mono(1000, 60)]);
+        assert!(parse(&bytes).is_ok());
+
+        let error = parse(&bytes[..bytes.len() - 10]).unwrap_err();
+        assert!(format!("{error:#}").contains("truncado"), "{error:#}");
+        assert!(parse(&bytes[..8]).is_err());
+
+        let dangling = build(
+            &[("Strings", 0, 0, vec![vec![(INSTRUMENT, 5)]])],
+            &instrument,
+            &[mono(1000, 60)],
+        );
+        let error = parse(&dangling).unwrap_err();
+        assert!(
+            format!("{error:#}").contains("instrumento inexistente"),
+            "{error:#}"
+        );
+
+        let missing_sample = build(&preset, &[vec![vec![(SAMPLE_ID, 3)]]], &[mono(1000, 60)]);
+        let error = parse(&missing_sample).unwrap_err();
+        assert!(
+            format!("{error:#}").contains("sample inexistente"),
+            "{error:#}"
+        );
+    }
+}
@@ -0,0 +1,462 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::engine::{Articulation, EnvelopeShape};
+use crate::patch::NoteRange;
+use crate::sampler::{SampleData, SampleSet, SampleZone};
+
+// An SFZ file assigned to a program of bank 0, as kept in the plugin state
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SfzInstrument {
+    pub program: u8,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Header {
+    Control,
+    Global,
+    Master,
+    Group,
+    Region,
+}
+
+type Opcodes = HashMap<String, String>;
+
+#[derive(Default)]
+struct Scopes {
+    control: Opcodes,
+    global: Opcodes,
+    master: Opcodes,
+    group: Opcodes,
+    regions: Vec<Opcodes>,
+    // Opcodes under headers the engine does not use, such as curves and effects
+    ignored: Opcodes,
+}
+
+impl Scopes {
+    // Each header starts a fresh scope and clears the ones nested below it
+    fn open(&mut self, header: Option<Header>) {
+        match header {
+            Some(Header::Global) => {
+                self.global.clear();
+                self.master.clear();
+                self.group.clear();
+            }
+            Some(Header::Master) => {
+                self.master.clear();
+                self.group.clear();
+            }
+            Some(Header::Group) => self.group.clear(),
+            Some(Header::Region) => {
+                let mut region = self.global.clone();
+                region.extend(self.master.clone());
+                region.extend(self.group.clone());
+                self.regions.push(region);
+            }
+            Some(Header::Control) | None => {}
+        }
+    }
+
+    fn current(&mut self, header: Option<Header>) -> &mut Opcodes {
+        match header {
+            Some(Header::Control) => &mut self.control,
+            Some(Header::Global) => &mut self.global,
+            Some(Header::Master) => &mut self.master,
+            Some(Header::Group) => &mut self.group,
+            Some(Header::Region) => self.regions.last_mut().unwrap_or(&mut self.ignored),
+            None => &mut self.ignored,
+        }
+    }
+}
+
+pub fn load(path: &Path) -> Result<SampleSet> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("Falha ao ler SFZ: {path:?}"))?;
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    let mut set = parse(&text, base_dir)?;
+    if let Some(name) = path.file_stem() {
+        set.name = name.to_string_lossy().into_owned();
+    }
+    Ok(set)
+}
+
+pub fn parse(text: &str, base_dir: &Path) -> Result<SampleSet> {
+    let mut scopes = Scopes::default();
+    let mut header: Option<Header> = Some(Header::Global);
+    let mut current: Option<String> = None;
+
+    for token in tokens(&strip_comments(text)) {
+        match token {
+            Token::Header(name) => {
+                header = match name.as_str() {
+                    "control" => Some(Header::Control),
+                    "global" => Some(Header::Global),
+                    "master" => Some(Header::Master),
+                    "group" => Some(Header::Group),
+                    "region" => Some(Header::Region),
+                    _ => None,
+                };
+                scopes.open(header);
+                current = None;
+            }
+            Token::Opcode(name, value) => {
+                scopes.current(header).insert(name.clone(), value);
+                current = Some(name);
+            }
+            // Unquoted sample names may contain spaces, which split them into several words
+            Token::Word(word) => {
+                let scope = scopes.current(header);
+                if let Some(value) = current.as_ref().and_then(|name| scope.get_mut(name)) {
+                    value.push(' ');
+                    value.push_str(&word);
+                }
+            }
+        }
+    }
+
+    let regions = scopes.regions;
+    let default_path = scopes
+        .control
+        .get("default_path")
+        .cloned()
+        .unwrap_or_default();
+    let keyswitches = keyswitch_articulations(&regions)?;
+    let mut cache: HashMap<PathBuf, Arc<SampleData>> = HashMap::new();
+    let mut set = SampleSet::default();
+
+    for region in &regions {
+        let Some(sample) = region.get("sample") else {
+            continue;
+        };
+        let articulation = match region.get("sw_last") {
+            Some(key) => match keyswitches.get(&parse_key(key)?) {
+                Some(&articulation) => Some(articulation),
+                // Keyswitched articulations the engine has no equivalent for are left out
+                None => continue,
+            },
+            None => None,
+        };
+
+        let path = base_dir.join(format!("{default_path}{sample}").replace('\\', "/"));
+        let data = match cache.get(&path) {
+            Some(data) => data.clone(),
+            None => {
+                let data = Arc::new(SampleData::open(&path)?);
+                cache.insert(path, data.clone());
+                data
+            }
+        };
+
+        set.zones.push(zone(region, data, articulation)?);
+    }
+
+    if set.zones.is_empty() {
+        bail!("SFZ sem regiões tocáveis");
+    }
+    set.keyswitches = keyswitches.into_iter().collect();
+    set.keyswitches.sort_by_key(|&(key, _)| key);
+    Ok(set)
+}
+
+fn zone(
+    region: &Opcodes,
+    data: Arc<SampleData>,
+    articulation: Option<Articulation>,
+) -> Result<SampleZone> {
+    let key = region.get("key").map(|k| parse_key(k)).transpose()?;
+    let low = opcode_key(region, "lokey")?.or(key).unwrap_or(0);
+    let high = opcode_key(region, "hikey")?.or(key).unwrap_or(127);
+    let root = opcode_key(region, "pitch_keycenter")?.or(key).unwrap_or(60);
+    let transpose: f32 = opcode(region, "transpose")?.unwrap_or(0.0);
+
+    let mut zone = SampleZone::new(data, root);
+    zone.keys = NoteRange::new(low, high);
+    zone.set_velocity_window(
+        opcode(region, "lovel")?.unwrap_or(0.0) as u8,
+        opcode(region, "hivel")?.unwrap_or(127.0) as u8,
+    );
+    zone.articulation = articulation;
+    zone.tune_cents = opcode(region, "tune")?.unwrap_or(0.0) + transpose * 100.0;
+    zone.gain = 10f32.powf(opcode(region, "volume")?.unwrap_or(0.0) / 20.0);
+    // seq_position counts from 1, and the cycle length follows from the highest position in use
+    let position: Option<f32> = opcode(region, "seq_position")?;
+    zone.round_robin = position.map(|p| (p as usize).saturating_sub(1));
+    if let Some(length) = opcode::<f32>(region, "seq_length")? {
+        zone.round_robin = zone.round_robin.map(|p| p % (length as usize).max(1));
+    }
+
+    let looped = matches!(
+        region.get("loop_mode").map(String::as_str),
+        Some("loop_continuous" | "loop_sustain")
+    );
+    if looped {
+        let start = opcode::<f32>(region, "loop_start")?.or(opcode(region, "loopstart")?);
+        let end = opcode::<f32>(region, "loop_end")?.or(opcode(region, "loopend")?);
+        if let (Some(start), Some(end)) = (start, end) {
+            zone.loop_points = Some((start as usize, end as usize));
+        }
+    }
+
+    // The SFZ amplitude envelope is in seconds and percent and defaults to an organ-like shape
+    let stage = |name: &str| opcode::<f32>(region, &format!("ampeg_{name}"));
+    let ampeg = [
+        stage("attack")?,
+        stage("decay")?,
+        stage("sustain")?,
+        stage("release")?,
+    ];
+    if ampeg.iter().any(Option::is_some) {
+        let [attack, decay, sustain, release] = ampeg;
+        zone.envelope = Some(EnvelopeShape::new(
+            attack.unwrap_or(0.0) * 1000.0,
+            decay.unwrap_or(0.0) * 1000.0,
+            sustain.unwrap_or(100.0) / 100.0,
+            release.unwrap_or(0.001) * 1000.0,
+        ));
+    }
+
+    Ok(zone)
+}
+
+// Maps each `sw_last` key to an articulation, by its `sw_label` when it names one and otherwise by
+// the usual order of sustain, staccato and marcato from the lowest key up
+fn keyswitch_articulations(regions: &[Opcodes]) -> Result<HashMap<u8, Articulation>> {
+    let mut labels: Vec<(u8, Option<String>)> = Vec::new();
+    for region in regions {
+        if let Some(key) = region.get("sw_last") {
+            let key = parse_key(key)?;
+            if !labels.iter().any(|(k, _)| *k == key) {
+                labels.push((key, region.get("sw_label").map(|l| l.to_ascii_lowercase())));
+            }
+        }
+    }
+    labels.sort_by_key(|(key, _)| *key);
+
+    let mut map = HashMap::new();
+    let mut unlabeled = [
+        Articulation::Sustain,
+        Articulation::Staccato,
+        Articulation::Marcato,
+    ]
+    .into_iter();
+    for (key, label) in labels {
+        let articulation = match label {
+            Some(label) => label_articulation(&label),
+            None => unlabeled.next(),
+        };
+        if let Some(articulation) = articulation {
+            map.insert(key, articulation);
+        }
+    }
+    Ok(map)
+}
+
+fn label_articulation(label: &str) -> Option<Articulation> {
+    const STACCATO: [&str; 3] = ["stac", "spic", "short"];
+    const MARCATO: [&str; 3] = ["marc", "accent", "sfz"];
+    const SUSTAIN: [&str; 4] = ["sus", "leg", "long", "arco"];
+
+    if STACCATO.iter().any(|word| label.contains(word)) {
+        Some(Articulation::Staccato)
+    } else if MARCATO.iter().any(|word| label.contains(word)) {
+        Some(Articulation::Marcato)
+    } else if SUSTAIN.iter().any(|word| label.contains(word)) {
+        Some(Articulation::Sustain)
+    } else {
+        None
+    }
+}
+
+fn opcode<T: std::str::FromStr>(region: &Opcodes, name: &str) -> Result<Option<T>> {
+    region
+        .get(name)
+        .map(|value| {
+            value
+                .parse()
+                .ok()
+                .with_context(|| format!("Valor inválido para {name}: {value}"))
+        })
+        .transpose()
+}
+
+fn opcode_key(region: &Opcodes, name: &str) -> Result<Option<u8>> {
+    region.get(name).map(|value| parse_key(value)).transpose()
+}
+
+// Keys are either MIDI numbers or note names with C4 as middle C, e.g. `c#4` or `eb-1`
+fn parse_key(value: &str) -> Result<u8> {
+    if let Ok(number) = value.parse::<i32>() {
+        return u8::try_from(number)
+            .ok()
+            .filter(|&n| n < 128)
+            .with_context(|| format!("Nota fora da faixa MIDI: {value}"));
+    }
+
+    let lower = value.to_ascii_lowercase();
+    let mut chars = lower.chars();
+    let pitch_class = match chars.next() {
+        Some('c') => 0,
+        Some('d') => 2,
+        Some('e') => 4,
+        Some('f') => 5,
+        Some('g') => 7,
+        Some('a') => 9,
+        Some('b') => 11,
+        _ => bail!("Nota inválida: {value}"),
+    };
+    let rest = chars.as_str();
+    let (accidental, octave) = match rest.chars().next() {
+        Some('#') => (1, &rest[1..]),
+        Some('b') => (-1, &rest[1..]),
+        _ => (0, rest),
+    };
+    let octave: i32 = octave
+        .parse()
+        .with_context(|| format!("Nota inválida: {value}"))?;
+    let number = (octave + 1) * 12 + pitch_class + accidental;
+    u8::try_from(number)
+        .ok()
+        .filter(|&n| n < 128)
+        .with_context(|| format!("Nota fora da faixa MIDI: {value}"))
+}
+
+fn strip_comments(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("/*") {
+            rest = after.find("*/").map_or("", |end| &after[end + 2..]);
+        } else if let Some(after) = rest.strip_prefix("//") {
+            rest = after.find('\n').map_or("", |end| &after[end..]);
+        } else {
+            let next = rest.chars().next().unwrap_or_default();
+            out.push(next);
+            rest = &rest[next.len_utf8()..];
+        }
+    }
+    out
+}
+
+enum Token {
+    Header(String),
+    Opcode(String, String),
+    Word(String),
+}
+
+fn tokens(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for word in text.split_whitespace() {
+        let mut word = word;
+        // Headers may be glued to the opcode that follows them, as in `<region>sample=a.wav`
+        while let Some(after) = word.strip_prefix('<') {
+            let Some(end) = after.find('>') else {
+                break;
+            };
+            tokens.push(Token::Header(after[..end].to_ascii_lowercase()));
+            word = &after[end + 1..];
+        }
+        if word.is_empty() {
+            continue;
+        }
+
+        match word.split_once('=') {
+            Some((name, value))
+                if !name.is_empty()
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+            {
+                tokens.push(Token::Opcode(name.to_ascii_lowercase(), value.to_string()));
+            }
+            _ => tokens.push(Token::Word(word.to_string())),
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{DynamicLayer, MidiProcessor};
+
+    // Folder with one short WAV for the regions to point at
+    fn sample_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sfz-test-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 48_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(dir.join("a.wav"), spec).unwrap();
+        for _ in 0..64 {
+            writer.write_sample(1000i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        dir
+    }
+
+    fn select(set: &SampleSet, velocity: u8) -> Option<usize> {
+        let (layer, _) = DynamicLayer::from_velocity(velocity);
+        set.select(60, velocity, layer, Articulation::Sustain, 0)
+    }
+
+    #[test]
+    fn velocity_windows_pick_their_regions() {
+        let dir = sample_dir("windows");
+        // Eight windows, more than there are dynamic layers, split at 64 like most two-layer libraries
+        let text: String = (0..8)
+            .map(|n| format!("<region> sample=a.wav lovel={} hivel={}\n", n * 16, n * 16 + 15))
+            .collect();
+        let set = parse(&text, &dir).unwrap();
+        assert_eq!((set.zones[3].velocity_low, set.zones[3].velocity_high), (48, 63));
+        assert_eq!((set.zones[4].velocity_low, set.zones[4].velocity_high), (64, 79));
+        assert_eq!(select(&set, 63), Some(3));
+        assert_eq!(select(&set, 64), Some(4));
+        for velocity in 0..=127 {
+            assert_eq!(select(&set, velocity), Some(velocity as usize / 16), "velocity {velocity}");
+        }
+
+        // A region without a window plays at every velocity
+        let set = parse("<region> sample=a.wav", &dir).unwrap();
+        assert_eq!(set.zones[0].layer, None);
+        assert!((0..=127).all(|velocity| select(&set, velocity) == Some(0)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn velocities_between_windows_play_the_nearest_layer() {
+        let dir = sample_dir("gaps");
+        let text = "<region> sample=a.wav lovel=0 hivel=40\n\
+                    <region> sample=a.wav lovel=100 hivel=127";
+        let set = parse(text, &dir).unwrap();
+        let layers: Vec<_> = set.zones.iter().map(|zone| zone.layer).collect();
+        assert_eq!(layers, [Some(DynamicLayer::Pp), Some(DynamicLayer::Ff)]);
+        for (velocity, zone) in [(40, 0), (45, 0), (55, 0), (80, 1), (99, 1), (100, 1)] {
+            assert_eq!(select(&set, velocity), Some(zone), "velocity {velocity}");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn every_sequence_position_plays_in_turn() {
+        let dir = sample_dir("sequence");
+        let text: String = (1..=6)
+            .map(|position| format!("<region> sample=a.wav seq_length=6 seq_position={position}\n"))
+            .collect();
+        let set = parse(&text, &dir).unwrap();
+        let mut midi = MidiProcessor::new();
+        let played: Vec<_> = (0..12)
+            .map(|_| {
+                let pass = midi.step_round_robin(60);
+                set.select(60, 100, DynamicLayer::F, Articulation::Sustain, pass)
+            })
+            .collect();
+        let expected: Vec<_> = (0..12).map(|n| Some(n % 6)).collect();
+        assert_eq!(played, expected);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
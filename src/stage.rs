@@ -0,0 +1,317 @@
+use serde::{Deserialize, Serialize};
+
+use crate::engine::Section;
+use crate::reflections::EarlyReflections;
+
+// Distance from the listener to the front desks and to the back row of the stage
+pub const STAGE_NEAR_M: f32 = 4.0;
+pub const STAGE_FAR_M: f32 = 16.0;
+pub const SPEED_OF_SOUND: f32 = 343.0;
+// Sounds that are no orchestral section, like pianos or drums from a SoundFont, get the last bus
+pub const STAGE_BUSES: usize = Section::ALL.len() + 1;
+const MAX_PRE_DELAY_MS: f32 = (STAGE_FAR_M - STAGE_NEAR_M) / SPEED_OF_SOUND * 1000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Seating {
+    // Violins together on the left, cellos and basses on the right
+    #[default]
+    American,
+    // Violins split left and right, cellos and basses behind the first violins
+    European,
+}
+
+impl Seating {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "american" => Some(Seating::American),
+            "european" | "german" => Some(Seating::European),
+            _ => None,
+        }
+    }
+
+    pub fn position(self, section: Section) -> StagePosition {
+        let (azimuth, depth) = match (self, section) {
+            (_, Section::Violins1) => (-0.65, 0.1),
+            (Seating::American, Section::Violins2) => (-0.3, 0.3),
+            (Seating::American, Section::Violas) => (0.3, 0.3),
+            (Seating::American, Section::Cellos) => (0.6, 0.15),
+            (Seating::American, Section::Basses) => (0.8, 0.4),
+            (Seating::European, Section::Violins2) => (0.65, 0.1),
+            (Seating::European, Section::Violas) => (0.3, 0.25),
+            (Seating::European, Section::Cellos) => (-0.3, 0.25),
+            (Seating::European, Section::Basses) => (-0.75, 0.45),
+            (_, Section::Flutes) => (-0.15, 0.5),
+            (_, Section::Oboes) => (0.15, 0.5),
+            (_, Section::Clarinets) => (-0.15, 0.65),
+            (_, Section::Bassoons) => (0.15, 0.65),
+            (_, Section::Horns) => (-0.45, 0.75),
+            (_, Section::Trumpets) => (0.05, 0.85),
+            (_, Section::Trombones) => (0.35, 0.85),
+            (_, Section::Tuba) => (0.55, 0.85),
+        };
+        StagePosition { azimuth, depth }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StagePosition {
+    // -1 at the left edge of the stage to 1 at the right, seen from the audience
+    pub azimuth: f32,
+    // 0 at the front edge to 1 at the back wall
+    pub depth: f32,
+}
+
+impl StagePosition {
+    pub const CENTRE: Self = Self {
+        azimuth: 0.0,
+        depth: 0.0,
+    };
+
+    pub fn pan(self) -> f32 {
+        0.5 + self.azimuth * 0.5
+    }
+
+    // `depth_amount` scales the stage depth, 0 brings every seat up to the front desks
+    pub fn distance_m(self, depth_amount: f32) -> f32 {
+        STAGE_NEAR_M + (STAGE_FAR_M - STAGE_NEAR_M) * self.depth * depth_amount.clamp(0.0, 1.0)
+    }
+}
+
+// One section's sound on its way from its seat to the listener: quieter, darker and later with distance
+#[derive(Debug, Clone)]
+struct StageBus {
+    delay: Vec<[f32; 2]>,
+    write: usize,
+    delay_samples: usize,
+    gain: f32,
+    // Level sent to the hall reverb, which rises with distance as the direct sound gets weaker
+    send: f32,
+    coefficient: f32,
+    lowpass: [f32; 2],
+}
+
+impl StageBus {
+    fn new(length: usize) -> Self {
+        Self {
+            delay: vec![[0.0; 2]; length],
+            write: 0,
+            delay_samples: 0,
+            gain: 1.0,
+            send: 0.5,
+            coefficient: 1.0,
+            lowpass: [0.0; 2],
+        }
+    }
+
+    fn place(&mut self, distance_m: f32, sample_rate: f32) {
+        // Half the free-field falloff, the hall's reverberant field keeps distant players from fading as fast
+        self.gain = (STAGE_NEAR_M / distance_m).sqrt();
+        let travel = distance_m - STAGE_NEAR_M;
+        self.send = 0.5 + 0.5 * travel / (STAGE_FAR_M - STAGE_NEAR_M);
+        self.delay_samples =
+            ((travel / SPEED_OF_SOUND * sample_rate) as usize).min(self.delay.len() - 1);
+        // Air and the players in front take the top octaves off the back rows
+        let damping_hz = 20_000.0 * 0.3_f32.powf(travel / (STAGE_FAR_M - STAGE_NEAR_M));
+        self.coefficient = if damping_hz >= sample_rate * 0.45 {
+            1.0
+        } else {
+            1.0 - (-std::f32::consts::TAU * damping_hz / sample_rate).exp()
+        };
+    }
+
+    // Direct sound and reverb send
+    fn next(&mut self, input: [f32; 2]) -> ([f32; 2], [f32; 2]) {
+        let length = self.delay.len();
+        self.delay[self.write] = input;
+        let delayed = self.delay[(self.write + length - self.delay_samples) % length];
+        self.write = (self.write + 1) % length;
+        for (state, sample) in self.lowpass.iter_mut().zip(delayed) {
+            *state += (sample - *state) * self.coefficient;
+        }
+        let [left, right] = self.lowpass;
+        (
+            [left * self.gain, right * self.gain],
+            [left * self.send, right * self.send],
+        )
+    }
+
+    fn clear(&mut self) {
+        self.delay.fill([0.0; 2]);
+        self.lowpass = [0.0; 2];
+    }
+}
+
+// One sample of the stage as heard from the listener
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StageOutput {
+    pub direct: [f32; 2],
+    // What the sections send to the hall reverb
+    pub send: [f32; 2],
+    // First echoes off the walls, which tell the ear how deep and wide each seat is
+    pub early: [f32; 2],
+}
+
+#[derive(Debug)]
+pub struct Stage {
+    pub seating: Seating,
+    pub depth: f32,
+    buses: Vec<StageBus>,
+    reflections: EarlyReflections,
+    sample_rate: f32,
+    // Seating and depth the buses are set up for
+    placed: Option<(Seating, f32)>,
+}
+
+impl Stage {
+    pub fn new(sample_rate: f32) -> Self {
+        let length = (MAX_PRE_DELAY_MS / 1000.0 * sample_rate) as usize + 2;
+        Self {
+            seating: Seating::default(),
+            depth: 1.0,
+            buses: (0..STAGE_BUSES).map(|_| StageBus::new(length)).collect(),
+            reflections: EarlyReflections::new(sample_rate),
+            sample_rate,
+            placed: None,
+        }
+    }
+
+    // Rebuilds the delay lines, outside the audio thread
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        *self = Self {
+            seating: self.seating,
+            depth: self.depth,
+            ..Self::new(sample_rate)
+        };
+    }
+
+    pub fn position(&self, section: Option<Section>) -> StagePosition {
+        section.map_or(StagePosition::CENTRE, |section| {
+            self.seating.position(section)
+        })
+    }
+
+    // Buses follow `Section::ALL`, the last one takes everything else
+    pub fn bus(section: Option<Section>) -> usize {
+        section
+            .and_then(|section| Section::ALL.iter().position(|&s| s == section))
+            .unwrap_or(STAGE_BUSES - 1)
+    }
+
+    pub fn reset(&mut self) {
+        for bus in &mut self.buses {
+            bus.clear();
+        }
+        self.reflections.reset();
+    }
+
+    // Takes the sum of each bus for one sample
+    pub fn process(&mut self, buses: &[[f32; 2]; STAGE_BUSES]) -> StageOutput {
+        if self.placed != Some((self.seating, self.depth)) {
+            self.place();
+        }
+        let mut output = StageOutput::default();
+        for (bus, &input) in self.buses.iter_mut().zip(buses) {
+            let (bus_direct, bus_send) = bus.next(input);
+            for channel in 0..2 {
+                output.direct[channel] += bus_direct[channel];
+                output.send[channel] += bus_send[channel];
+            }
+        }
+        output.early = self.reflections.next(buses);
+        output
+    }
+
+    fn place(&mut self) {
+        self.placed = Some((self.seating, self.depth));
+        let sections = Section::ALL.map(Some).into_iter().chain([None]);
+        for section in sections {
+            let (index, position) = (Self::bus(section), self.position(section));
+            self.buses[index].place(position.distance_m(self.depth), self.sample_rate);
+            self.reflections.place(index, position, self.depth);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    // Direct sound of one section after a click on its bus
+    fn impulse_response(seating: Seating, section: Option<Section>) -> Vec<StageOutput> {
+        let mut stage = Stage::new(SAMPLE_RATE);
+        stage.seating = seating;
+        let mut buses = [[0.0; 2]; STAGE_BUSES];
+        buses[Stage::bus(section)] = [1.0; 2];
+        let mut out = vec![stage.process(&buses)];
+        out.extend((1..8_000).map(|_| stage.process(&[[0.0; 2]; STAGE_BUSES])));
+        out
+    }
+
+    #[test]
+    fn every_section_has_a_bus_of_its_own() {
+        let mut buses: Vec<_> = Section::ALL.iter().map(|&s| Stage::bus(Some(s))).collect();
+        buses.push(Stage::bus(None));
+        assert_eq!(buses, (0..STAGE_BUSES).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn seatings_put_the_strings_on_their_sides() {
+        let side = |seating: Seating, section| seating.position(section).pan() - 0.5;
+        for seating in [Seating::American, Seating::European] {
+            assert!(side(seating, Section::Violins1) < 0.0);
+            assert!(side(seating, Section::Horns) < 0.0);
+            assert!(side(seating, Section::Tuba) > 0.0);
+        }
+        assert!(side(Seating::American, Section::Violins2) < 0.0);
+        assert!(side(Seating::American, Section::Cellos) > 0.0);
+        assert!(side(Seating::American, Section::Basses) > 0.0);
+        assert!(side(Seating::European, Section::Violins2) > 0.0);
+        assert!(side(Seating::European, Section::Cellos) < 0.0);
+        assert!(side(Seating::European, Section::Basses) < 0.0);
+        assert_eq!(StagePosition::CENTRE.pan(), 0.5);
+    }
+
+    #[test]
+    fn each_seat_is_heard_at_its_distance() {
+        for seating in [Seating::American, Seating::European] {
+            let sections = Section::ALL.map(Some).into_iter().chain([None]);
+            for section in sections {
+                let position = section.map_or(StagePosition::CENTRE, |s| seating.position(s));
+                let distance = position.distance_m(1.0);
+                let out = impulse_response(seating, section);
+                let arrival = out.iter().position(|o| o.direct[0] > 1e-6).unwrap();
+                let expected = ((distance - STAGE_NEAR_M) / SPEED_OF_SOUND * SAMPLE_RATE) as usize;
+                assert_eq!(arrival, expected, "{seating:?} {section:?}");
+                // The lowpass keeps the level of what gets through, so the sum is the distance gain
+                let level = out.iter().map(|o| o.direct[0]).sum::<f32>();
+                let gain = (STAGE_NEAR_M / distance).sqrt();
+                assert!(
+                    (level - gain).abs() < 1e-3,
+                    "{seating:?} {section:?}: {level}"
+                );
+                assert_eq!(out[arrival].direct[0], out[arrival].direct[1]);
+            }
+        }
+        let pre_delay = |section| {
+            let out = impulse_response(Seating::American, Some(section));
+            out.iter().position(|o| o.direct[0] > 1e-6).unwrap() as f32 / SAMPLE_RATE
+        };
+        assert!(pre_delay(Section::Trumpets) > pre_delay(Section::Violins1) + 0.02);
+        assert!(pre_delay(Section::Trumpets) < MAX_PRE_DELAY_MS / 1000.0);
+    }
+
+    #[test]
+    fn stage_depth_brings_everyone_to_the_front() {
+        let mut stage = Stage::new(SAMPLE_RATE);
+        stage.depth = 0.0;
+        let mut buses = [[0.0; 2]; STAGE_BUSES];
+        buses[Stage::bus(Some(Section::Tuba))] = [1.0; 2];
+        let mut out = vec![stage.process(&buses)];
+        out.extend((1..8_000).map(|_| stage.process(&[[0.0; 2]; STAGE_BUSES])));
+        assert!(out[0].direct[0] > 0.0);
+        let level = out.iter().map(|o| o.direct[0]).sum::<f32>();
+        assert!((level - 1.0).abs() < 1e-3, "{level}");
+    }
+}
@@ -0,0 +1,202 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::cc_map::CcMap;
+use crate::engine::{ModMatrix, OrchestraEngine, HUMANIZE_SEED, MIDI_CHANNELS};
+use crate::preset::{apply_channel_layout, channel_layout, ChannelPreset};
+use crate::sfz::SfzInstrument;
+use crate::tuning::TuningConfig;
+
+// Bumped whenever a field changes meaning, together with a step in `migrate` for the older state
+pub const STATE_VERSION: u32 = 1;
+// Persistent field holding the engine state
+pub const STATE_KEY: &str = "engine";
+
+// Engine configuration that is not an automatable parameter, saved with the DAW project
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EngineState {
+    pub version: u32,
+    pub cc_map: CcMap,
+    pub tuning: TuningConfig,
+    pub mod_matrix: ModMatrix,
+    pub sfz_instruments: Vec<SfzInstrument>,
+    pub sound_font: Option<PathBuf>,
+    // WAV the convolution reverb plays the hall from, instead of the algorithmic reverb
+    pub impulse_response: Option<PathBuf>,
+    // Patch, solo, legato and keyswitch articulation of each MIDI channel
+    pub channels: [ChannelPreset; MIDI_CHANNELS],
+    pub humanize_seed: u64,
+}
+
+impl Default for EngineState {
+    fn default() -> Self {
+        Self {
+            version: STATE_VERSION,
+            cc_map: CcMap::default(),
+            tuning: TuningConfig::default(),
+            mod_matrix: ModMatrix::default(),
+            sfz_instruments: Vec::new(),
+            sound_font: None,
+            impulse_response: None,
+            channels: std::array::from_fn(|channel| ChannelPreset {
+                channel: channel as u8 + 1,
+                ..ChannelPreset::default()
+            }),
+            humanize_seed: HUMANIZE_SEED,
+        }
+    }
+}
+
+impl EngineState {
+    // Picks up what the engine changes on its own while playing, cheap enough for the audio thread
+    pub fn capture(&mut self, engine: &OrchestraEngine) {
+        self.channels = channel_layout(engine);
+        self.humanize_seed = engine.midi.seed();
+    }
+
+    // Channel layout and seed, once the instruments the channels point at are loaded
+    pub fn restore_layout(&self, engine: &mut OrchestraEngine) {
+        engine.midi.reseed(self.humanize_seed);
+        apply_channel_layout(engine, &self.channels);
+    }
+}
+
+// Brings the engine state saved by any earlier version up to the current one, before nih-plug deserializes it
+pub fn migrate_fields(fields: &mut BTreeMap<String, String>) -> Result<()> {
+    let Some(text) = fields.get(STATE_KEY) else {
+        return Ok(());
+    };
+    let mut state = serde_json::from_str(text).context("Estado do motor ilegível")?;
+    migrate(&mut state)?;
+    fields.insert(STATE_KEY.to_string(), serde_json::to_string(&state)?);
+    Ok(())
+}
+
+fn migrate(state: &mut Value) -> Result<()> {
+    let Some(object) = state.as_object_mut() else {
+        bail!("Estado do motor não é um objeto JSON");
+    };
+    let version = object
+        .get("version")
+        .and_then(Value::as_u64)
+        .unwrap_or(STATE_VERSION as u64);
+    if version > STATE_VERSION as u64 {
+        bail!("Estado salvo por uma versão mais nova do plugin: {version}");
+    }
+
+    // Each step takes the state one version up. Version 1 is the first, so there are none yet; fields a
+    // state lacks fall back to their defaults
+    object.insert("version".to_string(), STATE_VERSION.into());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cc_map::CcTarget;
+    use crate::engine::{Articulation, ModDestination, ModRoute, ModSource};
+    use crate::preset::channel_layout;
+
+    #[test]
+    fn layout_and_seed_survive_a_save_and_reload() {
+        let mut engine = OrchestraEngine::new(48_000.0);
+        engine.program_change(1, 3);
+        engine.program_change(4, 10);
+        engine.set_solo(4, true);
+        engine.channels[2].legato_enabled = false;
+        engine.program_change(6, 1);
+        engine.channels[6].keyswitch = Some(Articulation::Staccato);
+        engine.midi.reseed(1234);
+
+        let mut state = EngineState::default();
+        state.capture(&engine);
+        let text = serde_json::to_string(&state).unwrap();
+        let restored: EngineState = serde_json::from_str(&text).unwrap();
+        let mut fresh = OrchestraEngine::new(48_000.0);
+        restored.restore_layout(&mut fresh);
+
+        for (saved, loaded) in engine.channels.iter().zip(&fresh.channels) {
+            assert_eq!(saved.patch, loaded.patch);
+            assert_eq!(saved.solo, loaded.solo);
+            assert_eq!(saved.legato_enabled, loaded.legato_enabled);
+            assert_eq!(saved.keyswitch, loaded.keyswitch);
+        }
+        assert_eq!(channel_layout(&fresh), channel_layout(&engine));
+        assert_ne!(
+            fresh.channels[4].patch,
+            OrchestraEngine::new(48_000.0).channels[4].patch
+        );
+        assert_eq!(fresh.midi.seed(), 1234);
+    }
+
+    #[test]
+    fn every_part_of_the_state_survives_a_save_and_reload() {
+        let mut state = EngineState::default();
+        state.cc_map.bind(21, CcTarget::Vibrato);
+        state.tuning = TuningConfig {
+            scl: Some("! test.scl\nQuarter tones\n1\n2/1\n".to_string()),
+            kbm: None,
+        };
+        state.mod_matrix.routes.push(ModRoute {
+            source: ModSource::Cc(2),
+            destination: ModDestination::Cutoff,
+            amount: 0.5,
+        });
+        state.sfz_instruments.push(SfzInstrument {
+            program: 3,
+            path: PathBuf::from("cellos.sfz"),
+        });
+        state.sound_font = Some(PathBuf::from("gm.sf2"));
+        state.impulse_response = Some(PathBuf::from("hall.wav"));
+        state.humanize_seed = 99;
+
+        let mut fields = BTreeMap::new();
+        fields.insert(
+            STATE_KEY.to_string(),
+            serde_json::to_string(&state).unwrap(),
+        );
+        migrate_fields(&mut fields).unwrap();
+        let restored: EngineState = serde_json::from_str(&fields[STATE_KEY]).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&restored).unwrap(),
+            serde_json::to_value(&state).unwrap()
+        );
+        let binding = restored.cc_map.bindings().find(|b| b.msb == 21).copied();
+        assert_eq!(
+            binding.map(|b| (b.msb, b.target)),
+            Some((21, CcTarget::Vibrato))
+        );
+        assert_eq!(restored.tuning, state.tuning);
+        assert_eq!(restored.mod_matrix.routes, state.mod_matrix.routes);
+        assert_eq!(restored.sfz_instruments, state.sfz_instruments);
+        assert_eq!(restored.sound_font, state.sound_font);
+        assert_eq!(restored.impulse_response, state.impulse_response);
+        assert_eq!(restored.humanize_seed, 99);
+    }
+
+    #[test]
+    fn missing_fields_fall_back_to_their_defaults() {
+        let mut fields = BTreeMap::new();
+        fields.insert(STATE_KEY.to_string(), "{}".to_string());
+        migrate_fields(&mut fields).unwrap();
+        let state: EngineState = serde_json::from_str(&fields[STATE_KEY]).unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.humanize_seed, HUMANIZE_SEED);
+        assert!(state.sfz_instruments.is_empty());
+        assert_eq!(state.channels[9].channel, 10);
+    }
+
+    #[test]
+    fn state_from_a_newer_version_is_rejected() {
+        let mut fields = BTreeMap::new();
+        let newer = format!("{{\"version\": {}}}", STATE_VERSION + 1);
+        fields.insert(STATE_KEY.to_string(), newer.clone());
+        assert!(migrate_fields(&mut fields).is_err());
+        assert_eq!(fields[STATE_KEY], newer);
+    }
+}
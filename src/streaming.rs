@@ -0,0 +1,471 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::engine::MAX_VOICES;
+use crate::sampler::{read_frames, SampleData};
+
+// Frames of a streamed recording kept in memory, long enough to hide the reader's latency at note start
+pub const STREAM_HEAD_FRAMES: usize = 32_768;
+// Frames buffered ahead of each voice
+pub const STREAM_RING_FRAMES: usize = 16_384;
+// A ring is topped up once this much of it has been played
+pub const STREAM_CHUNK_FRAMES: usize = 4_096;
+
+// Where the rest of a recording lives when only its head is in memory
+#[derive(Debug, Clone)]
+pub struct StreamSource {
+    pub path: PathBuf,
+    pub frames: usize,
+}
+
+// Single producer, single consumer ring of stereo frames, one per voice. The upper half of `head` holds a
+// generation so a voice can restart it for a new note while the reader still fills it for the old one
+#[derive(Debug)]
+pub struct StreamRing {
+    frames: Box<[AtomicU64]>,
+    head: AtomicU64,
+    consumed: AtomicU32,
+    pending: AtomicBool,
+    // Latest generation the reader has taken the start of
+    started: AtomicU32,
+    underruns: AtomicU64,
+}
+
+impl StreamRing {
+    fn new() -> Self {
+        Self {
+            frames: (0..STREAM_RING_FRAMES).map(|_| AtomicU64::new(0)).collect(),
+            head: AtomicU64::new(0),
+            consumed: AtomicU32::new(0),
+            pending: AtomicBool::new(false),
+            started: AtomicU32::new(0),
+            underruns: AtomicU64::new(0),
+        }
+    }
+
+    // Empties the ring for a new note, returning the generation the reader has to fill
+    pub fn restart(&self) -> u32 {
+        let generation = ((self.head.load(Ordering::Acquire) >> 32) as u32).wrapping_add(1);
+        self.consumed.store(0, Ordering::Release);
+        self.head
+            .store((generation as u64) << 32, Ordering::Release);
+        generation
+    }
+
+    pub fn generation(&self) -> u32 {
+        (self.head.load(Ordering::Acquire) >> 32) as u32
+    }
+
+    // True until the reader has the start of the current generation
+    pub fn start_pending(&self) -> bool {
+        self.started.load(Ordering::Acquire) != self.generation()
+    }
+
+    // Frame `index` of the stream, if the reader has delivered it and it has not been released
+    pub fn get(&self, index: usize) -> Option<[f32; 2]> {
+        let written = self.head.load(Ordering::Acquire) as u32 as usize;
+        let consumed = self.consumed.load(Ordering::Relaxed) as usize;
+        if index < consumed || index >= written {
+            return None;
+        }
+        let packed = self.frames[index % STREAM_RING_FRAMES].load(Ordering::Relaxed);
+        Some([
+            f32::from_bits(packed as u32),
+            f32::from_bits((packed >> 32) as u32),
+        ])
+    }
+
+    // Frames before `index` will not be read again, so the reader may overwrite them
+    pub fn release(&self, index: usize) {
+        if index as u32 > self.consumed.load(Ordering::Relaxed) {
+            self.consumed.store(index as u32, Ordering::Release);
+        }
+    }
+
+    pub fn free(&self) -> usize {
+        let written = self.head.load(Ordering::Acquire) as u32;
+        let buffered = written.saturating_sub(self.consumed.load(Ordering::Acquire)) as usize;
+        STREAM_RING_FRAMES.saturating_sub(buffered)
+    }
+
+    // True if no refill is queued yet, in which case the caller queues one
+    pub fn request_fill(&self) -> bool {
+        !self.pending.swap(true, Ordering::AcqRel)
+    }
+
+    pub fn count_underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Frames already written and the room left after them, or None once the voice has moved on
+    fn space(&self, generation: u32) -> Option<(u32, usize)> {
+        let head = self.head.load(Ordering::Acquire);
+        if (head >> 32) as u32 != generation {
+            return None;
+        }
+        let written = head as u32;
+        let buffered = written.saturating_sub(self.consumed.load(Ordering::Acquire)) as usize;
+        Some((written, STREAM_RING_FRAMES.saturating_sub(buffered)))
+    }
+
+    // Publishes the frames unless the voice restarted the ring meanwhile
+    fn push(&self, generation: u32, written: u32, frames: &[[f32; 2]]) -> bool {
+        for (offset, &[left, right]) in frames.iter().enumerate() {
+            let packed = left.to_bits() as u64 | (right.to_bits() as u64) << 32;
+            self.frames[(written as usize + offset) % STREAM_RING_FRAMES]
+                .store(packed, Ordering::Relaxed);
+        }
+        let generation = (generation as u64) << 32;
+        self.head
+            .compare_exchange(
+                generation | written as u64,
+                generation | (written + frames.len() as u32) as u64,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+    }
+}
+
+// The rings of all voices, shared between the audio thread and the reader
+#[derive(Debug)]
+pub struct Streams {
+    rings: Vec<StreamRing>,
+}
+
+impl Streams {
+    pub fn new() -> Self {
+        Self {
+            rings: (0..MAX_VOICES).map(|_| StreamRing::new()).collect(),
+        }
+    }
+
+    pub fn ring(&self, slot: usize) -> &StreamRing {
+        &self.rings[slot % MAX_VOICES]
+    }
+
+    // Frames the voices had to play as silence because the disk was late
+    pub fn underruns(&self) -> u64 {
+        self.rings
+            .iter()
+            .map(|ring| ring.underruns.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// First frame of the unrolled playback position that comes through the ring instead of the head
+pub fn stream_start(data: &SampleData, loop_points: Option<(usize, usize)>) -> usize {
+    data.frames
+        .len()
+        .min(loop_points.map_or(usize::MAX, |(_, end)| end + 1))
+}
+
+#[derive(Debug, Clone)]
+pub enum StreamRequest {
+    // A voice started a streamed recording
+    Start {
+        slot: usize,
+        generation: u32,
+        data: Arc<SampleData>,
+        loop_points: Option<(usize, usize)>,
+    },
+    // A voice has played enough of its ring to take another chunk
+    Fill {
+        slot: usize,
+    },
+}
+
+struct Cursor {
+    generation: u32,
+    reader: hound::WavReader<BufReader<File>>,
+    frames: usize,
+    loop_points: Option<(usize, usize)>,
+    // Next frame of the file to deliver, and where the reader currently stands
+    next: usize,
+    position: usize,
+}
+
+impl Cursor {
+    fn open(
+        generation: u32,
+        data: &SampleData,
+        loop_points: Option<(usize, usize)>,
+    ) -> Result<Option<Self>> {
+        let Some(source) = &data.stream else {
+            return Ok(None);
+        };
+        let reader = hound::WavReader::open(&source.path)
+            .with_context(|| format!("Falha ao abrir WAV: {:?}", source.path))?;
+        let loop_points = loop_points.filter(|&(start, end)| start < end && end < source.frames);
+        // When the whole loop fits in the head, the ring starts on its first repeat
+        let next = match (stream_start(data, loop_points), loop_points) {
+            (next, Some((start, end))) if next > end => start,
+            (next, _) => next,
+        };
+        Ok(Some(Self {
+            generation,
+            reader,
+            frames: source.frames,
+            loop_points,
+            next,
+            position: 0,
+        }))
+    }
+
+    // Reads into the ring until it is full, returning false once there is nothing left to deliver
+    fn fill(&mut self, ring: &StreamRing, buffer: &mut Vec<[f32; 2]>) -> Result<bool> {
+        loop {
+            let Some((written, free)) = ring.space(self.generation) else {
+                return Ok(false);
+            };
+            // Loops are unrolled here, so a run never reads past the loop end
+            let end = self.loop_points.map_or(self.frames, |(_, end)| end + 1);
+            if self.next >= end {
+                return Ok(false);
+            }
+            let count = free.min(STREAM_CHUNK_FRAMES).min(end - self.next);
+            if count == 0 {
+                return Ok(true);
+            }
+
+            if self.position != self.next {
+                self.reader.seek(self.next as u32)?;
+            }
+            buffer.clear();
+            read_frames(&mut self.reader, count, buffer)?;
+            if buffer.is_empty() {
+                return Ok(false);
+            }
+            self.position = self.next + buffer.len();
+            self.next = match self.loop_points {
+                Some((start, end)) if self.position > end => start,
+                _ => self.position,
+            };
+            if !ring.push(self.generation, written, buffer) {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+// Background side of the streaming: serves the requests in order, so it runs on a single thread
+pub struct StreamReader {
+    streams: Arc<Streams>,
+    cursors: Vec<Option<Cursor>>,
+    buffer: Vec<[f32; 2]>,
+}
+
+impl StreamReader {
+    pub fn new(streams: Arc<Streams>) -> Self {
+        Self {
+            streams,
+            cursors: (0..MAX_VOICES).map(|_| None).collect(),
+            buffer: Vec::with_capacity(STREAM_CHUNK_FRAMES),
+        }
+    }
+
+    pub fn run(&mut self, request: StreamRequest) -> Result<()> {
+        let slot = match request {
+            StreamRequest::Start {
+                slot,
+                generation,
+                data,
+                loop_points,
+            } => {
+                let slot = slot % MAX_VOICES;
+                // Starts are sent until one arrives, so repeats and those of a note already replaced are
+                // left alone
+                let ring = self.streams.ring(slot);
+                if generation != ring.generation() || !ring.start_pending() {
+                    return Ok(());
+                }
+                // Taken even if the file fails to open, which another try would not fix
+                ring.started.store(generation, Ordering::Release);
+                self.cursors[slot] = None;
+                self.cursors[slot] = Cursor::open(generation, &data, loop_points)?;
+                slot
+            }
+            StreamRequest::Fill { slot } => slot % MAX_VOICES,
+        };
+
+        let ring = self.streams.ring(slot);
+        ring.pending.store(false, Ordering::Release);
+        let Some(cursor) = &mut self.cursors[slot] else {
+            return Ok(());
+        };
+        match cursor.fill(ring, &mut self.buffer) {
+            Ok(true) => Ok(()),
+            Ok(false) => {
+                self.cursors[slot] = None;
+                Ok(())
+            }
+            Err(err) => {
+                self.cursors[slot] = None;
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stereo float WAV whose frame `i` is `[i, -i]`
+    fn numbered_wav(name: &str, frames: usize) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("stream-test-{name}-{}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48_000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..frames {
+            writer.write_sample(i as f32).unwrap();
+            writer.write_sample(-(i as f32)).unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    // The first `head` frames in memory, the rest left to the reader
+    fn streamed(path: PathBuf, frames: usize, head: usize) -> Arc<SampleData> {
+        Arc::new(SampleData {
+            frames: (0..head).map(|i| [i as f32, -(i as f32)]).collect(),
+            sample_rate: 48_000.0,
+            stream: Some(StreamSource { path, frames }),
+        })
+    }
+
+    // File frames the ring delivers, in order
+    fn delivered(ring: &StreamRing, count: usize) -> Vec<usize> {
+        (0..count)
+            .map(|index| {
+                let [left, right] = ring.get(index).expect("frame not delivered");
+                assert_eq!(left, -right);
+                left as usize
+            })
+            .collect()
+    }
+
+    fn start(
+        reader: &mut StreamReader,
+        ring: &StreamRing,
+        data: &Arc<SampleData>,
+        loop_points: Option<(usize, usize)>,
+    ) {
+        let generation = ring.restart();
+        reader
+            .run(StreamRequest::Start {
+                slot: 0,
+                generation,
+                data: data.clone(),
+                loop_points,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn restart_hands_the_ring_to_a_new_generation() {
+        let streams = Streams::new();
+        let ring = streams.ring(0);
+        let generation = ring.restart();
+        assert_eq!(ring.generation(), generation);
+        assert!(ring.push(generation, 0, &[[1.0, 2.0], [3.0, 4.0]]));
+        assert_eq!(ring.get(1), Some([3.0, 4.0]));
+        assert_eq!(ring.free(), STREAM_RING_FRAMES - 2);
+        ring.release(1);
+        assert_eq!(ring.get(0), None);
+        assert_eq!(ring.free(), STREAM_RING_FRAMES - 1);
+
+        let next = ring.restart();
+        assert_eq!(next, generation.wrapping_add(1));
+        assert_eq!(ring.get(0), None);
+        assert_eq!(ring.free(), STREAM_RING_FRAMES);
+        // The reader still filling the old note finds the ring taken
+        assert_eq!(ring.space(generation), None);
+        assert!(!ring.push(generation, 2, &[[5.0, 6.0]]));
+        assert_eq!(ring.get(2), None);
+        assert_eq!(ring.space(next), Some((0, STREAM_RING_FRAMES)));
+        assert!(ring.push(next, 0, &[[7.0, 8.0]]));
+        assert_eq!(ring.get(0), Some([7.0, 8.0]));
+    }
+
+    #[test]
+    fn starts_stay_pending_until_the_reader_takes_them() {
+        let streams = Arc::new(Streams::new());
+        let mut reader = StreamReader::new(streams.clone());
+        let ring = streams.ring(0);
+        let path = numbered_wav("pending", 100);
+        let data = streamed(path.clone(), 100, 10);
+
+        let stale = ring.restart();
+        let generation = ring.restart();
+        assert!(ring.start_pending());
+        // A start sent for the note before is of no use any more
+        let request = |generation| StreamRequest::Start {
+            slot: 0,
+            generation,
+            data: data.clone(),
+            loop_points: None,
+        };
+        reader.run(request(stale)).unwrap();
+        assert!(ring.start_pending());
+        assert_eq!(ring.get(0), None);
+
+        reader.run(request(generation)).unwrap();
+        assert!(!ring.start_pending());
+        assert_eq!(delivered(ring, 90), (10..100).collect::<Vec<_>>());
+        // A repeat of a start the reader already has changes nothing
+        ring.release(50);
+        reader.run(request(generation)).unwrap();
+        assert_eq!(ring.get(50), Some([60.0, -60.0]));
+        assert_eq!(ring.get(90), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn loops_are_unrolled_into_the_ring() {
+        let streams = Arc::new(Streams::new());
+        let mut reader = StreamReader::new(streams.clone());
+        let ring = streams.ring(0);
+        let path = numbered_wav("loop", 100);
+
+        // The ring carries on after the head and goes round the loop
+        start(
+            &mut reader,
+            ring,
+            &streamed(path.clone(), 100, 10),
+            Some((40, 59)),
+        );
+        let expected: Vec<_> = (10..60).chain((0..4).flat_map(|_| 40..60)).collect();
+        assert_eq!(delivered(ring, expected.len()), expected);
+
+        // With the whole loop in the head, the ring starts on its first repeat
+        start(
+            &mut reader,
+            ring,
+            &streamed(path.clone(), 100, 70),
+            Some((40, 59)),
+        );
+        assert_eq!(
+            stream_start(&streamed(PathBuf::new(), 100, 70), Some((40, 59))),
+            60
+        );
+        let expected: Vec<_> = (0..3).flat_map(|_| 40..60).collect();
+        assert_eq!(delivered(ring, expected.len()), expected);
+        std::fs::remove_file(path).unwrap();
+    }
+}
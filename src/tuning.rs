@@ -0,0 +1,714 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+pub const STANDARD_PITCH: f32 = 440.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scale {
+    pub description: String,
+    // Cents of every degree above the root, the last entry being the period (usually 1200)
+    pub cents: Vec<f64>,
+}
+
+impl Default for Scale {
+    fn default() -> Self {
+        Self {
+            description: "12-TET".to_string(),
+            cents: (1..=12).map(|d| d as f64 * 100.0).collect(),
+        }
+    }
+}
+
+impl Scale {
+    pub fn parse_scl(text: &str) -> Result<Self> {
+        let mut lines = text.lines().map(str::trim).filter(|l| !l.starts_with('!'));
+        let description = lines.next().context("Arquivo .scl vazio")?.to_string();
+        let count: usize = lines
+            .next()
+            .and_then(|l| l.split_whitespace().next())
+            .context("Arquivo .scl sem número de notas")?
+            .parse()
+            .context("Número de notas inválido no .scl")?;
+
+        let mut cents = Vec::with_capacity(count);
+        for line in lines.take(count) {
+            let token = line.split_whitespace().next().context("Grau vazio no .scl")?;
+            cents.push(parse_pitch(token).with_context(|| format!("Grau inválido no .scl: {token}"))?);
+        }
+
+        if cents.len() != count || count == 0 {
+            bail!("Arquivo .scl declara {count} notas mas contém {}", cents.len());
+        }
+
+        Ok(Self { description, cents })
+    }
+
+    pub fn period(&self) -> f64 {
+        *self.cents.last().unwrap_or(&1200.0)
+    }
+
+    fn degree_cents(&self, degree: i64) -> f64 {
+        let size = self.cents.len() as i64;
+        let octave = degree.div_euclid(size);
+        let step = degree.rem_euclid(size);
+        let within = if step == 0 { 0.0 } else { self.cents[step as usize - 1] };
+        octave as f64 * self.period() + within
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyboardMapping {
+    pub first_note: u8,
+    pub last_note: u8,
+    pub middle_note: u8,
+    pub reference_note: u8,
+    pub reference_hz: f64,
+    // Scale degree acting as the formal octave; zero means the scale's own period
+    pub octave_degree: usize,
+    // Empty for a linear mapping, otherwise one optional scale degree per key in the pattern
+    pub keys: Vec<Option<usize>>,
+}
+
+impl Default for KeyboardMapping {
+    fn default() -> Self {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_hz: 440.0,
+            octave_degree: 0,
+            keys: Vec::new(),
+        }
+    }
+}
+
+impl KeyboardMapping {
+    pub fn parse_kbm(text: &str) -> Result<Self> {
+        let mut lines = text
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('!'))
+            .map(|l| l.split_whitespace().next().unwrap_or(""));
+
+        let mut field = |name: &str| -> Result<&str> { lines.next().with_context(|| format!("Arquivo .kbm sem {name}")) };
+
+        let size: usize = field("tamanho do mapa")?.parse().context("Tamanho do mapa inválido no .kbm")?;
+        let first_note = parse_note(field("primeira nota")?)?;
+        let last_note = parse_note(field("última nota")?)?;
+        let middle_note = parse_note(field("nota central")?)?;
+        let reference_note = parse_note(field("nota de referência")?)?;
+        let reference_hz: f64 = field("frequência de referência")?
+            .parse()
+            .context("Frequência de referência inválida no .kbm")?;
+        if !reference_hz.is_finite() || reference_hz <= 0.0 {
+            bail!("Frequência de referência fora do intervalo no .kbm: {reference_hz}");
+        }
+        let octave_degree: usize = field("grau de oitava")?.parse().context("Grau de oitava inválido no .kbm")?;
+
+        let mut keys = Vec::with_capacity(size);
+        for _ in 0..size {
+            // Trailing entries may be omitted, in which case the keys are unmapped
+            keys.push(match lines.next() {
+                Some("x") | None => None,
+                Some(entry) => Some(entry.parse().with_context(|| format!("Entrada inválida no .kbm: {entry}"))?),
+            });
+        }
+
+        Ok(Self {
+            first_note,
+            last_note,
+            middle_note,
+            reference_note,
+            reference_hz,
+            octave_degree,
+            keys,
+        })
+    }
+
+    fn degree(&self, note: u8, scale: &Scale) -> Option<i64> {
+        if note < self.first_note || note > self.last_note {
+            return None;
+        }
+
+        let offset = note as i64 - self.middle_note as i64;
+        if self.keys.is_empty() {
+            return Some(offset);
+        }
+
+        let size = self.keys.len() as i64;
+        let octave_degree = match self.octave_degree {
+            0 => scale.cents.len(),
+            d => d,
+        } as i64;
+        let step = self.keys[offset.rem_euclid(size) as usize]? as i64;
+        Some(offset.div_euclid(size) * octave_degree + step)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Temperament {
+    #[default]
+    Equal,
+    Meantone,
+    Werckmeister,
+    Vallotti,
+    Kirnberger,
+}
+
+impl Temperament {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "equal" | "12-tet" => Some(Temperament::Equal),
+            "meantone" => Some(Temperament::Meantone),
+            "werckmeister" => Some(Temperament::Werckmeister),
+            "vallotti" => Some(Temperament::Vallotti),
+            "kirnberger" => Some(Temperament::Kirnberger),
+            _ => None,
+        }
+    }
+
+    // Deviation of each pitch class from equal temperament in cents, starting at C
+    pub fn offsets(self) -> [f32; 12] {
+        match self {
+            Temperament::Equal => [0.0; 12],
+            // Quarter-comma meantone with the wolf between G# and Eb
+            Temperament::Meantone => [0.0, -24.0, -6.8, 10.3, -13.7, 3.4, -20.5, -3.4, -27.4, -10.3, 6.8, -17.1],
+            // Werckmeister III
+            Temperament::Werckmeister => [0.0, -9.8, -7.8, -5.9, -9.8, -2.0, -11.7, -3.9, -7.8, -11.7, -3.9, -7.8],
+            Temperament::Vallotti => [5.9, 0.0, 2.0, 3.9, -2.0, 7.8, -2.0, 3.9, 2.0, 0.0, 5.9, -3.9],
+            // Kirnberger III
+            Temperament::Kirnberger => [0.0, -9.8, -6.8, -5.9, -13.7, -2.0, -9.8, -3.4, -7.8, -10.3, -3.9, -11.7],
+        }
+    }
+}
+
+// Deviation of the just interval above a chord root from its equal-tempered size, in cents. Only the
+// thirds, fifths and their inversions are corrected; other intervals keep their tempered size.
+pub fn just_deviation(interval: u8) -> f32 {
+    match interval % 12 {
+        3 => 15.6,   // 6/5
+        4 => -13.7,  // 5/4
+        5 => -2.0,   // 4/3
+        7 => 2.0,    // 3/2
+        8 => 13.7,   // 8/5
+        9 => -15.6,  // 5/3
+        _ => 0.0,
+    }
+}
+
+// Picks the pitch class that best explains the sounding notes as a triad or seventh chord root, with
+// ties going to the bass. Returns `None` when fewer than two distinct pitch classes are held.
+pub fn detect_chord_root(pitch_classes: u16, bass: u8) -> Option<u8> {
+    if pitch_classes.count_ones() < 2 {
+        return None;
+    }
+
+    let has = |pc: u8| pitch_classes & (1 << (pc % 12)) != 0;
+    let mut best: Option<(u8, u32)> = None;
+    for root in (0..12).map(|i| (bass + i) % 12) {
+        if !has(root) {
+            continue;
+        }
+        let third = if has(root + 4) || has(root + 3) { 3 } else { 0 };
+        let fifth = if has(root + 7) { 2 } else { 0 };
+        let seventh = if has(root + 10) || has(root + 11) { 1 } else { 0 };
+        let score = third + fifth + seventh;
+        if best.is_none_or(|(_, s)| score > s) {
+            best = Some((root, score));
+        }
+    }
+
+    best.map(|(root, _)| root)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TuningConfig {
+    pub scl: Option<String>,
+    pub kbm: Option<String>,
+}
+
+impl TuningConfig {
+    pub fn from_files(scl: Option<&Path>, kbm: Option<&Path>) -> Result<Self> {
+        let read = |path: &Path| fs::read_to_string(path).with_context(|| format!("Falha ao ler afinação: {path:?}"));
+        Ok(Self {
+            scl: scl.map(read).transpose()?,
+            kbm: kbm.map(read).transpose()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Tuning {
+    scale: Scale,
+    mapping: KeyboardMapping,
+    // A4 pitch the mapping's reference frequency is scaled against, e.g. 415 for baroque pitch
+    concert_pitch: f32,
+    temperament: Temperament,
+    frequencies: [Option<f32>; 128],
+    // Absolute frequencies set at runtime through MTS single note tuning changes
+    overrides: [Option<f32>; 128],
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::new(Scale::default(), KeyboardMapping::default())
+    }
+}
+
+impl Tuning {
+    pub fn new(scale: Scale, mapping: KeyboardMapping) -> Self {
+        let mut tuning = Self {
+            concert_pitch: STANDARD_PITCH,
+            temperament: Temperament::Equal,
+            scale,
+            mapping,
+            frequencies: [None; 128],
+            overrides: [None; 128],
+        };
+        tuning.rebuild();
+        tuning
+    }
+
+    pub fn from_config(config: &TuningConfig) -> Result<Self> {
+        let scale = match &config.scl {
+            Some(text) => Scale::parse_scl(text)?,
+            None => Scale::default(),
+        };
+        let mapping = match &config.kbm {
+            Some(text) => KeyboardMapping::parse_kbm(text)?,
+            None => KeyboardMapping::default(),
+        };
+        Ok(Self::new(scale, mapping))
+    }
+
+    pub fn scale(&self) -> &Scale {
+        &self.scale
+    }
+
+    pub fn concert_pitch(&self) -> f32 {
+        self.concert_pitch
+    }
+
+    pub fn set_concert_pitch(&mut self, concert_pitch: f32) {
+        if (concert_pitch - self.concert_pitch).abs() > f32::EPSILON {
+            self.concert_pitch = concert_pitch;
+            self.rebuild();
+        }
+    }
+
+    pub fn temperament(&self) -> Temperament {
+        self.temperament
+    }
+
+    pub fn set_temperament(&mut self, temperament: Temperament) {
+        if temperament != self.temperament {
+            self.temperament = temperament;
+            self.rebuild();
+        }
+    }
+
+    pub fn frequency(&self, note: u8) -> Option<f32> {
+        let note = note as usize;
+        self.overrides.get(note).copied().flatten().or(self.frequencies.get(note).copied().flatten())
+    }
+
+    pub fn set_note_frequency(&mut self, note: u8, frequency: f32) {
+        if let Some(slot) = self.overrides.get_mut(note as usize) {
+            *slot = Some(frequency);
+        }
+    }
+
+    pub fn clear_overrides(&mut self) {
+        self.overrides = [None; 128];
+    }
+
+    fn rebuild(&mut self) {
+        let reference_note = self.mapping.reference_note;
+        // A reference key that is itself unmapped still anchors the pitch of the linear degree
+        let reference_cents = self
+            .mapping
+            .degree(reference_note, &self.scale)
+            .map(|d| self.scale.degree_cents(d))
+            .unwrap_or_else(|| self.scale.degree_cents(reference_note as i64 - self.mapping.middle_note as i64));
+
+        let reference_hz = self.mapping.reference_hz * (self.concert_pitch / STANDARD_PITCH) as f64;
+        // Temperaments are applied on top of the scale, keeping the reference key at its pitch
+        let offsets = self.temperament.offsets();
+        let reference_offset = offsets[reference_note as usize % 12] as f64;
+
+        for note in 0..128u8 {
+            self.frequencies[note as usize] = self.mapping.degree(note, &self.scale).map(|degree| {
+                let temper = offsets[note as usize % 12] as f64 - reference_offset;
+                let cents = self.scale.degree_cents(degree) - reference_cents + temper;
+                (reference_hz * 2.0_f64.powf(cents / 1200.0)) as f32
+            });
+        }
+    }
+}
+
+fn parse_pitch(token: &str) -> Result<f64> {
+    if token.contains('.') {
+        return Ok(token.parse()?);
+    }
+
+    let (num, den) = match token.split_once('/') {
+        Some((num, den)) => (num.parse::<f64>()?, den.parse::<f64>()?),
+        None => (token.parse::<f64>()?, 1.0),
+    };
+    if num <= 0.0 || den <= 0.0 {
+        bail!("Razão não positiva");
+    }
+    Ok(1200.0 * (num / den).log2())
+}
+
+fn parse_note(token: &str) -> Result<u8> {
+    let note: u8 = token.parse().with_context(|| format!("Nota inválida no .kbm: {token}"))?;
+    if note > 127 {
+        bail!("Nota fora do intervalo MIDI no .kbm: {note}");
+    }
+    Ok(note)
+}
+
+// Single note changes kept from one message, a message retuning more keys than this is ignored
+pub const MTS_MAX_CHANGES: usize = 32;
+// Longest message written back out: header, bank, program, count and the changes
+pub const MTS_MAX_LEN: usize = 6 + MTS_MAX_CHANGES * 4;
+
+// MIDI Tuning Standard message, parsed out of the SysEx so that note events carrying it stay small
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MtsSysEx {
+    // Groups of four bytes: key, semitone and the 14-bit fraction of a semitone
+    SingleNote {
+        realtime: bool,
+        count: u8,
+        changes: [[u8; 4]; MTS_MAX_CHANGES],
+    },
+    Octave {
+        realtime: bool,
+        channel_mask: u16,
+        cents: [f32; 12],
+    },
+}
+
+impl MtsSysEx {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.strip_prefix(&[0xF0]).unwrap_or(bytes);
+        let bytes = bytes.strip_suffix(&[0xF7]).unwrap_or(bytes);
+        // Universal real-time (0x7F) or non-real-time (0x7E), any device, sub-ID #1 0x08 (MIDI Tuning)
+        if bytes.len() < 4 || !matches!(bytes[0], 0x7E | 0x7F) || bytes[2] != 0x08 {
+            return None;
+        }
+        let realtime = bytes[0] == 0x7F;
+
+        match bytes[3] {
+            // Single note tuning change, without and with a bank number
+            0x02 => single_note_changes(bytes.get(5..)?, realtime),
+            0x07 => single_note_changes(bytes.get(6..)?, realtime),
+            0x08 => {
+                let payload = bytes.get(4..19)?;
+                let mut cents = [0.0; 12];
+                for (c, &b) in cents.iter_mut().zip(&payload[3..]) {
+                    *c = b as f32 - 64.0;
+                }
+                Some(MtsSysEx::Octave {
+                    realtime,
+                    channel_mask: channel_mask(payload),
+                    cents,
+                })
+            }
+            0x09 => {
+                let payload = bytes.get(4..31)?;
+                let mut cents = [0.0; 12];
+                for (c, pair) in cents.iter_mut().zip(payload[3..].chunks_exact(2)) {
+                    let value = ((pair[0] as u16) << 7) | pair[1] as u16;
+                    *c = (value as f32 - 8192.0) * 100.0 / 8192.0;
+                }
+                Some(MtsSysEx::Octave {
+                    realtime,
+                    channel_mask: channel_mask(payload),
+                    cents,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    // Without the leading 0xF0 and trailing 0xF7. Octave tunings always go out in the 2-byte form
+    pub fn to_bytes(&self) -> ([u8; MTS_MAX_LEN], usize) {
+        let mut bytes = [0; MTS_MAX_LEN];
+        let len = match *self {
+            MtsSysEx::SingleNote {
+                realtime,
+                count,
+                changes,
+            } => {
+                bytes[..6].copy_from_slice(&[if realtime { 0x7F } else { 0x7E }, 0x7F, 0x08, 0x07, 0, 0]);
+                bytes[6] = count;
+                for (chunk, group) in bytes[7..].chunks_exact_mut(4).zip(&changes[..count as usize]) {
+                    chunk.copy_from_slice(group);
+                }
+                7 + count as usize * 4
+            }
+            MtsSysEx::Octave {
+                realtime,
+                channel_mask,
+                cents,
+            } => {
+                bytes[..4].copy_from_slice(&[if realtime { 0x7F } else { 0x7E }, 0x7F, 0x08, 0x09]);
+                bytes[4] = (channel_mask >> 14) as u8 & 0x03;
+                bytes[5] = (channel_mask >> 7) as u8 & 0x7F;
+                bytes[6] = channel_mask as u8 & 0x7F;
+                for (pair, c) in bytes[7..31].chunks_exact_mut(2).zip(cents) {
+                    let value = (c * 8192.0 / 100.0 + 8192.0).round().clamp(0.0, 16383.0) as u16;
+                    pair.copy_from_slice(&[(value >> 7) as u8, value as u8 & 0x7F]);
+                }
+                31
+            }
+        };
+        (bytes, len)
+    }
+}
+
+// Decodes one key/frequency group; 0x7F 0x7F 0x7F is the reserved "no change" value
+pub fn mts_frequency(group: &[u8]) -> Option<(u8, Option<f32>)> {
+    let [key, semitone, msb, lsb] = *group else {
+        return None;
+    };
+    if semitone == 0x7F && msb == 0x7F && lsb == 0x7F {
+        return Some((key, None));
+    }
+
+    let fraction = (((msb as u16) << 7) | lsb as u16) as f32 / 16384.0;
+    let note = semitone as f32 + fraction;
+    Some((key & 0x7F, Some(STANDARD_PITCH * (2.0_f32).powf((note - 69.0) / 12.0))))
+}
+
+fn single_note_changes(bytes: &[u8], realtime: bool) -> Option<MtsSysEx> {
+    let (&count, rest) = bytes.split_first()?;
+    if count as usize > MTS_MAX_CHANGES {
+        return None;
+    }
+    let mut changes = [[0; 4]; MTS_MAX_CHANGES];
+    for (change, group) in changes.iter_mut().zip(rest.get(..count as usize * 4)?.chunks_exact(4)) {
+        change.copy_from_slice(group);
+    }
+    Some(MtsSysEx::SingleNote {
+        realtime,
+        count,
+        changes,
+    })
+}
+
+fn channel_mask(payload: &[u8]) -> u16 {
+    // Three 7-bit bytes covering channels 16-15, 14-8 and 7-1
+    (((payload[0] & 0x03) as u16) << 14) | (((payload[1] & 0x7F) as u16) << 7) | (payload[2] & 0x7F) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_cents(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?}");
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 0.01, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn scl_reads_cents_and_ratios_past_comments() {
+        let text = "! meantone.scl\n!\nQuarter-comma meantone\n 4\n!\n 193.157 major second\n5/4\n 3/2 fifth\n2\n";
+        let scale = Scale::parse_scl(text).unwrap();
+        assert_eq!(scale.description, "Quarter-comma meantone");
+        assert_cents(&scale.cents, &[193.157, 386.314, 701.955, 1200.0]);
+        assert_eq!(scale.period(), 1200.0);
+    }
+
+    #[test]
+    fn scl_rejects_bad_input() {
+        for text in [
+            "",
+            "Sem notas\n",
+            "Contagem\ndoze\n100.0\n",
+            "Curta\n3\n100.0\n200.0\n",
+            "Vazia\n0\n",
+            "Razão\n1\n-3/2\n",
+            "Texto\n1\nquinta\n",
+        ] {
+            assert!(Scale::parse_scl(text).is_err(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn kbm_leaves_x_and_omitted_keys_unmapped() {
+        // Seven-note scale on the white keys, the last key of the pattern left out
+        let kbm = "! white keys\n12\n0\n127\n60\n69\n440.0\n7\n! mapping\n0\nx\n1\nx\n2\n3\nx\n4\nx\n5\nx\n";
+        let mapping = KeyboardMapping::parse_kbm(kbm).unwrap();
+        assert_eq!(mapping.keys.len(), 12);
+        assert_eq!(mapping.keys[11], None);
+        let scl = "Just major\n7\n9/8\n5/4\n4/3\n3/2\n5/3\n15/8\n2/1\n";
+        let tuning = Tuning::new(Scale::parse_scl(scl).unwrap(), mapping);
+        assert_eq!(tuning.frequency(69), Some(440.0));
+        for note in [61, 63, 66, 68, 70, 71] {
+            assert_eq!(tuning.frequency(note), None, "{note}");
+        }
+        let c = tuning.frequency(60).unwrap();
+        assert!((c - 440.0 * 3.0 / 5.0).abs() < 0.01, "{c}");
+        assert!((tuning.frequency(67).unwrap() / c - 1.5).abs() < 1e-4);
+        assert!((tuning.frequency(72).unwrap() / c - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn kbm_rejects_bad_input() {
+        let kbm = |reference: &str, last: &str| format!("0\n0\n{last}\n60\n69\n{reference}\n0\n");
+        assert!(KeyboardMapping::parse_kbm(&kbm("440", "127")).is_ok());
+        let bad = [("0", "127"), ("-440", "127"), ("inf", "127"), ("NaN", "127"), ("lá", "127"), ("440", "128")];
+        for (reference, last) in bad {
+            assert!(KeyboardMapping::parse_kbm(&kbm(reference, last)).is_err(), "{reference} {last}");
+        }
+        assert!(KeyboardMapping::parse_kbm("1\n0\n127\n").is_err());
+    }
+
+    fn note_changes(message: MtsSysEx) -> Vec<(u8, Option<f32>)> {
+        let MtsSysEx::SingleNote { count, changes, .. } = message else {
+            panic!("{message:?}");
+        };
+        changes[..count as usize].iter().filter_map(|g| mts_frequency(g)).collect()
+    }
+
+    #[test]
+    fn single_note_changes_decode_with_and_without_a_bank() {
+        // A4 to 440 Hz and C4 a quarter tone up, then a reserved "no change" group
+        let changes = [0x45, 0x45, 0x00, 0x00, 0x3C, 0x3C, 0x40, 0x00, 0x40, 0x7F, 0x7F, 0x7F];
+        let realtime = [&[0xF0, 0x7F, 0x7F, 0x08, 0x02, 0x00, 0x03][..], &changes, &[0xF7]].concat();
+        let banked = [&[0x7E, 0x00, 0x08, 0x07, 0x01, 0x05, 0x03][..], &changes].concat();
+        for (bytes, realtime) in [(realtime, true), (banked, false)] {
+            let message = MtsSysEx::from_bytes(&bytes).unwrap();
+            assert!(matches!(message, MtsSysEx::SingleNote { realtime: r, count: 3, .. } if r == realtime));
+            let decoded = note_changes(message);
+            assert_eq!(decoded[0], (0x45, Some(440.0)));
+            assert_eq!(decoded[1].0, 0x3C);
+            assert!((decoded[1].1.unwrap() - 261.6256 * 2.0_f32.powf(0.5 / 12.0)).abs() < 0.01);
+            assert_eq!(decoded[2], (0x40, None));
+        }
+    }
+
+    #[test]
+    fn single_note_changes_past_the_cap_or_cut_short_are_ignored() {
+        let header = [0x7F, 0x7F, 0x08, 0x02, 0x00];
+        let full = [&header[..], &[MTS_MAX_CHANGES as u8], &[0x3C, 0x3C, 0, 0].repeat(MTS_MAX_CHANGES)].concat();
+        assert!(MtsSysEx::from_bytes(&full).is_some());
+        let over = [&header[..], &[MTS_MAX_CHANGES as u8 + 1], &[0x3C, 0x3C, 0, 0].repeat(MTS_MAX_CHANGES + 1)].concat();
+        assert_eq!(MtsSysEx::from_bytes(&over), None);
+        assert_eq!(MtsSysEx::from_bytes(&[0x7F, 0x7F, 0x08, 0x02, 0x00, 0x02, 0x3C, 0x3C, 0, 0]), None);
+    }
+
+    #[test]
+    fn octave_tunings_decode_in_one_and_two_byte_forms() {
+        let one_byte: Vec<u8> = [0x7E, 0x7F, 0x08, 0x08, 0x00, 0x00, 0x01].into_iter().chain(52..64).collect();
+        let Some(MtsSysEx::Octave { realtime, cents, .. }) = MtsSysEx::from_bytes(&one_byte) else {
+            panic!();
+        };
+        assert!(!realtime);
+        assert_eq!(cents, [-12.0, -11.0, -10.0, -9.0, -8.0, -7.0, -6.0, -5.0, -4.0, -3.0, -2.0, -1.0]);
+
+        let mut two_byte = vec![0x7F, 0x7F, 0x08, 0x09, 0x00, 0x00, 0x01];
+        for pair in [[0x00, 0x00], [0x40, 0x00], [0x7F, 0x7F], [0x50, 0x00]].repeat(3) {
+            two_byte.extend(pair);
+        }
+        let Some(MtsSysEx::Octave { realtime, cents, .. }) = MtsSysEx::from_bytes(&two_byte) else {
+            panic!();
+        };
+        assert!(realtime);
+        for (actual, expected) in cents.iter().zip([-100.0, 0.0, 99.988, 25.0].repeat(3)) {
+            assert!((actual - expected).abs() < 0.001, "{cents:?}");
+        }
+        assert_eq!(MtsSysEx::from_bytes(&two_byte[..30]), None);
+    }
+
+    #[test]
+    fn channel_mask_covers_all_sixteen_channels() {
+        let mask = |bytes: [u8; 3]| {
+            let message: Vec<u8> = [0x7E, 0x7F, 0x08, 0x08].into_iter().chain(bytes).chain([64; 12]).collect();
+            match MtsSysEx::from_bytes(&message) {
+                Some(MtsSysEx::Octave { channel_mask, .. }) => channel_mask,
+                other => panic!("{other:?}"),
+            }
+        };
+        assert_eq!(mask([0x00, 0x00, 0x01]), 1 << 0);
+        assert_eq!(mask([0x00, 0x00, 0x40]), 1 << 6);
+        assert_eq!(mask([0x00, 0x01, 0x00]), 1 << 7);
+        assert_eq!(mask([0x00, 0x40, 0x00]), 1 << 13);
+        assert_eq!(mask([0x01, 0x00, 0x00]), 1 << 14);
+        assert_eq!(mask([0x02, 0x00, 0x00]), 1 << 15);
+        assert_eq!(mask([0x03, 0x7F, 0x7F]), 0xFFFF);
+    }
+
+    #[test]
+    fn messages_survive_being_written_back_out() {
+        let mut cents = [0.0; 12];
+        cents[4] = -13.671875;
+        for message in [
+            MtsSysEx::Octave {
+                realtime: true,
+                channel_mask: 0x8001,
+                cents,
+            },
+            MtsSysEx::from_bytes(&[0x7F, 0x7F, 0x08, 0x02, 0x00, 0x01, 0x3C, 0x3D, 0x12, 0x34]).unwrap(),
+        ] {
+            let (bytes, len) = message.to_bytes();
+            assert_eq!(MtsSysEx::from_bytes(&bytes[..len]), Some(message));
+        }
+        assert_eq!(MtsSysEx::from_bytes(&[0x7F, 0x7F, 0x04, 0x01]), None);
+    }
+
+    #[test]
+    fn temperaments_match_their_published_tables() {
+        // Cents above C, as usually tabulated for each temperament
+        let tables = [
+            (Temperament::Equal, [0.0, 100.0, 200.0, 300.0, 400.0, 500.0, 600.0, 700.0, 800.0, 900.0, 1000.0, 1100.0]),
+            (Temperament::Meantone, [0.0, 76.0, 193.2, 310.3, 386.3, 503.4, 579.5, 696.6, 772.6, 889.7, 1006.8, 1082.9]),
+            (Temperament::Werckmeister, [0.0, 90.2, 192.2, 294.1, 390.2, 498.0, 588.3, 696.1, 792.2, 888.3, 996.1, 1092.2]),
+            (Temperament::Vallotti, [0.0, 94.1, 196.1, 298.0, 392.2, 502.0, 592.2, 698.0, 796.1, 894.1, 1000.0, 1090.2]),
+            (Temperament::Kirnberger, [0.0, 90.2, 193.2, 294.1, 386.3, 498.0, 590.2, 696.6, 792.2, 889.7, 996.1, 1088.3]),
+        ];
+        for (temperament, expected) in tables {
+            let mut tuning = Tuning::default();
+            tuning.set_temperament(temperament);
+            // The reference key keeps its pitch whatever the temperament
+            assert!((tuning.frequency(69).unwrap() - 440.0).abs() < 0.001, "{temperament:?}");
+            let c = tuning.frequency(60).unwrap();
+            for (note, expected) in (60..72).zip(expected) {
+                let cents = 1200.0 * (tuning.frequency(note).unwrap() / c).log2();
+                assert!((cents - expected).abs() < 0.2, "{temperament:?} {note}: {cents}");
+            }
+        }
+    }
+
+    #[test]
+    fn just_deviation_moves_thirds_fifths_and_inversions_to_pure_ratios() {
+        let ratios = [(3, 6.0 / 5.0), (4, 5.0 / 4.0), (5, 4.0 / 3.0), (7, 3.0 / 2.0), (8, 8.0 / 5.0), (9, 5.0 / 3.0)];
+        for (interval, ratio) in ratios {
+            let pure = 1200.0 * f32::log2(ratio) - interval as f32 * 100.0;
+            for octaves in 0..3 {
+                assert!((just_deviation(interval + octaves * 12) - pure).abs() < 0.1, "{interval}");
+            }
+        }
+        for interval in [0, 1, 2, 6, 10, 11, 12] {
+            assert_eq!(just_deviation(interval), 0.0);
+        }
+    }
+
+    #[test]
+    fn chord_root_is_found_in_any_inversion() {
+        let chord = |pcs: &[u8]| pcs.iter().fold(0u16, |mask, pc| mask | 1 << pc);
+        // C major in root position and first inversion, A minor seventh over its fifth, and a lone dyad
+        assert_eq!(detect_chord_root(chord(&[0, 4, 7]), 0), Some(0));
+        assert_eq!(detect_chord_root(chord(&[0, 4, 7]), 4), Some(0));
+        assert_eq!(detect_chord_root(chord(&[9, 0, 4, 7]), 4), Some(9));
+        assert_eq!(detect_chord_root(chord(&[2, 9]), 2), Some(2));
+        assert_eq!(detect_chord_root(chord(&[5]), 5), None);
+    }
+}
@@ -0,0 +1,119 @@
+// Longest string the delay lines hold, about 47 Hz at 192 kHz and well below the basses at 48 kHz
+pub const WAVEGUIDE_LEN: usize = 4096;
+const BOW_POSITION: f32 = 0.127;
+
+// On the heap, so the voices that hold one stay small
+#[derive(Debug, Clone)]
+pub struct DelayLine {
+    buffer: Box<[f32]>,
+    write: usize,
+}
+
+impl DelayLine {
+    pub fn new(length: usize) -> Self {
+        Self {
+            buffer: vec![0.0; length].into_boxed_slice(),
+            write: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.fill(0.0);
+        self.write = 0;
+    }
+
+    // Sample written `delay` samples ago, linearly interpolated so the length can glide
+    pub fn read(&self, delay: f32) -> f32 {
+        let length = self.buffer.len();
+        let delay = delay.clamp(1.0, (length - 2) as f32);
+        let whole = delay as usize;
+        let frac = delay - whole as f32;
+        let a = self.buffer[(self.write + length - whole) % length];
+        let b = self.buffer[(self.write + length - whole - 1) % length];
+        a + (b - a) * frac
+    }
+
+    pub fn write(&mut self, input: f32) {
+        self.buffer[self.write] = input;
+        self.write = (self.write + 1) % self.buffer.len();
+    }
+}
+
+// Bowed string after the STK model: two delay lines meet at the bow, whose stick-slip friction
+// injects energy depending on the difference between bow and string velocity
+#[derive(Debug, Clone)]
+pub struct BowedString {
+    neck: DelayLine,
+    bridge: DelayLine,
+    string_filter: f32,
+    body_filter: f32,
+}
+
+impl BowedString {
+    pub fn new() -> Self {
+        Self {
+            neck: DelayLine::new(WAVEGUIDE_LEN),
+            bridge: DelayLine::new(WAVEGUIDE_LEN / 4),
+            string_filter: 0.0,
+            body_filter: 0.0,
+        }
+    }
+
+    // Holds no buffers and must not be played, only stands in while a voice's real one is moved out
+    pub fn empty() -> Self {
+        Self {
+            neck: DelayLine::new(0),
+            bridge: DelayLine::new(0),
+            string_filter: 0.0,
+            body_filter: 0.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.neck.clear();
+        self.bridge.clear();
+        self.string_filter = 0.0;
+        self.body_filter = 0.0;
+    }
+
+    // `pressure` and `speed` are 0..1, and the bow speed is expected to already follow the note envelope
+    pub fn next(
+        &mut self,
+        freq_hz: f32,
+        pressure: f32,
+        speed: f32,
+        cutoff_hz: f32,
+        sample_rate: f32,
+    ) -> f32 {
+        // Losses at the bridge grow with frequency, as in a real string. Short strings pass through the
+        // filter many more times per second, so it is opened up for them or they never leave the stick phase
+        let pole = (0.75 - 0.2 * 22_050.0 / sample_rate) * (220.0 / freq_hz.max(220.0)).sqrt();
+        // The loss filter delays the loop by p / (1 - p) samples, which the string length makes up for
+        let period = (sample_rate / freq_hz.max(1.0) - pole / (1.0 - pole))
+            .clamp(4.0, (WAVEGUIDE_LEN - 2) as f32);
+        let neck_out = self.neck.read(period * (1.0 - BOW_POSITION));
+        let bridge_out = self.bridge.read(period * BOW_POSITION);
+        self.string_filter = bridge_out * 0.95 * (1.0 - pole) + self.string_filter * pole;
+        let bridge_reflection = -self.string_filter;
+        let nut_reflection = -neck_out;
+
+        let string_velocity = bridge_reflection + nut_reflection;
+        let bow_velocity = 0.25 * speed.clamp(0.0, 1.0);
+        let difference = bow_velocity - string_velocity;
+        let injected = difference * bow_table(difference, pressure);
+
+        self.neck.write(bridge_reflection + injected);
+        self.bridge.write(nut_reflection + injected);
+
+        let coefficient = 1.0 - (-std::f32::consts::TAU * cutoff_hz / sample_rate).exp();
+        self.body_filter += (bridge_out - self.body_filter) * coefficient;
+        self.body_filter * 1.5
+    }
+}
+
+// Friction curve: a higher pressure narrows the slip region, so the bow grips the string longer
+fn bow_table(difference: f32, pressure: f32) -> f32 {
+    let slope = 5.0 - 4.0 * pressure.clamp(0.0, 1.0);
+    let sample = (difference * slope + 0.001).abs() + 0.75;
+    sample.powi(-4).min(1.0)
+}